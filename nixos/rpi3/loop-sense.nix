@@ -30,7 +30,11 @@
       # Environment variables (if needed)
       # Environment = "RUST_LOG=info";
 
-      # Working directory
+      # Secrets are kept out of the nix store, the service does not start without
+      # LOOP_SENSE_DB_ACCESS_TOKEN=<token> in this file
+      EnvironmentFile = "/etc/loop-sense/secrets.env";
+
+      # Working directory, holds users.json, admin_token and experiment_state.json
       WorkingDirectory = "/home/${username}";
 
       # Security hardening (optional)
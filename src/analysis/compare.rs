@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use thiserror::Error;
+use tracing::*;
+use uuid::Uuid;
+
+use crate::analysis::hemodynamics::{
+    BEAT_COLUMN, Hemodynamics, PULMONARY_FLOW_COLUMN, SYSTEMIC_FLOW_COLUMN, query_beat_variability,
+};
+use crate::analysis::stats::{ChannelStats, query_channel_stats};
+use crate::analysis::steps::{ProtocolStep, protocol_steps, same_protocol};
+use crate::database::experiment_data::query_time_range;
+use crate::experiment::listing::{
+    ExperimentFromDB, query_experiments_from_influxdb, query_setpoint_events,
+};
+use crate::http::messages::{ExperimentComparison, StepComparison};
+
+/// Maximum number of experiments compared in a single request
+pub const MAX_COMPARED_EXPERIMENTS: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum CompareError {
+    #[error("experiment {0} not found")]
+    NotFound(Uuid),
+    #[error("experiment {0} holds no data")]
+    NoData(Uuid),
+    #[error("{0}")]
+    Database(String),
+}
+
+impl From<String> for CompareError {
+    fn from(err: String) -> Self {
+        CompareError::Database(err)
+    }
+}
+
+/// Channels needed to derive the hemodynamics, added to the requested channels
+const HEMODYNAMIC_COLUMNS: &[&str] = &[SYSTEMIC_FLOW_COLUMN, PULMONARY_FLOW_COLUMN, BEAT_COLUMN];
+
+/// Requested channels plus the channels the hemodynamics are derived from, without duplicates
+pub fn analysed_columns<'a>(columns: &[&'a str]) -> Vec<&'a str> {
+    let mut analysed = columns.to_vec();
+    for column in HEMODYNAMIC_COLUMNS {
+        if !analysed.contains(column) {
+            analysed.push(column);
+        }
+    }
+    analysed
+}
+
+/// Channel statistics and hemodynamics of an experiment table over the given range
+pub async fn analyse_table(
+    client: &reqwest::Client,
+    table_name: &str,
+    columns: &[&str],
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<(BTreeMap<String, ChannelStats>, Hemodynamics), String> {
+    let channels = query_channel_stats(client, table_name, columns, range).await?;
+    let beats = match channels.get(BEAT_COLUMN) {
+        Some(stats) => query_beat_variability(client, table_name, stats, range).await?,
+        None => None,
+    };
+    let hemodynamics = Hemodynamics::new(&channels, beats);
+    Ok((channels, hemodynamics))
+}
+
+/// Split an experiment into the steps of the protocol it ran
+pub async fn query_protocol_steps(
+    client: &reqwest::Client,
+    experiment_id: Uuid,
+    table_name: &str,
+) -> Result<Vec<ProtocolStep>, CompareError> {
+    let (first, last) = query_time_range(client, table_name)
+        .await?
+        .ok_or(CompareError::NoData(experiment_id))?;
+    let events = query_setpoint_events(client, experiment_id).await?;
+
+    // Steps exclude their end, so extend the last one past the last sample
+    Ok(protocol_steps(
+        &events,
+        first,
+        last + Duration::milliseconds(1),
+    ))
+}
+
+/// Compare the channels of two or more experiments, the first experiment serves as the baseline
+/// Columns must be taken from `NUMERIC_COLUMNS`
+pub async fn compare_experiments(
+    experiment_ids: &[Uuid],
+    columns: &[&str],
+) -> Result<ExperimentComparison, CompareError> {
+    let client = reqwest::Client::new();
+    let columns = analysed_columns(columns);
+
+    let listed = query_experiments_from_influxdb().await?;
+    let experiments: Vec<ExperimentFromDB> = experiment_ids
+        .iter()
+        .map(|id| {
+            listed
+                .iter()
+                .find(|e| e.experiment_id == id.to_string())
+                .cloned()
+                .ok_or(CompareError::NotFound(*id))
+        })
+        .collect::<Result<_, _>>()?;
+
+    let mut comparison = ExperimentComparison {
+        experiments: experiments.clone(),
+        channels: BTreeMap::new(),
+        mean_difference: BTreeMap::new(),
+        hemodynamics: Vec::new(),
+        steps: None,
+    };
+
+    let mut protocols = Vec::new();
+    for (id, experiment) in experiment_ids.iter().zip(&experiments) {
+        info!("Analysing experiment {} for comparison", id);
+        let (channels, hemodynamics) =
+            analyse_table(&client, &experiment.table_name, &columns, None).await?;
+        for (column, stats) in channels {
+            comparison.channels.entry(column).or_default().push(stats);
+        }
+        comparison.hemodynamics.push(hemodynamics);
+        protocols.push(query_protocol_steps(&client, *id, &experiment.table_name).await?);
+    }
+
+    // Differences are taken relative to the baseline
+    for (column, stats) in &comparison.channels {
+        let baseline = stats.first().and_then(|s| s.mean);
+        let difference = stats
+            .iter()
+            .map(|s| s.mean.zip(baseline).map(|(mean, base)| mean - base))
+            .collect();
+        comparison
+            .mean_difference
+            .insert(column.clone(), difference);
+    }
+
+    if !same_protocol(&protocols) {
+        info!("Compared experiments did not run the same protocol");
+        return Ok(comparison);
+    }
+
+    let mut steps = Vec::new();
+    for (index, step) in protocols[0].iter().enumerate() {
+        let mut step_comparison = StepComparison {
+            index,
+            setpoint: step.setpoint.clone(),
+            duration_seconds: Vec::new(),
+            channels: BTreeMap::new(),
+        };
+
+        for (experiment, steps) in experiments.iter().zip(&protocols) {
+            let step = &steps[index];
+            let range = Some((step.start, step.end));
+            let channels =
+                query_channel_stats(&client, &experiment.table_name, &columns, range).await?;
+
+            step_comparison
+                .duration_seconds
+                .push(step.duration_seconds());
+            for (column, stats) in channels {
+                step_comparison
+                    .channels
+                    .entry(column)
+                    .or_default()
+                    .push(stats);
+            }
+        }
+        steps.push(step_comparison);
+    }
+    comparison.steps = Some(steps);
+
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::experiment_data::parse_influx_time;
+    use crate::database::fake_influx::{self, FIRST_SAMPLE};
+    use crate::experiment::naming::table_name_for;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn experiment_without_setpoint_events_is_a_single_step() {
+        fake_influx::start();
+        let id = Uuid::new_v4();
+        let steps = query_protocol_steps(&reqwest::Client::new(), id, &table_name_for(id))
+            .await
+            .unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(Some(steps[0].start), parse_influx_time(FIRST_SAMPLE));
+        assert_eq!(steps[0].setpoint, Value::Null);
+        assert!(!same_protocol(&[steps.clone(), steps]));
+    }
+}
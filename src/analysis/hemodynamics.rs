@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::analysis::stats::ChannelStats;
+use crate::database::experiment_data::{parse_influx_time, time_range_filter};
+use crate::database::query::{quote_identifier, stream_sql};
+
+/// Channel the heart beats are detected in, the pressure behind the systemic valve
+pub const BEAT_COLUMN: &str = "systemic_afterload_pressure_mmhg";
+/// Channel holding the flow pumped into the systemic circulation
+pub const SYSTEMIC_FLOW_COLUMN: &str = "systemic_flow_l_per_min";
+/// Channel holding the flow pumped into the pulmonary circulation
+pub const PULMONARY_FLOW_COLUMN: &str = "pulmonary_flow_l_per_min";
+/// Shortest plausible beat interval in seconds, shorter intervals are noise
+const MIN_BEAT_INTERVAL: f64 = 0.2;
+/// Longest plausible beat interval in seconds, longer intervals span a pause or a gap
+const MAX_BEAT_INTERVAL: f64 = 3.0;
+/// Hysteresis around the mean pressure a beat has to cross, as a fraction of its std
+const BEAT_HYSTERESIS: f64 = 0.25;
+
+/// Output of the heart over a whole experiment
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct Hemodynamics {
+    /// Mean systemic flow, in L/min
+    pub cardiac_output_l_per_min: Option<f64>,
+    /// Mean pulmonary flow, in L/min
+    pub pulmonary_output_l_per_min: Option<f64>,
+    /// Cardiac output divided by the detected beat rate, in mL
+    pub stroke_volume_ml: Option<f64>,
+    pub beats: Option<BeatVariability>,
+}
+
+impl Hemodynamics {
+    /// Combine the flow statistics of an experiment with its detected beats
+    pub fn new(channels: &BTreeMap<String, ChannelStats>, beats: Option<BeatVariability>) -> Self {
+        let mean = |column: &str| channels.get(column).and_then(|stats| stats.mean);
+        let cardiac_output = mean(SYSTEMIC_FLOW_COLUMN);
+        let stroke_volume = cardiac_output
+            .zip(beats.as_ref().map(|b| b.beat_rate))
+            .filter(|(_, rate)| *rate > 0.0)
+            .map(|(output, rate)| output * 1000.0 / rate);
+
+        Self {
+            cardiac_output_l_per_min: cardiac_output,
+            pulmonary_output_l_per_min: mean(PULMONARY_FLOW_COLUMN),
+            stroke_volume_ml: stroke_volume,
+            beats,
+        }
+    }
+}
+
+/// Beat-to-beat variability of the detected heart beats
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct BeatVariability {
+    pub beats: u64,
+    /// Mean beat rate, in 1/min
+    pub beat_rate: f64,
+    pub mean_interval_ms: f64,
+    /// Standard deviation of the beat intervals
+    pub sdnn_ms: f64,
+    /// Root mean square of the differences between successive beat intervals
+    pub rmssd_ms: f64,
+}
+
+/// Detects heart beats as upward crossings of the mean pressure, sample by sample
+/// Intervals outside the plausible beat range are dropped, so pauses and gaps in the data do not
+/// count as slow beats
+pub struct BeatDetector {
+    high: f64,
+    low: f64,
+    /// Did the pressure drop below the low threshold since the last beat?
+    armed: bool,
+    last_beat: Option<DateTime<Utc>>,
+    last_interval: Option<f64>,
+    beats: u64,
+    intervals: u64,
+    interval_sum: f64,
+    interval_sum_sq: f64,
+    successive: u64,
+    successive_sum_sq: f64,
+}
+
+impl BeatDetector {
+    /// Create a detector for a pressure channel with the given statistics
+    /// Returns None for a channel without variation, which holds no beats
+    pub fn new(stats: &ChannelStats) -> Option<Self> {
+        let (mean, std) = stats.mean.zip(stats.std)?;
+        if std <= 0.0 {
+            return None;
+        }
+
+        Some(Self {
+            high: mean + BEAT_HYSTERESIS * std,
+            low: mean - BEAT_HYSTERESIS * std,
+            armed: false,
+            last_beat: None,
+            last_interval: None,
+            beats: 0,
+            intervals: 0,
+            interval_sum: 0.0,
+            interval_sum_sq: 0.0,
+            successive: 0,
+            successive_sum_sq: 0.0,
+        })
+    }
+
+    /// Feed the next pressure sample, samples must be in time order
+    pub fn push(&mut self, time: DateTime<Utc>, pressure: f64) {
+        if pressure < self.low {
+            self.armed = true;
+            return;
+        }
+        if !self.armed || pressure < self.high {
+            return;
+        }
+
+        self.armed = false;
+        self.beats += 1;
+        let previous = self.last_beat.replace(time);
+        let Some(previous) = previous else {
+            return;
+        };
+
+        let interval = (time - previous).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6;
+        if !(MIN_BEAT_INTERVAL..=MAX_BEAT_INTERVAL).contains(&interval) {
+            // Successive differences must not span a gap
+            self.last_interval = None;
+            return;
+        }
+
+        self.intervals += 1;
+        self.interval_sum += interval;
+        self.interval_sum_sq += interval * interval;
+        if let Some(last) = self.last_interval {
+            self.successive += 1;
+            self.successive_sum_sq += (interval - last).powi(2);
+        }
+        self.last_interval = Some(interval);
+    }
+
+    /// Variability of the beats seen so far, None if fewer than two valid intervals were seen
+    pub fn finish(&self) -> Option<BeatVariability> {
+        if self.intervals < 2 {
+            return None;
+        }
+
+        let n = self.intervals as f64;
+        let mean = self.interval_sum / n;
+        let variance = ((self.interval_sum_sq - n * mean * mean) / (n - 1.0)).max(0.0);
+        let rmssd = if self.successive > 0 {
+            (self.successive_sum_sq / self.successive as f64).sqrt()
+        } else {
+            0.0
+        };
+
+        Some(BeatVariability {
+            beats: self.beats,
+            beat_rate: 60.0 / mean,
+            mean_interval_ms: mean * 1000.0,
+            sdnn_ms: variance.sqrt() * 1000.0,
+            rmssd_ms: rmssd * 1000.0,
+        })
+    }
+}
+
+/// Detect the heart beats in the pressure channel of an experiment table and return their
+/// variability. `stats` are the statistics of `BEAT_COLUMN` over the same range
+pub async fn query_beat_variability(
+    client: &reqwest::Client,
+    table_name: &str,
+    stats: &ChannelStats,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<Option<BeatVariability>, String> {
+    let Some(mut detector) = BeatDetector::new(stats) else {
+        return Ok(None);
+    };
+
+    let filter = time_range_filter(range);
+    let query = format!(
+        r#"SELECT time, {column} FROM {table} {filter} ORDER BY time ASC"#,
+        column = quote_identifier(BEAT_COLUMN),
+        table = quote_identifier(table_name),
+    );
+
+    // Stream the samples, an experiment easily holds millions of them
+    let mut rows = stream_sql(client, &query).await?.boxed();
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let time = row
+            .get("time")
+            .and_then(Value::as_str)
+            .and_then(parse_influx_time);
+        let pressure = row.get(BEAT_COLUMN).and_then(Value::as_f64);
+        if let (Some(time), Some(pressure)) = (time, pressure) {
+            detector.push(time, pressure);
+        }
+    }
+
+    Ok(detector.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    /// Sample period of the synthetic waveform, in ms
+    const SAMPLE_MS: i64 = 1;
+
+    /// Pressure waveform with a beat of the given lengths, in ms, after a second of diastole
+    /// A beat is 200 ms of systole at 120 mmHg followed by diastole at 80 mmHg
+    fn waveform(beats: &[i64]) -> Vec<(DateTime<Utc>, f64)> {
+        let start = DateTime::from_timestamp(1_760_000_000, 0).unwrap();
+        let mut samples = Vec::new();
+        let mut beat_start = 1000;
+        let mut push = |ms: i64, pressure: f64| {
+            samples.push((start + TimeDelta::milliseconds(ms), pressure));
+        };
+        for ms in (0..beat_start).step_by(SAMPLE_MS as usize) {
+            push(ms, 80.0);
+        }
+        for length in beats {
+            for offset in (0..*length).step_by(SAMPLE_MS as usize) {
+                push(beat_start + offset, if offset < 200 { 120.0 } else { 80.0 });
+            }
+            beat_start += length;
+        }
+        samples
+    }
+
+    fn stats(samples: &[(DateTime<Utc>, f64)]) -> ChannelStats {
+        let n = samples.len() as f64;
+        let mean = samples.iter().map(|(_, p)| p).sum::<f64>() / n;
+        let variance = samples.iter().map(|(_, p)| (p - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        ChannelStats {
+            samples: samples.len() as u64,
+            mean: Some(mean),
+            std: Some(variance.sqrt()),
+            ..Default::default()
+        }
+    }
+
+    fn detect(samples: &[(DateTime<Utc>, f64)]) -> Option<BeatVariability> {
+        let mut detector = BeatDetector::new(&stats(samples))?;
+        for (time, pressure) in samples {
+            detector.push(*time, *pressure);
+        }
+        detector.finish()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn detects_beat_variability() {
+        let beats = detect(&waveform(&[800, 1000, 800, 1000, 800])).unwrap();
+        // Intervals of 0.8, 1.0, 0.8 and 1.0 s between the five beats
+        assert_eq!(beats.beats, 5);
+        assert_close(beats.mean_interval_ms, 900.0);
+        assert_close(beats.beat_rate, 60.0 / 0.9);
+        assert_close(beats.sdnn_ms, (0.04f64 / 3.0).sqrt() * 1000.0);
+        assert_close(beats.rmssd_ms, 200.0);
+    }
+
+    #[test]
+    fn regular_beats_have_no_variability() {
+        let beats = detect(&waveform(&[750; 8])).unwrap();
+        assert_eq!(beats.beats, 8);
+        assert_close(beats.beat_rate, 80.0);
+        assert_close(beats.sdnn_ms, 0.0);
+        assert_close(beats.rmssd_ms, 0.0);
+    }
+
+    #[test]
+    fn drops_intervals_spanning_a_gap() {
+        // The 5 s beat is a pause, its interval and the differences to it are dropped
+        let beats = detect(&waveform(&[800, 1000, 5000, 800, 1000, 800])).unwrap();
+        assert_eq!(beats.beats, 6);
+        assert_close(beats.mean_interval_ms, 900.0);
+        // Only the successive differences 0.8 -> 1.0 and 0.8 -> 1.0 remain
+        assert_close(beats.rmssd_ms, 200.0);
+    }
+
+    #[test]
+    fn needs_two_intervals() {
+        assert_eq!(detect(&waveform(&[800, 1000])), None);
+        assert!(detect(&waveform(&[800, 1000, 800])).is_some());
+    }
+
+    #[test]
+    fn flat_pressure_holds_no_beats() {
+        let flat = waveform(&[]);
+        assert!(BeatDetector::new(&stats(&flat)).is_none());
+        assert!(BeatDetector::new(&ChannelStats::default()).is_none());
+    }
+}
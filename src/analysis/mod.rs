@@ -0,0 +1,5 @@
+pub mod compare;
+pub mod hemodynamics;
+pub mod stats;
+pub mod steps;
+pub mod summary;
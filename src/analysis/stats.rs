@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::database::experiment_data::time_range_filter;
+use crate::database::query::{query_sql, quote_identifier};
+
+/// Summary statistics of a single channel, null if the channel holds no samples
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct ChannelStats {
+    pub samples: u64,
+    pub mean: Option<f64>,
+    pub std: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// 5th percentile, approximated by the database
+    pub p05: Option<f64>,
+    /// Approximated by the database
+    pub median: Option<f64>,
+    /// 95th percentile, approximated by the database
+    pub p95: Option<f64>,
+}
+
+/// Aggregates computed per channel, as SQL function and column suffix
+const AGGREGATES: &[(&str, &str)] = &[
+    ("count({})", "samples"),
+    ("avg({})", "mean"),
+    ("stddev({})", "std"),
+    ("min({})", "min"),
+    ("max({})", "max"),
+    ("approx_percentile_cont({}, 0.05)", "p05"),
+    ("approx_percentile_cont({}, 0.5)", "median"),
+    ("approx_percentile_cont({}, 0.95)", "p95"),
+];
+
+/// Query the summary statistics of `columns` of an experiment table, optionally limited to the
+/// samples from `from` up to, but excluding, `to`
+/// Columns must be taken from `NUMERIC_COLUMNS`
+pub async fn query_channel_stats(
+    client: &reqwest::Client,
+    table_name: &str,
+    columns: &[&str],
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+) -> Result<BTreeMap<String, ChannelStats>, String> {
+    let aggregates: Vec<String> = columns
+        .iter()
+        .flat_map(|column| {
+            let quoted = quote_identifier(column);
+            AGGREGATES.iter().map(move |(function, suffix)| {
+                format!(
+                    "{} AS {}",
+                    function.replace("{}", &quoted),
+                    quote_identifier(&format!("{column}_{suffix}"))
+                )
+            })
+        })
+        .collect();
+
+    let filter = time_range_filter(range);
+    let query = format!(
+        r#"SELECT {} FROM {} {}"#,
+        aggregates.join(", "),
+        quote_identifier(table_name),
+        filter
+    );
+
+    let data = query_sql(client, &query).await?;
+    let row = data
+        .as_array()
+        .and_then(|rows| rows.first())
+        .cloned()
+        .unwrap_or(Value::Null);
+
+    Ok(columns
+        .iter()
+        .map(|column| {
+            let value = |suffix: &str| {
+                row.get(format!("{column}_{suffix}"))
+                    .and_then(Value::as_f64)
+            };
+            let stats = ChannelStats {
+                samples: value("samples").unwrap_or(0.0) as u64,
+                mean: value("mean"),
+                std: value("std"),
+                min: value("min"),
+                max: value("max"),
+                p05: value("p05"),
+                median: value("median"),
+                p95: value("p95"),
+            };
+            (column.to_string(), stats)
+        })
+        .collect())
+}
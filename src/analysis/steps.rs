@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::database::experiment_data::parse_influx_time;
+use crate::http::messages::SetpointEventFromDB;
+
+/// Period of an experiment during which the setpoints did not change
+/// The backend has no notion of a protocol, so the steps of a protocol are taken to be the
+/// periods between accepted setpoint changes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProtocolStep {
+    pub index: usize,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Complete `FrontendSetpoint` during this step, null if it is unknown
+    pub setpoint: Value,
+}
+
+impl ProtocolStep {
+    pub fn duration_seconds(&self) -> f64 {
+        (self.end - self.start).num_milliseconds() as f64 / 1000.0
+    }
+}
+
+/// Split the time between `start` and `end` into protocol steps at every setpoint event
+/// Events must be ordered by time, events outside the range are ignored
+pub fn protocol_steps(
+    events: &[SetpointEventFromDB],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<ProtocolStep> {
+    let mut steps = Vec::new();
+    let mut step_start = start;
+    let mut setpoint = events
+        .first()
+        .map(|event| event.old.clone())
+        .unwrap_or(Value::Null);
+
+    for event in events {
+        let Some(time) = parse_influx_time(&event.time) else {
+            continue;
+        };
+        if time >= end {
+            break;
+        }
+        if time <= step_start {
+            setpoint = event.new.clone();
+            continue;
+        }
+
+        steps.push(ProtocolStep {
+            index: steps.len(),
+            start: step_start,
+            end: time,
+            setpoint,
+        });
+        step_start = time;
+        setpoint = event.new.clone();
+    }
+
+    steps.push(ProtocolStep {
+        index: steps.len(),
+        start: step_start,
+        end,
+        setpoint,
+    });
+    steps
+}
+
+/// Did the experiments with the given steps run the same protocol, i.e. apply the same setpoints
+/// in the same order? Experiments without setpoint changes share no protocol
+pub fn same_protocol(protocols: &[Vec<ProtocolStep>]) -> bool {
+    let Some((first, rest)) = protocols.split_first() else {
+        return false;
+    };
+
+    first.len() > 1
+        && rest.iter().all(|steps| {
+            steps.len() == first.len()
+                && steps
+                    .iter()
+                    .zip(first)
+                    .all(|(a, b)| a.setpoint == b.setpoint)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use serde_json::json;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_760_000_000, 0).unwrap() + TimeDelta::seconds(seconds)
+    }
+
+    /// Setpoint event at `seconds`, formatted like the database does
+    fn event(seconds: i64, old: i64, new: i64) -> SetpointEventFromDB {
+        SetpointEventFromDB {
+            time: at(seconds).format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+            source: String::from("http"),
+            client: String::new(),
+            user: String::new(),
+            old: json!({ "heart_rate": old }),
+            new: json!({ "heart_rate": new }),
+        }
+    }
+
+    /// Heart rates and bounds of the steps, in seconds
+    fn summary(steps: &[ProtocolStep]) -> Vec<(i64, i64, Value)> {
+        steps
+            .iter()
+            .map(|step| {
+                assert_eq!(steps[step.index], *step);
+                (
+                    (step.start - at(0)).num_seconds(),
+                    (step.end - at(0)).num_seconds(),
+                    step.setpoint["heart_rate"].clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_at_every_setpoint_event() {
+        let events = [event(10, 60, 80), event(25, 80, 100)];
+        let steps = protocol_steps(&events, at(0), at(40));
+        assert_eq!(
+            summary(&steps),
+            [
+                (0, 10, json!(60)),
+                (10, 25, json!(80)),
+                (25, 40, json!(100)),
+            ]
+        );
+        assert_eq!(steps[1].duration_seconds(), 15.0);
+    }
+
+    #[test]
+    fn without_events_is_a_single_unknown_step() {
+        let steps = protocol_steps(&[], at(0), at(40));
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].setpoint, Value::Null);
+        assert_eq!(steps[0].duration_seconds(), 40.0);
+    }
+
+    #[test]
+    fn ignores_events_outside_the_range() {
+        // The event before the start sets the setpoint of the first step
+        let events = [
+            event(-5, 50, 60),
+            event(0, 60, 70),
+            event(20, 70, 80),
+            event(40, 80, 90),
+            event(50, 90, 100),
+        ];
+        let steps = protocol_steps(&events, at(0), at(40));
+        assert_eq!(summary(&steps), [(0, 20, json!(70)), (20, 40, json!(80))]);
+    }
+
+    #[test]
+    fn skips_events_with_invalid_time() {
+        let mut invalid = event(10, 60, 80);
+        invalid.time = String::from("yesterday");
+        let events = [invalid, event(20, 80, 100)];
+        let steps = protocol_steps(&events, at(0), at(40));
+        assert_eq!(summary(&steps), [(0, 20, json!(60)), (20, 40, json!(100))]);
+    }
+
+    #[test]
+    fn same_protocol_compares_setpoints_in_order() {
+        let first = protocol_steps(&[event(10, 60, 80), event(25, 80, 100)], at(0), at(40));
+        // Same setpoints at different times
+        let shifted = protocol_steps(&[event(5, 60, 80), event(30, 80, 100)], at(0), at(60));
+        let reordered = protocol_steps(&[event(10, 60, 100), event(25, 100, 80)], at(0), at(40));
+        let shorter = protocol_steps(&[event(10, 60, 80)], at(0), at(40));
+
+        assert!(same_protocol(&[first.clone(), shifted.clone()]));
+        assert!(same_protocol(&[first.clone(), shifted, first.clone()]));
+        assert!(!same_protocol(&[first.clone(), reordered]));
+        assert!(!same_protocol(&[first, shorter]));
+    }
+
+    #[test]
+    fn no_setpoint_changes_share_no_protocol() {
+        let unchanged = protocol_steps(&[], at(0), at(40));
+        assert!(!same_protocol(&[unchanged.clone(), unchanged]));
+        assert!(!same_protocol(&[]));
+    }
+}
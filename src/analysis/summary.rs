@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+use tracing::*;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::analysis::compare::{CompareError, analyse_table, query_protocol_steps};
+use crate::analysis::hemodynamics::Hemodynamics;
+use crate::analysis::stats::{ChannelStats, query_channel_stats};
+use crate::database::experiment_data::{NUMERIC_COLUMNS, column_unit};
+use crate::database::query::{query_sql, query_table_columns, quote_identifier};
+use crate::database::secrets::SUMMARY_TABLE;
+use crate::experiment::Experiment;
+use crate::experiment::listing::{
+    ExperimentFromDB, find_experiment, query_annotations, query_data_gaps,
+};
+use crate::experiment::quality::REPORT_PERIOD;
+use crate::export::format_annotation;
+use crate::http::messages::{AnnotationFromDB, DataGapFromDB};
+use crate::messages::db_messages::DatabaseMessage;
+
+/// Time the DB task gets to write the last reports of a stopped experiment before it is summarised
+const SUMMARY_DELAY: Duration = Duration::from_secs(5);
+
+/// Overview of a recorded experiment, computed when it stops
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ExperimentSummary {
+    pub experiment_id: Uuid,
+    pub name: String,
+    pub description: String,
+    /// Time of the first sample
+    pub start_time: DateTime<Utc>,
+    /// End of the recording
+    pub end_time: DateTime<Utc>,
+    pub duration_seconds: f64,
+    /// Time logging was paused, unknown for experiments summarised on request
+    pub paused_seconds: Option<f64>,
+    pub samples: u64,
+    /// Samples the MCU should have sent while logging, outside of data gaps
+    pub expected_samples: u64,
+    pub dropped_samples: u64,
+    pub data_gaps: Vec<DataGapFromDB>,
+    /// Statistics of every measured channel
+    pub channels: BTreeMap<String, ChannelStats>,
+    pub hemodynamics: Hemodynamics,
+    /// Channel means per protocol step, empty if the setpoints never changed
+    pub steps: Vec<StepSummary>,
+    pub annotations: Vec<AnnotationFromDB>,
+    pub generated_at: DateTime<Utc>,
+}
+
+/// Channel means during a single protocol step
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct StepSummary {
+    pub index: usize,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Complete `FrontendSetpoint` during this step
+    pub setpoint: Value,
+    pub means: BTreeMap<String, Option<f64>>,
+}
+
+impl ExperimentSummary {
+    /// Human readable summary, one line per fact, used as the preamble of text exports
+    pub fn report_lines(&self) -> Vec<String> {
+        let optional = |value: Option<f64>, unit: &str| match value {
+            Some(value) => format!("{value:.3} {unit}"),
+            None => String::from("unknown"),
+        };
+
+        let mut lines = vec![
+            format!("Experiment: {} ({})", self.name, self.experiment_id),
+            format!(
+                "Recorded: {} to {}, {:.1} s, paused {}",
+                self.start_time.to_rfc3339(),
+                self.end_time.to_rfc3339(),
+                self.duration_seconds,
+                optional(self.paused_seconds, "s"),
+            ),
+            format!(
+                "Samples: {} of {} expected, {} dropped, {} data gaps",
+                self.samples,
+                self.expected_samples,
+                self.dropped_samples,
+                self.data_gaps.len()
+            ),
+        ];
+        for gap in &self.data_gaps {
+            lines.push(format!(
+                "Data gap: {} for {:.1} s, {}",
+                gap.start, gap.duration_seconds, gap.cause
+            ));
+        }
+
+        let beats = self.hemodynamics.beats.as_ref();
+        lines.push(format!(
+            "Cardiac output: {}, stroke volume: {}, beat rate: {}, SDNN: {}, RMSSD: {}",
+            optional(self.hemodynamics.cardiac_output_l_per_min, "L/min"),
+            optional(self.hemodynamics.stroke_volume_ml, "mL"),
+            optional(beats.map(|b| b.beat_rate), "1/min"),
+            optional(beats.map(|b| b.sdnn_ms), "ms"),
+            optional(beats.map(|b| b.rmssd_ms), "ms"),
+        ));
+        for (column, stats) in &self.channels {
+            let unit = column_unit(column).unwrap_or("");
+            lines.push(format!(
+                "{column}: min {}, max {}, mean {}",
+                optional(stats.min, unit),
+                optional(stats.max, unit),
+                optional(stats.mean, unit),
+            ));
+        }
+        if !self.steps.is_empty() {
+            lines.push(format!(
+                "Protocol steps: {}, see the summary endpoint for the means per step",
+                self.steps.len()
+            ));
+        }
+        for annotation in &self.annotations {
+            lines.push(format!(
+                "Annotation: {} {}",
+                annotation.time,
+                format_annotation(annotation)
+            ));
+        }
+        lines
+    }
+}
+
+/// Compute the summary of a recorded experiment
+/// `paused_seconds` is only known for an experiment that just stopped
+/// Returns None if the experiment holds no data
+pub async fn compute_summary(
+    client: &reqwest::Client,
+    experiment: &ExperimentFromDB,
+    paused_seconds: Option<f64>,
+) -> Result<Option<ExperimentSummary>, String> {
+    let experiment_id = Uuid::parse_str(&experiment.experiment_id)
+        .map_err(|e| format!("Invalid experiment id: {}", e))?;
+    let table_name = &experiment.table_name;
+
+    let steps = match query_protocol_steps(client, experiment_id, table_name).await {
+        Ok(steps) => steps,
+        Err(CompareError::NoData(_)) => return Ok(None),
+        Err(err) => return Err(err.to_string()),
+    };
+    let (Some(first), Some(last)) = (steps.first(), steps.last()) else {
+        return Ok(None);
+    };
+    let (start_time, end_time) = (first.start, last.end);
+
+    // Every numeric column apart from the MCU clock is a measurement
+    let columns: Vec<&str> = NUMERIC_COLUMNS
+        .iter()
+        .copied()
+        .filter(|c| !["simulation_time", "mcu_timestamp_us"].contains(c))
+        .collect();
+    let (channels, hemodynamics) = analyse_table(client, table_name, &columns, None).await?;
+
+    // A single step means the setpoints never changed, so there is no protocol to summarise
+    let mut step_summaries = Vec::new();
+    if steps.len() > 1 {
+        for step in steps {
+            let stats =
+                query_channel_stats(client, table_name, &columns, Some((step.start, step.end)))
+                    .await?;
+            step_summaries.push(StepSummary {
+                index: step.index,
+                start: step.start,
+                end: step.end,
+                setpoint: step.setpoint,
+                means: stats.into_iter().map(|(c, s)| (c, s.mean)).collect(),
+            });
+        }
+    }
+
+    let data_gaps = query_data_gaps(client, experiment_id).await?;
+    let annotations = query_annotations(client, experiment_id).await?;
+
+    let duration_seconds = (end_time - start_time).num_milliseconds() as f64 / 1000.0;
+    let samples = channels.values().map(|s| s.samples).max().unwrap_or(0);
+    let gap_seconds: f64 = data_gaps.iter().map(|g| g.duration_seconds).sum();
+    let logging_seconds = duration_seconds - gap_seconds - paused_seconds.unwrap_or(0.0);
+    let expected_samples = (logging_seconds.max(0.0) / REPORT_PERIOD.as_secs_f64()).round() as u64;
+
+    Ok(Some(ExperimentSummary {
+        experiment_id,
+        name: experiment.experiment_name.clone(),
+        description: experiment.description.clone(),
+        start_time,
+        end_time,
+        duration_seconds,
+        paused_seconds,
+        samples,
+        expected_samples,
+        dropped_samples: expected_samples.saturating_sub(samples),
+        data_gaps,
+        channels,
+        hemodynamics,
+        steps: step_summaries,
+        annotations,
+        generated_at: Utc::now(),
+    }))
+}
+
+/// Query the latest stored summary of an experiment
+pub async fn query_stored_summary(
+    client: &reqwest::Client,
+    experiment_id: Uuid,
+) -> Result<Option<ExperimentSummary>, String> {
+    // The summary table only exists once an experiment was summarised
+    if query_table_columns(client, SUMMARY_TABLE).await?.is_empty() {
+        return Ok(None);
+    }
+
+    // The experiment id is a parsed uuid, so it is safe to interpolate
+    let query = format!(
+        r#"SELECT summary
+           FROM {}
+           WHERE experiment_id = '{}'
+           ORDER BY time DESC
+           LIMIT 1"#,
+        quote_identifier(SUMMARY_TABLE),
+        experiment_id
+    );
+
+    let data = query_sql(client, &query).await?;
+    let summary = data
+        .as_array()
+        .and_then(|rows| rows.first())
+        .and_then(|row| row.get("summary"))
+        .and_then(Value::as_str);
+
+    match summary {
+        Some(summary) => serde_json::from_str(summary)
+            .map(Some)
+            .map_err(|e| format!("Failed to parse stored summary: {}", e)),
+        None => Ok(None),
+    }
+}
+
+/// Return the stored summary of an experiment, or compute it if there is none
+/// Returns the summary and whether it was computed, None if the experiment holds no data
+pub async fn load_summary(
+    client: &reqwest::Client,
+    experiment: &ExperimentFromDB,
+) -> Result<Option<(ExperimentSummary, bool)>, String> {
+    let experiment_id = Uuid::parse_str(&experiment.experiment_id)
+        .map_err(|e| format!("Invalid experiment id: {}", e))?;
+
+    if let Some(mut summary) = query_stored_summary(client, experiment_id).await? {
+        // The experiment may have been renamed after it was summarised
+        summary.name = experiment.experiment_name.clone();
+        summary.description = experiment.description.clone();
+        return Ok(Some((summary, false)));
+    }
+    Ok(compute_summary(client, experiment, None)
+        .await?
+        .map(|summary| (summary, true)))
+}
+
+/// Summarise an experiment that just stopped and store the summary
+/// Waits for the DB task to write the last reports first
+pub async fn summarise_stopped_experiment(
+    experiment: Experiment,
+    stopped_at: DateTime<Utc>,
+    db_sender: mpsc::Sender<DatabaseMessage>,
+) {
+    time::sleep(SUMMARY_DELAY).await;
+
+    let listed = match find_experiment(experiment.id).await {
+        Ok(Some(listed)) => listed,
+        Ok(None) => {
+            warn!(
+                "Experiment {} holds no data, not summarising",
+                experiment.id
+            );
+            return;
+        }
+        Err(err) => {
+            error!(
+                "Unable to look up experiment {} to summarise: {err}",
+                experiment.id
+            );
+            return;
+        }
+    };
+
+    let paused_seconds =
+        experiment.paused_duration_at(stopped_at).num_milliseconds() as f64 / 1000.0;
+    let client = reqwest::Client::new();
+    match compute_summary(&client, &listed, Some(paused_seconds)).await {
+        Ok(Some(summary)) => {
+            info!(
+                "Summarised experiment {} - {}",
+                experiment.id, experiment.name
+            );
+            if let Err(err) = db_sender
+                .send(DatabaseMessage::Summary(Box::new(summary)))
+                .await
+            {
+                error!("Unable to send experiment summary to database task: {err}");
+            }
+        }
+        Ok(None) => warn!(
+            "Experiment {} holds no data, not summarising",
+            experiment.id
+        ),
+        Err(err) => error!("Unable to summarise experiment {}: {err}", experiment.id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::experiment_data::parse_influx_time;
+    use crate::database::fake_influx::{self, FIRST_SAMPLE, LAST_SAMPLE};
+    use crate::experiment::naming::table_name_for;
+
+    #[tokio::test]
+    async fn summarises_experiment_without_events_or_annotations() {
+        fake_influx::start();
+        let id = Uuid::new_v4();
+        let experiment = ExperimentFromDB {
+            table_name: table_name_for(id),
+            experiment_id: id.to_string(),
+            experiment_name: String::from("baseline"),
+            description: String::new(),
+            start_time: None,
+            duration_seconds: 300.0,
+            tags: Vec::new(),
+            started_by: None,
+            deleted_at: None,
+        };
+
+        let summary = compute_summary(&reqwest::Client::new(), &experiment, Some(0.0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.experiment_id, id);
+        assert_eq!(Some(summary.start_time), parse_influx_time(FIRST_SAMPLE));
+        assert!(summary.end_time > parse_influx_time(LAST_SAMPLE).unwrap());
+        assert!(summary.steps.is_empty());
+        assert!(summary.annotations.is_empty());
+        assert!(summary.data_gaps.is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::*;
+use utoipa::ToSchema;
+
+use crate::http::error::ApiError;
+
+/// File storing the users and the hashes of their tokens, relative to the service working directory
+const USERS_PATH: &str = "users.json";
+/// Name of the admin created when no users file exists
+const INITIAL_ADMIN: &str = "admin";
+/// File the token of that admin is written to, next to the users file. Never logged, as the log
+/// is kept long after the token should be forgotten
+const INITIAL_ADMIN_TOKEN_PATH: &str = "admin_token";
+/// Number of random bytes in a token
+const TOKEN_BYTES: usize = 32;
+/// Maximum length of a user name, in characters
+const MAX_USER_NAME_LEN: usize = 32;
+
+/// What a user is allowed to do, every role may do everything the roles before it may
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Read measurements and experiments
+    Viewer,
+    /// Drive the rig and record experiments
+    Operator,
+    /// Manage users
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Authenticated user, as seen by the handlers
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+}
+
+impl User {
+    /// Check that this user may do what `role` may
+    pub fn require(&self, role: Role) -> Result<(), ApiError> {
+        if self.role >= role {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden(format!(
+                "{} has the {} role, this requires the {} role",
+                self.name,
+                self.role.as_str(),
+                role.as_str()
+            )))
+        }
+    }
+}
+
+/// User as stored in the users file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredUser {
+    role: Role,
+    /// Hex encoded SHA-256 hash of the token, the token itself is never stored
+    token_hash: String,
+    created_at: DateTime<Utc>,
+}
+
+/// User as listed to admins
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UserInfo {
+    pub name: String,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum UserError {
+    #[error("user names must be 1-{MAX_USER_NAME_LEN} letters, digits, '-', '_' or '.'")]
+    InvalidName,
+    #[error("user {0} already exists")]
+    Exists(String),
+    #[error("user {0} not found")]
+    NotFound(String),
+    #[error("the last admin cannot be removed")]
+    LastAdmin,
+}
+
+/// Users allowed to access the API, keyed by name
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UserStore {
+    users: BTreeMap<String, StoredUser>,
+}
+
+impl UserStore {
+    /// Load the users file, creating it with a single admin if it does not exist
+    /// The token of that admin is only ever written to `INITIAL_ADMIN_TOKEN_PATH`
+    pub async fn load_or_init() -> Result<Self> {
+        match fs::read(USERS_PATH).await {
+            Ok(bytes) => {
+                let store: UserStore = serde_json::from_slice(&bytes)?;
+                info!("Loaded {} users from {USERS_PATH}", store.users.len());
+                Ok(store)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let mut store = UserStore::default();
+                let token = store.add(INITIAL_ADMIN, Role::Admin)?;
+                store.save().await?;
+                write_private(INITIAL_ADMIN_TOKEN_PATH, format!("{token}\n").as_bytes()).await?;
+                warn!(
+                    "Created {USERS_PATH} with user {INITIAL_ADMIN}, its token is in {INITIAL_ADMIN_TOKEN_PATH}. Store it elsewhere and delete the file"
+                );
+                Ok(store)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Store the users on disk
+    pub async fn save(&self) -> Result<()> {
+        // Write to a temporary file first, so a crash halfway through never leaves a corrupt file
+        let tmp_path = format!("{USERS_PATH}.tmp");
+        write_private(&tmp_path, &serde_json::to_vec_pretty(self)?).await?;
+        fs::rename(&tmp_path, USERS_PATH).await?;
+        Ok(())
+    }
+
+    /// User holding the given token, if any
+    pub fn authenticate(&self, token: &str) -> Option<User> {
+        let hash = hash_token(token);
+        self.users
+            .iter()
+            .find(|(_, user)| user.token_hash == hash)
+            .map(|(name, user)| User {
+                name: name.clone(),
+                role: user.role,
+            })
+    }
+
+    pub fn list(&self) -> Vec<UserInfo> {
+        self.users
+            .iter()
+            .map(|(name, user)| UserInfo {
+                name: name.clone(),
+                role: user.role,
+                created_at: user.created_at,
+            })
+            .collect()
+    }
+
+    /// Add a user, returning the token it authenticates with
+    pub fn add(&mut self, name: &str, role: Role) -> Result<String, UserError> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+        if name.is_empty() || name.chars().count() > MAX_USER_NAME_LEN || !name.chars().all(valid) {
+            return Err(UserError::InvalidName);
+        }
+        if self.users.contains_key(name) {
+            return Err(UserError::Exists(name.to_string()));
+        }
+
+        let token = generate_token();
+        self.users.insert(
+            name.to_string(),
+            StoredUser {
+                role,
+                token_hash: hash_token(&token),
+                created_at: Utc::now(),
+            },
+        );
+        Ok(token)
+    }
+
+    /// Remove a user, revoking its token
+    pub fn remove(&mut self, name: &str) -> Result<(), UserError> {
+        let Some(user) = self.users.get(name) else {
+            return Err(UserError::NotFound(name.to_string()));
+        };
+        let admins = self
+            .users
+            .values()
+            .filter(|u| u.role == Role::Admin)
+            .count();
+        if user.role == Role::Admin && admins == 1 {
+            return Err(UserError::LastAdmin);
+        }
+        self.users.remove(name);
+        Ok(())
+    }
+}
+
+/// Write a file only the user running the service can read
+async fn write_private(path: &str, contents: &[u8]) -> Result<()> {
+    let mut file = fs::File::create(path).await?;
+    // Also restricts a file left behind with wider permissions
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Random token, hex encoded
+fn generate_token() -> String {
+    rand::random::<[u8; TOKEN_BYTES]>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Hex encoded SHA-256 hash of a token
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
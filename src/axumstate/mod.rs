@@ -1,12 +1,16 @@
 use std::sync::{Arc, Mutex};
 
 use chrono::{DateTime, Utc};
-use tokio::sync::watch::Sender;
+use tokio::sync::{broadcast, mpsc};
 
 use crate::{
-    experiment::{Experiment, ExperimentStartMessage},
+    auth::UserStore,
+    control::{clock::McuClockStatus, history::ReportHistory, lease::ControlLease},
+    experiment::{Experiment, ExperimentCommand, quality::DataQuality},
     http::messages::ExperimentList,
-    messages::frontend_messages,
+    messages::{
+        db_messages::DatabaseMessage, frontend_messages, system_events::SystemEventMessage,
+    },
 };
 
 /// All shared state involved in http (DB & frontend) communication
@@ -18,15 +22,43 @@ pub struct AxumState {
     /// Latest report to expose to http
     pub report: Arc<Mutex<Option<frontend_messages::Report>>>,
 
-    /// Notifies changes to the current experiment from the frontend experiment manager
-    pub experiment_watch: Sender<Option<ExperimentStartMessage>>,
+    /// Every report received from the MCU, streamed to the websocket clients
+    pub report_broadcast: broadcast::Sender<frontend_messages::Report>,
+
+    /// Reports of the last minutes, kept whether or not an experiment is running
+    pub history: Arc<Mutex<ReportHistory>>,
+
+    /// Experiment lifecycle commands, delivered in order to the experiment manager
+    pub experiment_commands: mpsc::Sender<ExperimentCommand>,
 
     /// Currently running experiment (stored to calculate duration dynamically)
     pub current_experiment: Arc<Mutex<Option<Experiment>>>,
 
+    /// Data-quality counters of the current experiment, updated by the control loop and DB task
+    pub data_quality: Arc<Mutex<DataQuality>>,
+
     /// List of all experiments
     pub experiments: Arc<Mutex<ExperimentList>>,
 
+    /// System and experiment state changes, streamed to the clients of the event stream
+    pub event_broadcast: broadcast::Sender<SystemEventMessage>,
+
+    /// Forwards setpoint events and other records to the DB task
+    pub db_sender: mpsc::Sender<DatabaseMessage>,
+
+    /// Mapping of the MCU clock onto the host clock, updated by the control loop
+    pub mcu_clock: Arc<Mutex<McuClockStatus>>,
+
+    /// Client allowed to change the setpoints and run experiments
+    pub control_lease: Arc<Mutex<ControlLease>>,
+
+    /// Users allowed to access the API
+    pub users: Arc<Mutex<UserStore>>,
+
+    /// Held for the whole of a change to the users, which is stored on disk before it takes
+    /// effect, so concurrent changes cannot overwrite each other
+    pub users_update: Arc<tokio::sync::Mutex<()>>,
+
     /// Time at which this application was started
     pub start_time: Arc<DateTime<Utc>>,
 }
@@ -1,6 +1,6 @@
 use love_letter::{Report, Setpoint};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::time::{Duration, timeout};
+use tokio::time::timeout;
 use tokio_serial::{
     DataBits, FlowControl, Parity, SerialPortBuilderExt, SerialPortInfo, SerialPortType,
     SerialStream, StopBits,
@@ -10,8 +10,8 @@ use tracing::*;
 use anyhow::Result;
 
 use crate::communicator::MockloopCommunicator;
+use crate::config::config;
 
-const COMMS_TIMEOUT: Duration = Duration::from_millis(2000);
 const UART_MANUFACTURER: &str = "Silicon Labs";
 
 pub struct UartCommunicator {
@@ -75,7 +75,7 @@ impl MockloopCommunicator for UartCommunicator {
         // Receive data over uart
         let mut bytes = Vec::with_capacity(love_letter::REPORT_BYTES);
         loop {
-            match timeout(COMMS_TIMEOUT, self.uart.read_u8()).await {
+            match timeout(config().comms_timeout(), self.uart.read_u8()).await {
                 Ok(Ok(byte)) => {
                     debug!("Received data byte: {}", byte);
                     if byte == 0 {
@@ -0,0 +1,243 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::Duration;
+use utoipa::ToSchema;
+
+use crate::database::secrets::{DEFAULT_DB_NAME, DEFAULT_DB_URI};
+
+/// Configuration file read when no other file is given, relative to the service working directory
+const CONFIG_PATH: &str = "loop_sense.toml";
+/// Shown instead of secrets
+const REDACTED: &str = "<redacted>";
+
+/// Configuration in effect, set once at startup
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Runtime configuration of the service
+/// Read from the TOML file, then overridden by environment variables, then by command line flags
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address of the Pi, the frontend served from it is allowed by CORS
+    pub pi_ip: String,
+    /// Port the API listens on
+    pub port: u16,
+    /// Period of the high level control loop, in ms
+    pub control_loop_period_ms: u64,
+    /// Longest time between consecutive setpoints or reports before the MCU is considered silent,
+    /// in ms
+    pub comms_timeout_ms: u64,
+    /// Number of reports written to the DB per query
+    pub query_batch_len: usize,
+    /// Period at which measurements are sent to websocket clients by default, in ms
+    pub measurement_send_period_ms: u64,
+    pub db_uri: String,
+    pub db_name: String,
+    /// Secret, redacted wherever the configuration is shown. Has no default, the service does
+    /// not start until it is configured
+    pub db_access_token: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pi_ip: String::from("192.168.0.4"),
+            port: 8000,
+            control_loop_period_ms: 10,
+            comms_timeout_ms: 2000,
+            query_batch_len: 10,
+            measurement_send_period_ms: 100,
+            db_uri: String::from(DEFAULT_DB_URI),
+            db_name: String::from(DEFAULT_DB_NAME),
+            db_access_token: String::new(),
+        }
+    }
+}
+
+/// Command line flags, every flag can also be set through the environment variable listed
+#[derive(Debug, Parser)]
+#[command(version, about = "Backend of the Holland Hybrid Heart mockloop")]
+struct Args {
+    /// TOML configuration file, loop_sense.toml in the working directory if it exists
+    #[arg(long, env = "LOOP_SENSE_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "LOOP_SENSE_PI_IP")]
+    pi_ip: Option<String>,
+    #[arg(long, env = "LOOP_SENSE_PORT")]
+    port: Option<u16>,
+    #[arg(long, env = "LOOP_SENSE_CONTROL_LOOP_PERIOD_MS")]
+    control_loop_period_ms: Option<u64>,
+    #[arg(long, env = "LOOP_SENSE_COMMS_TIMEOUT_MS")]
+    comms_timeout_ms: Option<u64>,
+    #[arg(long, env = "LOOP_SENSE_QUERY_BATCH_LEN")]
+    query_batch_len: Option<usize>,
+    #[arg(long, env = "LOOP_SENSE_MEASUREMENT_SEND_PERIOD_MS")]
+    measurement_send_period_ms: Option<u64>,
+    #[arg(long, env = "LOOP_SENSE_DB_URI")]
+    db_uri: Option<String>,
+    #[arg(long, env = "LOOP_SENSE_DB_NAME")]
+    db_name: Option<String>,
+    #[arg(long, env = "LOOP_SENSE_DB_ACCESS_TOKEN", hide_env_values = true)]
+    db_access_token: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("unable to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("invalid configuration file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("invalid configuration: {}", .0.join(", "))]
+    Invalid(Vec<String>),
+}
+
+impl Config {
+    /// Build the configuration from the configuration file, environment and command line
+    /// Exits with a usage message if the command line is invalid
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+
+        let mut config = match args.config {
+            Some(path) => Self::read(path)?,
+            None if std::fs::exists(CONFIG_PATH).unwrap_or(false) => {
+                Self::read(PathBuf::from(CONFIG_PATH))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(pi_ip) = args.pi_ip {
+            config.pi_ip = pi_ip;
+        }
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(period) = args.control_loop_period_ms {
+            config.control_loop_period_ms = period;
+        }
+        if let Some(timeout) = args.comms_timeout_ms {
+            config.comms_timeout_ms = timeout;
+        }
+        if let Some(len) = args.query_batch_len {
+            config.query_batch_len = len;
+        }
+        if let Some(period) = args.measurement_send_period_ms {
+            config.measurement_send_period_ms = period;
+        }
+        if let Some(uri) = args.db_uri {
+            config.db_uri = uri;
+        }
+        if let Some(name) = args.db_name {
+            config.db_name = name;
+        }
+        if let Some(token) = args.db_access_token {
+            config.db_access_token = token;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: PathBuf) -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).map_err(|err| ConfigError::Parse(path, err)),
+            Err(err) => Err(ConfigError::Read(path, err)),
+        }
+    }
+
+    /// Check every setting, reporting all invalid ones at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |valid: bool, message: String| {
+            if !valid {
+                errors.push(message);
+            }
+        };
+
+        check(
+            !self.pi_ip.is_empty() && !self.pi_ip.contains(|c: char| c.is_whitespace() || c == '/'),
+            format!("pi_ip must be a host name or address, got {:?}", self.pi_ip),
+        );
+        check(self.port != 0, String::from("port must not be 0"));
+        check(
+            (1..=1000).contains(&self.control_loop_period_ms),
+            format!(
+                "control_loop_period_ms must be between 1 and 1000, got {}",
+                self.control_loop_period_ms
+            ),
+        );
+        check(
+            self.comms_timeout_ms > self.control_loop_period_ms && self.comms_timeout_ms <= 60_000,
+            format!(
+                "comms_timeout_ms must be above control_loop_period_ms and at most 60000, got {}",
+                self.comms_timeout_ms
+            ),
+        );
+        check(
+            (1..=1000).contains(&self.query_batch_len),
+            format!(
+                "query_batch_len must be between 1 and 1000, got {}",
+                self.query_batch_len
+            ),
+        );
+        // The websocket rate limits, 0.1 to 100 Hz
+        check(
+            (10..=10_000).contains(&self.measurement_send_period_ms),
+            format!(
+                "measurement_send_period_ms must be between 10 and 10000, got {}",
+                self.measurement_send_period_ms
+            ),
+        );
+        check(
+            self.db_uri.starts_with("http://") || self.db_uri.starts_with("https://"),
+            format!("db_uri must be an http(s) URI, got {:?}", self.db_uri),
+        );
+        check(
+            !self.db_name.is_empty(),
+            String::from("db_name must not be empty"),
+        );
+        check(
+            !self.db_access_token.is_empty(),
+            String::from("db_access_token must be set"),
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    /// This configuration with its secrets replaced, safe to show to clients and in logs
+    pub fn redacted(&self) -> Self {
+        Self {
+            db_access_token: String::from(REDACTED),
+            ..self.clone()
+        }
+    }
+
+    pub fn control_loop_period(&self) -> Duration {
+        Duration::from_millis(self.control_loop_period_ms)
+    }
+
+    pub fn comms_timeout(&self) -> Duration {
+        Duration::from_millis(self.comms_timeout_ms)
+    }
+
+    pub fn measurement_send_period(&self) -> Duration {
+        Duration::from_millis(self.measurement_send_period_ms)
+    }
+}
+
+/// Set the configuration in effect, only the first call has an effect
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// Configuration in effect, the defaults if it was never set
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
@@ -0,0 +1,333 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Offset between the host and the mapped MCU time above which the MCU clock is anchored again
+const MAX_CLOCK_OFFSET_US: i64 = 1_000_000;
+/// Time the MCU clock has to run after anchoring before its drift is estimated
+const DRIFT_BASELINE_US: f64 = 60_000_000.0;
+/// Weight of a new measurement in the estimated drift
+const DRIFT_SMOOTHING: f64 = 0.001;
+/// Drift beyond which the estimate is considered broken, no crystal is this far off
+const MAX_DRIFT_PPM: f64 = 1000.0;
+/// A 32 bit MCU timestamp that rewinds from within this many µs of its maximum wrapped around
+const WRAP_MARGIN_US: u64 = 10_000_000;
+/// A timestamp rewinding to below this many µs is the MCU counting from its start again
+const RESET_TIMESTAMP_US: u64 = 50_000;
+/// A timestamp rewinding by at most this many µs, 10 report periods, belongs to a report that
+/// arrived late or twice rather than to a reset
+const MAX_LATE_REPORT_US: u64 = 100_000;
+
+/// State of the MCU clock mapping, exposed through the heartbeat
+#[derive(Debug, Clone, Serialize, Default, ToSchema)]
+pub struct McuClockStatus {
+    /// Host time the MCU clock was last anchored to, None before the first report
+    pub anchored_at: Option<DateTime<Utc>>,
+    /// Raw MCU timestamp of the latest report, in µs since the firmware started
+    pub mcu_timestamp_us: u64,
+    /// Estimated drift of the MCU clock relative to the host clock, positive if the MCU is slow
+    pub drift_ppm: f64,
+    /// Delay between the mapped MCU time of the latest report and its arrival, in ms
+    pub offset_ms: f64,
+    /// Number of detected MCU resets
+    pub resets: u64,
+    /// Number of times the mapping was anchored again because it drifted too far off
+    pub resyncs: u64,
+}
+
+/// MCU timestamp mapped onto host wall-clock time
+#[derive(Debug, Clone, Copy)]
+pub struct McuTime {
+    pub time: DateTime<Utc>,
+    /// MCU timestamp in µs, with 32 bit wraparounds undone
+    pub timestamp_us: u64,
+    /// Did the MCU reset since the previous report?
+    pub reset: bool,
+}
+
+/// Point at which the MCU clock is tied to the host clock
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    /// Unwrapped MCU timestamp, in µs
+    timestamp: u64,
+    /// Host time of `timestamp`, moved back as reports with shorter delays arrive
+    time: DateTime<Utc>,
+    /// Host time the report at `timestamp` arrived, the baseline of the drift estimate
+    received: DateTime<Utc>,
+}
+
+/// Maps the MCU clock, which counts µs since the firmware started, onto host wall-clock time
+/// The clock is anchored to the host time the first report arrived, and anchored again when
+/// the MCU resets. The anchor moves back whenever a report arrives faster than any before, so
+/// it converges on the report with the shortest transport delay
+#[derive(Debug, Clone, Default)]
+pub struct McuClock {
+    anchor: Option<Anchor>,
+    last_timestamp: Option<u64>,
+    /// Number of times a 32 bit MCU timestamp wrapped around
+    wraps: u64,
+    status: McuClockStatus,
+}
+
+impl McuClock {
+    /// Map the raw timestamp of a report that arrived at `received` onto host time
+    /// A report older than the latest one that arrived late or twice is mapped without touching
+    /// the anchor, the cadence check counts it as a duplicate
+    pub fn map(&mut self, timestamp: u64, received: DateTime<Utc>) -> McuTime {
+        let mut reset = false;
+        let mut wraps = self.wraps;
+        let mut late = false;
+        if let Some(last) = self.last_timestamp {
+            if timestamp < last && wrapped_around(last, timestamp) {
+                self.wraps += 1;
+                wraps = self.wraps;
+            } else if timestamp < last
+                && (timestamp < RESET_TIMESTAMP_US || last - timestamp > MAX_LATE_REPORT_US)
+            {
+                // The MCU restarted counting, its previous anchor is meaningless
+                reset = true;
+                self.wraps = 0;
+                wraps = 0;
+                self.anchor = None;
+                self.status.resets += 1;
+            } else if timestamp < last {
+                late = true;
+            } else if self.wraps > 0 && wrapped_around(timestamp, last) {
+                // Sent before the latest wraparound
+                late = true;
+                wraps = self.wraps - 1;
+            }
+        }
+
+        let unwrapped = timestamp + (wraps << 32);
+        if late && let Some(anchor) = self.anchor {
+            return McuTime {
+                time: self.host_time(&anchor, unwrapped),
+                timestamp_us: unwrapped,
+                reset,
+            };
+        }
+        self.last_timestamp = Some(timestamp);
+        self.status.mcu_timestamp_us = timestamp;
+
+        let Some(anchor) = self.anchor else {
+            return self.anchor_at(unwrapped, received, reset);
+        };
+
+        let elapsed_us = unwrapped.saturating_sub(anchor.timestamp) as f64;
+        let time = self.host_time(&anchor, unwrapped);
+        let offset = received - time;
+        let offset_us = offset.num_microseconds().unwrap_or(i64::MAX);
+
+        if offset_us.abs() > MAX_CLOCK_OFFSET_US {
+            self.status.resyncs += 1;
+            return self.anchor_at(unwrapped, received, reset);
+        }
+
+        // A report can not arrive before it was sent, so the anchor was late
+        if offset_us < 0 {
+            self.anchor = Some(Anchor {
+                time: anchor.time + offset,
+                ..anchor
+            });
+            self.status.offset_ms = 0.0;
+            return McuTime {
+                time: received,
+                timestamp_us: unwrapped,
+                reset,
+            };
+        }
+        self.status.offset_ms = offset_us as f64 / 1000.0;
+
+        // The anchor time moves with the estimated drift, so measure against the arrival instead
+        if elapsed_us >= DRIFT_BASELINE_US {
+            let host_elapsed_us =
+                (received - anchor.received).num_microseconds().unwrap_or(0) as f64;
+            let measured_ppm = (host_elapsed_us / elapsed_us - 1.0) * 1e6;
+            if measured_ppm.abs() <= MAX_DRIFT_PPM {
+                self.status.drift_ppm += DRIFT_SMOOTHING * (measured_ppm - self.status.drift_ppm);
+            }
+        }
+
+        McuTime {
+            time,
+            timestamp_us: unwrapped,
+            reset,
+        }
+    }
+
+    pub fn status(&self) -> McuClockStatus {
+        self.status.clone()
+    }
+
+    /// Host time of an unwrapped MCU timestamp, corrected for the estimated drift
+    fn host_time(&self, anchor: &Anchor, timestamp: u64) -> DateTime<Utc> {
+        let elapsed_us = timestamp as f64 - anchor.timestamp as f64;
+        let corrected_us = elapsed_us * (1.0 + self.status.drift_ppm * 1e-6);
+        anchor.time + TimeDelta::microseconds(corrected_us as i64)
+    }
+
+    fn anchor_at(&mut self, timestamp: u64, received: DateTime<Utc>, reset: bool) -> McuTime {
+        self.anchor = Some(Anchor {
+            timestamp,
+            time: received,
+            received,
+        });
+        self.status.anchored_at = Some(received);
+        self.status.offset_ms = 0.0;
+        McuTime {
+            time: received,
+            timestamp_us: timestamp,
+            reset,
+        }
+    }
+}
+
+/// Did a 32 bit MCU timestamp wrap around between `before` and `after`?
+fn wrapped_around(before: u64, after: u64) -> bool {
+    before <= u32::MAX as u64 && before > u32::MAX as u64 - WRAP_MARGIN_US && after < WRAP_MARGIN_US
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_US: u64 = 10_000;
+
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    /// Host time `us` µs after the start
+    fn at(us: u64) -> DateTime<Utc> {
+        start() + TimeDelta::microseconds(us as i64)
+    }
+
+    /// Clock fed with reports every period from `first` up to and including `last`, anchored at
+    /// the start
+    fn clock_with_reports(first: u64, last: u64) -> McuClock {
+        let mut clock = McuClock::default();
+        for timestamp in (first..=last).step_by(PERIOD_US as usize) {
+            clock.map(timestamp, at(timestamp - first));
+        }
+        clock
+    }
+
+    #[test]
+    fn anchors_on_the_first_report() {
+        let mut clock = McuClock::default();
+        let mapped = clock.map(5_000_000, at(0));
+        assert_eq!(mapped.time, at(0));
+        assert_eq!(mapped.timestamp_us, 5_000_000);
+        assert!(!mapped.reset);
+        assert_eq!(clock.status().anchored_at, Some(at(0)));
+
+        let mapped = clock.map(5_000_000 + PERIOD_US, at(PERIOD_US + 2_000));
+        assert_eq!(mapped.time, at(PERIOD_US));
+        assert_eq!(clock.status().offset_ms, 2.0);
+    }
+
+    #[test]
+    fn moves_the_anchor_back_on_a_faster_report() {
+        let mut clock = McuClock::default();
+        clock.map(0, at(3_000));
+        // Arrives 2 ms sooner after being sent than the first report
+        let mapped = clock.map(PERIOD_US, at(PERIOD_US + 1_000));
+        assert_eq!(mapped.time, at(PERIOD_US + 1_000));
+        assert_eq!(clock.status().offset_ms, 0.0);
+
+        let mapped = clock.map(2 * PERIOD_US, at(2 * PERIOD_US + 1_500));
+        assert_eq!(mapped.time, at(2 * PERIOD_US + 1_000));
+    }
+
+    #[test]
+    fn unwraps_32_bit_timestamps() {
+        let last = (1 << 32) - 5_000;
+        let mut clock = clock_with_reports(last - 10 * PERIOD_US, last);
+        let mapped = clock.map(5_000, at(11 * PERIOD_US + 1_000));
+        assert_eq!(mapped.timestamp_us, (1 << 32) + 5_000);
+        assert_eq!(mapped.time, at(11 * PERIOD_US));
+        assert!(!mapped.reset);
+        assert_eq!(clock.status().resets, 0);
+
+        // A report sent before the wraparound arriving late keeps its place
+        let mapped = clock.map(last, at(11 * PERIOD_US + 5_000));
+        assert_eq!(mapped.timestamp_us, last);
+        assert_eq!(mapped.time, at(10 * PERIOD_US));
+        assert_eq!(
+            clock.map(15_000, at(12 * PERIOD_US + 1_000)).timestamp_us,
+            (1 << 32) + 15_000
+        );
+    }
+
+    #[test]
+    fn detects_a_reset_to_near_zero() {
+        let mut clock = clock_with_reports(1_000_000, 1_050_000);
+        let mapped = clock.map(10_000, at(60_000));
+        assert!(mapped.reset);
+        assert_eq!(mapped.time, at(60_000));
+        assert_eq!(mapped.timestamp_us, 10_000);
+        let status = clock.status();
+        assert_eq!(status.resets, 1);
+        assert_eq!(status.anchored_at, Some(at(60_000)));
+    }
+
+    #[test]
+    fn detects_a_reset_far_back() {
+        let mut clock = clock_with_reports(10_000_000, 10_050_000);
+        let mapped = clock.map(2_000_000, at(60_000));
+        assert!(mapped.reset);
+        assert_eq!(clock.status().resets, 1);
+    }
+
+    #[test]
+    fn maps_a_late_report_without_a_reset() {
+        let mut clock = clock_with_reports(1_000_000, 1_050_000);
+        let anchored_at = clock.status().anchored_at;
+
+        // A report from 3 periods ago arriving again
+        let mapped = clock.map(1_020_000, at(61_000));
+        assert!(!mapped.reset);
+        assert_eq!(mapped.timestamp_us, 1_020_000);
+        assert_eq!(mapped.time, at(20_000));
+        let status = clock.status();
+        assert_eq!(status.resets, 0);
+        assert_eq!(status.anchored_at, anchored_at);
+        assert_eq!(status.mcu_timestamp_us, 1_050_000);
+
+        // The next report continues from the latest one
+        let mapped = clock.map(1_060_000, at(61_000));
+        assert_eq!(mapped.time, at(60_000));
+    }
+
+    #[test]
+    fn resyncs_when_the_offset_is_too_large() {
+        let mut clock = clock_with_reports(0, 50_000);
+        let received = at(60_000 + 2 * MAX_CLOCK_OFFSET_US as u64);
+        let mapped = clock.map(60_000, received);
+        assert!(!mapped.reset);
+        assert_eq!(mapped.time, received);
+        let status = clock.status();
+        assert_eq!(status.resyncs, 1);
+        assert_eq!(status.anchored_at, Some(received));
+    }
+
+    #[test]
+    fn estimates_the_drift_of_a_slow_mcu() {
+        // The MCU clock runs 100 ppm slow: 1 s on the MCU takes 1.0001 s on the host
+        let mut clock = McuClock::default();
+        for second in 0..=600u64 {
+            let timestamp = second * 1_000_000;
+            clock.map(timestamp, at(timestamp + second * 100 + 1_000));
+        }
+        let drift_ppm = clock.status().drift_ppm;
+        assert!(drift_ppm > 30.0 && drift_ppm < 110.0, "drift {drift_ppm}");
+
+        // An MCU running in step with the host has no drift
+        let mut clock = clock_with_reports(0, 70_000_000);
+        assert!(clock.status().drift_ppm.abs() < 1.0);
+        assert!(!clock.map(70_010_000, at(70_011_000)).reset);
+    }
+}
@@ -1,20 +1,33 @@
+use crate::config::config;
 use crate::control::ControllerReport;
-use chrono::TimeDelta;
+use crate::control::clock::McuClock;
+use crate::control::history::BufferedReport;
+use crate::messages::db_messages::DatabaseMessage;
+use crate::messages::frontend_messages;
+use crate::messages::system_events::{SystemEvent, publish_event};
+use chrono::{DateTime, Utc};
 use love_letter::{Report, Setpoint};
 use tokio::{
     sync::{
         mpsc::{self},
         watch,
     },
-    time::{Duration, timeout},
+    time::timeout,
 };
 use tracing::*;
 
-use crate::{axumstate::AxumState, experiment::Experiment};
+use crate::{
+    axumstate::AxumState,
+    experiment::{
+        Experiment, ExperimentCommand,
+        quality::{Cadence, CadenceTracker, DataQuality},
+    },
+};
 
-/// Bounds the maximum duration between consecutive setpoints / reports
-const COMMS_TIMEOUT: Duration = Duration::from_millis(2000);
-const CONTROL_LOOP_PERIOD: Duration = Duration::from_millis(10);
+/// Fraction of the DB queue that has to fill up before a backlog is reported
+const DB_BACKLOG_WARNING: f64 = 0.8;
+/// Fraction of the DB queue that may remain filled once a backlog is cleared
+const DB_BACKLOG_CLEARED: f64 = 0.5;
 
 /// High level control loop for the HHH SBC, responsible for:
 /// * Parsing received MCU reports
@@ -28,18 +41,54 @@ pub async fn control_loop(
     mcu_setpoint_sender: watch::Sender<Setpoint>,
     mut experiment_receiver: watch::Receiver<Option<Experiment>>,
     axum_state: AxumState,
-    db_report_sender: mpsc::Sender<ControllerReport>,
+    db_report_sender: mpsc::Sender<DatabaseMessage>,
 ) {
-    let mut ticker = tokio::time::interval(CONTROL_LOOP_PERIOD);
+    let mut ticker = tokio::time::interval(config().control_loop_period());
 
     let mut current_experiment: Option<Experiment> = None;
 
+    // Number of reports logged for the current experiment, used to evaluate its stop conditions
+    let mut logged_samples: u64 = 0;
+    let mut stop_requested = false;
+
+    // Checks the logged reports against the MCU report cadence to detect gaps and duplicates
+    let mut cadence: Option<CadenceTracker> = None;
+
+    // Maps the MCU timestamps onto wall-clock time, anchored on the first report
+    let mut mcu_clock = McuClock::default();
+
+    // Reported through the event stream whenever they change
+    let mut mcu_link_up = false;
+    let mut db_backlog = false;
+
     loop {
         // Did the experiment change?
         if experiment_receiver.has_changed().unwrap_or(false) {
             // Ask the experiment manager for the current experiment status
             info!("Experiment change detected");
-            current_experiment = (*experiment_receiver.borrow_and_update()).clone();
+            let new_experiment = (*experiment_receiver.borrow_and_update()).clone();
+
+            // Pausing or resuming keeps the experiment, only a new experiment resets the count
+            if new_experiment.as_ref().map(|e| e.id) != current_experiment.as_ref().map(|e| e.id) {
+                logged_samples = 0;
+                stop_requested = false;
+
+                let experiment_id = new_experiment.as_ref().map(|e| e.id);
+                cadence = experiment_id.map(CadenceTracker::new);
+                if let Ok(mut quality) = axum_state.data_quality.lock() {
+                    *quality = DataQuality::new(experiment_id.unwrap_or_default());
+                }
+
+                // An experiment may start in the past, log what was buffered since
+                if let Some(ref experiment) = new_experiment
+                    && experiment.is_running
+                    && !experiment.is_paused
+                {
+                    logged_samples +=
+                        backfill_experiment(experiment, &axum_state, &db_report_sender).await;
+                }
+            }
+            current_experiment = new_experiment;
 
             // Update the current experiment in AxumState for the GET /experiment/status endpoint
             if let Ok(mut experiment) = axum_state.current_experiment.lock() {
@@ -61,20 +110,29 @@ pub async fn control_loop(
         }
 
         // Parse MCU report received from the mcu communcation task
-        match timeout(COMMS_TIMEOUT, mcu_report_receiver.recv()).await {
+        match timeout(config().comms_timeout(), mcu_report_receiver.recv()).await {
             Ok(Some(mcu_report)) => {
                 info!("Received MCU report: {:?}", mcu_report.clone());
+                let mcu_timestamp = mcu_report.measurements.timestamp;
+                if !mcu_link_up {
+                    info!("MCU link is up");
+                    mcu_link_up = true;
+                    publish_event(&axum_state.event_broadcast, SystemEvent::McuLinkUp);
+                }
 
-                // The MCU provides only an offset in micros since firwmare start:
-                // Compute the right report timestamp
-                let report_time = *axum_state.start_time
-                    + TimeDelta::microseconds(
-                        mcu_report
-                            .measurements
-                            .timestamp
-                            .try_into()
-                            .unwrap_or(i64::MAX), // Breaks after 17598506CE, should be ok :)
-                    );
+                // The MCU provides only an offset in micros since firmware start:
+                // Map it onto the host clock
+                let mcu_time = mcu_clock.map(mcu_timestamp, Utc::now());
+                let report_time = mcu_time.time;
+                if let Ok(mut clock) = axum_state.mcu_clock.lock() {
+                    *clock = mcu_clock.status();
+                }
+                if mcu_time.reset {
+                    warn!("MCU clock went back from an earlier report, the MCU was reset");
+                    if let Some(cadence) = cadence.as_mut() {
+                        cadence.mcu_reset();
+                    }
+                }
                 let report = ControllerReport::from_mcu_report(
                     mcu_report,
                     report_time,
@@ -83,29 +141,105 @@ pub async fn control_loop(
                 info!("Exposing Controller Report to axum: {:?}", report.clone());
 
                 // Update /measurement endpoint with latest report
+                let frontend_report: frontend_messages::Report = report.clone().into();
                 if let Ok(mut axum_report) = axum_state.report.lock() {
-                    *axum_report = Some(report.clone().into());
+                    *axum_report = Some(frontend_report.clone());
+                }
+
+                // Stream every report to the websocket clients, sending fails if none are connected
+                let _ = axum_state.report_broadcast.send(frontend_report);
+
+                // Keep the report in memory, so it can be replayed or logged retroactively
+                if let Ok(mut history) = axum_state.history.lock() {
+                    history.push(BufferedReport::from(&report));
+                } else {
+                    error!("Failed to update report history in AxumState");
                 }
 
                 // If an experiment is currently running: Update the DB
                 if let Some(Experiment {
                     is_running,
+                    is_paused,
                     ref id,
                     ref name,
                     ref duration_seconds,
+                    ref stop_conditions,
                     ..
                 }) = current_experiment
                 {
                     info!("An Experiment is running: writing to DB");
-                    if is_running {
+                    // Check the report against the expected cadence before logging it
+                    let cadence_check = match cadence.as_mut() {
+                        Some(cadence) if is_running && is_paused => {
+                            cadence.pause();
+                            Cadence::OnTime
+                        }
+                        Some(cadence) if is_running => {
+                            check_cadence(cadence, mcu_time.timestamp_us, report.time, &axum_state)
+                        }
+                        _ => Cadence::OnTime,
+                    };
+
+                    if is_running && is_paused {
+                        info!("Experiment {id} - {name} is paused, skipping DB write...");
+                    } else if let Cadence::Duplicate = cadence_check {
+                        warn!(
+                            "Experiment {id} - {name} received a duplicate MCU report, skipping DB write..."
+                        );
+                    } else if is_running {
+                        if let Cadence::Gap(gap) = cadence_check {
+                            warn!(
+                                "Experiment {id} - {name} lost MCU reports from {} to {} ({})",
+                                gap.start,
+                                gap.end,
+                                gap.cause.as_str()
+                            );
+                            if let Err(err) =
+                                db_report_sender.send(DatabaseMessage::DataGap(gap)).await
+                            {
+                                error!("Unable to send data gap to database task: {err}");
+                            }
+                        }
+
                         info!(
                             "Experiment {id} - {name} is running for {}s, writing report to DB: {:?}",
                             duration_seconds.as_seconds_f32(),
                             report.clone()
                         );
                         // Write latest report to db
-                        if let Err(err) = db_report_sender.send(report).await {
+                        if let Err(err) =
+                            db_report_sender.send(DatabaseMessage::Report(report)).await
+                        {
                             error!("Unable to send latest report to database task: {err}");
+                            if let Ok(mut quality) = axum_state.data_quality.lock() {
+                                quality.lost_samples += 1;
+                            }
+                        } else {
+                            logged_samples += 1;
+                            check_db_backlog(&db_report_sender, &mut db_backlog, &axum_state);
+                        }
+
+                        // Ask the experiment manager to stop once a stop condition is met
+                        let active_duration = current_experiment
+                            .as_ref()
+                            .map(|e| e.active_duration_at(Utc::now()))
+                            .unwrap_or_default();
+                        if !stop_requested
+                            && stop_conditions.is_met(active_duration, logged_samples)
+                        {
+                            info!(
+                                "Experiment {id} - {name} met its stop conditions after {logged_samples} samples, stopping"
+                            );
+                            // Never block the control loop, a full queue is retried next report
+                            match axum_state
+                                .experiment_commands
+                                .try_send(ExperimentCommand::Stop)
+                            {
+                                Ok(()) => stop_requested = true,
+                                Err(err) => error!(
+                                    "Unable to stop experiment after meeting stop conditions: {err}"
+                                ),
+                            }
                         }
                     }
                 } else {
@@ -117,9 +251,106 @@ pub async fn control_loop(
             }
             Err(err) => {
                 error!("timeout waiting on report from mcu comms task: {err}");
+                if mcu_link_up {
+                    mcu_link_up = false;
+                    publish_event(&axum_state.event_broadcast, SystemEvent::McuLinkDown);
+                }
+                if let Some(cadence) = cadence.as_mut() {
+                    cadence.timeout();
+                }
             }
         }
 
         ticker.tick().await;
     }
 }
+
+/// Check a logged report against the MCU report cadence and update the data-quality counters
+fn check_cadence(
+    cadence: &mut CadenceTracker,
+    mcu_timestamp: u64,
+    report_time: DateTime<Utc>,
+    axum_state: &AxumState,
+) -> Cadence {
+    let Ok(mut quality) = axum_state.data_quality.lock() else {
+        error!("Failed to update data quality in AxumState");
+        return Cadence::OnTime;
+    };
+
+    let checked = cadence.check(mcu_timestamp, report_time, &mut quality);
+    if let Cadence::Gap(ref gap) = checked {
+        quality.record_gap(gap);
+    }
+    checked
+}
+
+/// Send the buffered reports received since the experiment started to the DB task
+/// Returns the number of reports sent
+async fn backfill_experiment(
+    experiment: &Experiment,
+    axum_state: &AxumState,
+    db_report_sender: &mpsc::Sender<DatabaseMessage>,
+) -> u64 {
+    let buffered = match axum_state.history.lock() {
+        Ok(history) => history.since(experiment.start_time),
+        Err(_) => {
+            error!("Failed to read report history from AxumState");
+            return 0;
+        }
+    };
+    let reports: Vec<ControllerReport> = buffered
+        .into_iter()
+        .map(|report| report.with_experiment(experiment.clone()))
+        .collect();
+
+    if reports.is_empty() {
+        return 0;
+    }
+    let count = reports.len() as u64;
+    info!(
+        "Experiment {} - {} includes {count} buffered reports since {}",
+        experiment.id, experiment.name, experiment.start_time
+    );
+
+    let sent = db_report_sender
+        .send(DatabaseMessage::Backfill(reports))
+        .await;
+    if let Ok(mut quality) = axum_state.data_quality.lock() {
+        if sent.is_ok() {
+            quality.samples += count;
+        } else {
+            quality.lost_samples += count;
+        }
+    }
+    match sent {
+        Ok(()) => count,
+        Err(err) => {
+            error!("Unable to send buffered reports to database task: {err}");
+            0
+        }
+    }
+}
+
+/// Report a backlog through the event stream when the DB queue fills up, and when it drains
+fn check_db_backlog(
+    db_report_sender: &mpsc::Sender<DatabaseMessage>,
+    db_backlog: &mut bool,
+    axum_state: &AxumState,
+) {
+    let capacity = db_report_sender.max_capacity();
+    let queued = capacity - db_report_sender.capacity();
+    let filled = queued as f64 / capacity as f64;
+
+    if !*db_backlog && filled >= DB_BACKLOG_WARNING {
+        warn!("DB task is falling behind, {queued} of {capacity} reports queued");
+        *db_backlog = true;
+        publish_event(
+            &axum_state.event_broadcast,
+            SystemEvent::DbBacklog { queued, capacity },
+        );
+    } else if *db_backlog && filled <= DB_BACKLOG_CLEARED {
+        info!("DB task caught up, {queued} of {capacity} reports queued");
+        *db_backlog = false;
+        publish_event(&axum_state.event_broadcast, SystemEvent::DbBacklogCleared);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use love_letter::Measurements;
+
+use crate::control::ControllerReport;
+use crate::experiment::Experiment;
+use crate::experiment::quality::REPORT_PERIOD;
+use crate::messages::frontend_messages::{HeartControllerSetpoint, MockloopSetpoint};
+
+/// Number of seconds of reports kept in memory
+pub const HISTORY_SECONDS: u64 = 300;
+
+/// Number of seconds of reports returned when a client does not ask for a length
+pub const DEFAULT_HISTORY_SECONDS: u64 = 30;
+
+/// Report kept in the history, without the experiment it was received during
+#[derive(Clone, Debug)]
+pub struct BufferedReport {
+    pub mockloop_setpoint: MockloopSetpoint,
+    pub heart_controller_setpoint: HeartControllerSetpoint,
+    pub measurements: Measurements,
+    pub time: DateTime<Utc>,
+}
+
+impl BufferedReport {
+    /// Report as received during `experiment`
+    pub fn with_experiment(self, experiment: Experiment) -> ControllerReport {
+        ControllerReport {
+            mockloop_setpoint: self.mockloop_setpoint,
+            heart_controller_setpoint: self.heart_controller_setpoint,
+            measurements: self.measurements,
+            experiment,
+            time: self.time,
+        }
+    }
+}
+
+impl From<&ControllerReport> for BufferedReport {
+    fn from(report: &ControllerReport) -> Self {
+        Self {
+            mockloop_setpoint: report.mockloop_setpoint,
+            heart_controller_setpoint: report.heart_controller_setpoint.clone(),
+            measurements: report.measurements.clone(),
+            time: report.time,
+        }
+    }
+}
+
+/// Ring buffer of the most recent reports, kept whether or not an experiment is running
+#[derive(Debug, Clone)]
+pub struct ReportHistory {
+    reports: VecDeque<BufferedReport>,
+    capacity: usize,
+}
+
+impl Default for ReportHistory {
+    fn default() -> Self {
+        let capacity = (HISTORY_SECONDS as u128 * 1000 / REPORT_PERIOD.as_millis()) as usize;
+        Self {
+            reports: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+}
+
+impl ReportHistory {
+    /// Add the latest report, dropping reports that are too many or too old
+    pub fn push(&mut self, report: BufferedReport) {
+        let oldest = report.time - TimeDelta::seconds(HISTORY_SECONDS as i64);
+        while self.reports.len() >= self.capacity
+            || self.reports.front().is_some_and(|r| r.time < oldest)
+        {
+            self.reports.pop_front();
+        }
+        self.reports.push_back(report);
+    }
+
+    /// Reports received at or after `from`, oldest first
+    pub fn since(&self, from: DateTime<Utc>) -> Vec<BufferedReport> {
+        self.reports
+            .iter()
+            .filter(|r| r.time >= from)
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,319 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::time::{self, Duration};
+use tracing::*;
+use utoipa::ToSchema;
+
+use crate::axumstate::AxumState;
+use crate::messages::system_events::{SystemEvent, publish_event};
+
+/// Time a lease lasts after it was acquired, renewed or last used
+pub const LEASE_SECONDS: i64 = 30;
+/// Period at which expired leases are dropped
+const LEASE_CHECK_PERIOD: Duration = Duration::from_secs(1);
+/// Number of random bytes in a lease token
+const LEASE_TOKEN_BYTES: usize = 16;
+
+/// Right of a single client to change the setpoints and run experiments
+#[derive(Debug, Clone)]
+struct Lease {
+    /// Only known to the client holding the lease
+    token: String,
+    owner: String,
+    acquired_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+/// Lease as shown to every client, without its token
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct LeaseStatus {
+    /// User holding the lease, None if nobody is in control
+    pub owner: Option<String>,
+    pub acquired_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Lease as returned to the client that acquired it
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LeaseGrant {
+    /// Sent as `X-Control-Lease` header with every setpoint and experiment command
+    pub token: String,
+    pub owner: String,
+    pub acquired_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// How the holder of the lease changed
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaseChange {
+    Acquired,
+    Released,
+    Expired,
+    /// An admin took the lease from its holder
+    TakenOver,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LeaseError {
+    #[error("control is held by {0} until {1}")]
+    Held(String, DateTime<Utc>),
+    #[error("a control lease is required, acquire one at POST /control/lease")]
+    Required,
+    #[error("the control lease expired or was taken over")]
+    NotHolder,
+}
+
+/// Holder of the control lease, if any
+#[derive(Debug, Clone, Default)]
+pub struct ControlLease {
+    lease: Option<Lease>,
+}
+
+impl ControlLease {
+    pub fn status(&self) -> LeaseStatus {
+        match &self.lease {
+            Some(lease) => LeaseStatus {
+                owner: Some(lease.owner.clone()),
+                acquired_at: Some(lease.acquired_at),
+                expires_at: Some(lease.expires_at),
+            },
+            None => LeaseStatus::default(),
+        }
+    }
+
+    /// Acquire the lease for `owner`, or renew it if `token` is the current lease token
+    /// A lease held by another client is only replaced with `takeover`
+    /// Returns the grant, and the change to announce if the holder changed
+    pub fn acquire(
+        &mut self,
+        owner: &str,
+        token: Option<&str>,
+        takeover: bool,
+    ) -> Result<(LeaseGrant, Option<LeaseChange>), LeaseError> {
+        let now = Utc::now();
+        let expires_at = now + chrono::Duration::seconds(LEASE_SECONDS);
+
+        let change = match &mut self.lease {
+            Some(lease) if lease.expires_at > now && token == Some(lease.token.as_str()) => {
+                lease.expires_at = expires_at;
+                None
+            }
+            Some(lease) if lease.expires_at > now && !takeover => {
+                return Err(LeaseError::Held(lease.owner.clone(), lease.expires_at));
+            }
+            Some(lease) if lease.expires_at > now => Some(LeaseChange::TakenOver),
+            _ => Some(LeaseChange::Acquired),
+        };
+        if change.is_some() {
+            self.lease = Some(Lease {
+                token: generate_lease_token(),
+                owner: owner.to_string(),
+                acquired_at: now,
+                expires_at,
+            });
+        }
+
+        let lease = self.lease.as_ref().ok_or(LeaseError::Required)?;
+        Ok((
+            LeaseGrant {
+                token: lease.token.clone(),
+                owner: lease.owner.clone(),
+                acquired_at: lease.acquired_at,
+                expires_at: lease.expires_at,
+            },
+            change,
+        ))
+    }
+
+    /// Give up the lease, `force` releases it whoever holds it
+    /// Returns the owner of the released lease, None if nobody held it
+    pub fn release(
+        &mut self,
+        token: Option<&str>,
+        force: bool,
+    ) -> Result<Option<String>, LeaseError> {
+        match &self.lease {
+            Some(lease) if force || token == Some(lease.token.as_str()) => {
+                Ok(self.lease.take().map(|l| l.owner))
+            }
+            Some(_) if token.is_some() => Err(LeaseError::NotHolder),
+            Some(_) => Err(LeaseError::Required),
+            None => Ok(None),
+        }
+    }
+
+    /// Check that `token` is the token of a live lease, and extend the lease as it is in use
+    pub fn check(&mut self, token: Option<&str>) -> Result<(), LeaseError> {
+        let now = Utc::now();
+        match (&mut self.lease, token) {
+            (Some(lease), Some(token)) if lease.expires_at > now && lease.token == token => {
+                lease.expires_at = now + chrono::Duration::seconds(LEASE_SECONDS);
+                Ok(())
+            }
+            (_, Some(_)) => Err(LeaseError::NotHolder),
+            (_, None) => Err(LeaseError::Required),
+        }
+    }
+
+    /// Drop the lease if it expired, returning its owner
+    fn expire(&mut self, now: DateTime<Utc>) -> Option<String> {
+        if self.lease.as_ref().is_some_and(|l| l.expires_at <= now) {
+            self.lease.take().map(|l| l.owner)
+        } else {
+            None
+        }
+    }
+}
+
+/// Periodically drop the control lease once it expired, and tell every client
+pub async fn expire_control_lease(state: AxumState) {
+    let mut ticker = time::interval(LEASE_CHECK_PERIOD);
+
+    loop {
+        ticker.tick().await;
+
+        let expired = match state.control_lease.lock() {
+            Ok(mut lease) => lease.expire(Utc::now()),
+            Err(_) => {
+                error!("Unable to lock the control lease, stopping lease expiry");
+                return;
+            }
+        };
+        if let Some(owner) = expired {
+            info!("Control lease of {owner} expired");
+            publish_event(
+                &state.event_broadcast,
+                SystemEvent::ControlLeaseChanged {
+                    change: LeaseChange::Expired,
+                    owner: None,
+                    previous_owner: Some(owner),
+                },
+            );
+        }
+    }
+}
+
+/// Random lease token, hex encoded
+fn generate_lease_token() -> String {
+    rand::random::<[u8; LEASE_TOKEN_BYTES]>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lease held by `owner`, with its token
+    fn held_by(owner: &str) -> (ControlLease, String) {
+        let mut lease = ControlLease::default();
+        let (grant, change) = lease.acquire(owner, None, false).unwrap();
+        assert_eq!(change, Some(LeaseChange::Acquired));
+        (lease, grant.token)
+    }
+
+    #[test]
+    fn acquires_free_lease() {
+        let (lease, token) = held_by("alice");
+        assert_eq!(token.len(), 2 * LEASE_TOKEN_BYTES);
+        let status = lease.status();
+        assert_eq!(status.owner.as_deref(), Some("alice"));
+        let lasts = status.expires_at.unwrap() - status.acquired_at.unwrap();
+        assert_eq!(lasts, chrono::Duration::seconds(LEASE_SECONDS));
+    }
+
+    #[test]
+    fn renews_with_token() {
+        let (mut lease, token) = held_by("alice");
+        let before = lease.status().expires_at.unwrap();
+        let (grant, change) = lease.acquire("alice", Some(&token), false).unwrap();
+        assert_eq!(change, None);
+        assert_eq!(grant.token, token);
+        assert!(grant.expires_at >= before);
+    }
+
+    #[test]
+    fn refuses_held_lease() {
+        let (mut lease, token) = held_by("alice");
+        let err = lease.acquire("bob", None, false).unwrap_err();
+        assert!(matches!(err, LeaseError::Held(ref owner, _) if owner == "alice"));
+        let err = lease.acquire("bob", Some("guess"), false).unwrap_err();
+        assert!(matches!(err, LeaseError::Held(..)));
+        assert_eq!(lease.check(Some(&token)), Ok(()));
+    }
+
+    #[test]
+    fn takeover_replaces_holder() {
+        let (mut lease, token) = held_by("alice");
+        let (grant, change) = lease.acquire("admin", None, true).unwrap();
+        assert_eq!(change, Some(LeaseChange::TakenOver));
+        assert_eq!(grant.owner, "admin");
+        assert_ne!(grant.token, token);
+        assert_eq!(lease.check(Some(&token)), Err(LeaseError::NotHolder));
+        assert_eq!(lease.check(Some(&grant.token)), Ok(()));
+    }
+
+    #[test]
+    fn release_requires_token_unless_forced() {
+        let (mut lease, token) = held_by("alice");
+        assert_eq!(lease.release(None, false), Err(LeaseError::Required));
+        assert_eq!(
+            lease.release(Some("guess"), false),
+            Err(LeaseError::NotHolder)
+        );
+        assert_eq!(
+            lease.release(Some(&token), false),
+            Ok(Some("alice".to_string()))
+        );
+        assert_eq!(lease.release(None, false), Ok(None));
+        assert_eq!(lease.check(Some(&token)), Err(LeaseError::NotHolder));
+
+        let (mut lease, _) = held_by("alice");
+        assert_eq!(lease.release(None, true), Ok(Some("alice".to_string())));
+        assert_eq!(lease.status().owner, None);
+    }
+
+    #[test]
+    fn check_requires_token() {
+        let (mut lease, token) = held_by("alice");
+        assert_eq!(lease.check(None), Err(LeaseError::Required));
+        assert_eq!(lease.check(Some("guess")), Err(LeaseError::NotHolder));
+        assert_eq!(lease.check(Some(&token)), Ok(()));
+        assert_eq!(
+            ControlLease::default().check(None),
+            Err(LeaseError::Required)
+        );
+    }
+
+    #[test]
+    fn expires_after_lease_seconds() {
+        let (mut lease, token) = held_by("alice");
+        let expires_at = lease.status().expires_at.unwrap();
+        assert_eq!(
+            lease.expire(expires_at - chrono::Duration::seconds(1)),
+            None
+        );
+        assert_eq!(lease.expire(expires_at), Some("alice".to_string()));
+        assert_eq!(lease.status().owner, None);
+        assert_eq!(lease.expire(expires_at), None);
+        assert_eq!(lease.check(Some(&token)), Err(LeaseError::NotHolder));
+    }
+
+    #[test]
+    fn expired_lease_is_not_renewed() {
+        let (mut lease, token) = held_by("alice");
+        if let Some(held) = lease.lease.as_mut() {
+            held.expires_at = Utc::now() - chrono::Duration::seconds(1);
+        }
+        assert_eq!(lease.check(Some(&token)), Err(LeaseError::NotHolder));
+
+        // An expired lease is free, even before the expiry task drops it
+        let (grant, change) = lease.acquire("bob", Some(&token), false).unwrap();
+        assert_eq!(change, Some(LeaseChange::Acquired));
+        assert_eq!(grant.owner, "bob");
+        assert_ne!(grant.token, token);
+    }
+}
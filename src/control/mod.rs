@@ -6,7 +6,10 @@ use crate::{
     messages::frontend_messages::{HeartControllerSetpoint, MockloopSetpoint},
 };
 
+pub mod clock;
 pub mod controller;
+pub mod history;
+pub mod lease;
 
 #[derive(Clone, Debug)]
 pub struct ControllerReport {
@@ -1,16 +1,22 @@
 use influxdb::{Client, InfluxDbWriteable as _, WriteQuery};
 use serde_json::Value;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::Receiver;
 use tokio::time::{self, Duration};
 use tracing::*;
 
-use crate::control::ControllerReport;
+use crate::config::config;
 use crate::database::secrets::*;
-use crate::messages::db_messages::DatabaseRecord;
+use crate::experiment::gaps::{DataGap, GapCause};
+use crate::experiment::quality::DataQuality;
+use crate::messages::db_messages::{
+    AnnotationRecord, CatalogueRecord, DataGapRecord, DatabaseMessage, DatabaseRecord,
+    SetpointEventRecord, SummaryRecord,
+};
 
 const DB_LOOP_PERIOD: Duration = Duration::from_millis(10);
-const QUERY_BATCH_LEN: usize = 10;
+/// Number of buffered reports written per query when an experiment includes earlier reports
+const BACKFILL_BATCH_LEN: usize = 500;
 
 #[derive(Debug)]
 pub enum DBCommsError {
@@ -23,58 +29,193 @@ pub enum DBCommsError {
     ParseTimeStamp(String),
 }
 
-/// Log recorded sensor data and logs to the database
-pub async fn communicate_with_db(mut db_report_receiver: Receiver<ControllerReport>) {
+/// Log recorded sensor data, setpoint events and logs to the database
+/// Batches that fail to be written are recorded as data gaps in `data_quality`
+pub async fn communicate_with_db(
+    mut db_receiver: Receiver<DatabaseMessage>,
+    data_quality: Arc<Mutex<DataQuality>>,
+) {
     // Loop timekeeping
     let mut ticker = time::interval(DB_LOOP_PERIOD);
 
     // Initialize DB connection
-    let db_client = Arc::new(Client::new(DB_URI, DB_NAME).with_token(DB_ACCESS_TOKEN));
+    let db_client = Arc::new(
+        Client::new(&config().db_uri, &config().db_name).with_token(&config().db_access_token),
+    );
 
     // Initialize local state
-    let mut batched_data = Vec::with_capacity(QUERY_BATCH_LEN);
+    let mut batched_data = Vec::with_capacity(config().query_batch_len);
     let mut fall_back_storage = Vec::new();
+    // Time of the first report in the current batch
+    let mut batch_start = None;
+    // Data gaps that could not be written yet, retried after the next successful write
+    let mut pending_gaps: Vec<DataGap> = Vec::new();
 
     info!("initialized DB task, waiting for experiment start");
 
     // Main routine
     loop {
-        // Wait to receive report from the controller task task:
-        // This means an experiment is running and we need to log the measurements to the DB
-        if let Some(report) = db_report_receiver.recv().await {
-            // Batch received measurements
-            batched_data.push(DatabaseRecord::from(report.clone()));
-            info!("batched_query {:?}", batched_data);
-
-            // Write measurements to DB when batch is filled
-            if batched_data.len() >= QUERY_BATCH_LEN {
-                let query: Vec<WriteQuery> = batched_data
-                    .clone()
-                    .into_iter()
-                    .map(|el| el.into_query(report.experiment.table_name.clone()))
-                    .collect();
+        match db_receiver.recv().await {
+            // Report received from the controller task:
+            // This means an experiment is running and we need to log the measurements to the DB
+            Some(DatabaseMessage::Report(report)) => {
+                // Batch received measurements
+                batch_start.get_or_insert(report.time);
+                batched_data.push(DatabaseRecord::from(report.clone()));
+                info!("batched_query {:?}", batched_data);
 
-                match db_client.query(query).await {
-                    Ok(_) => info!("Inserted Batched measurements into the DB"),
-                    Err(err) => {
-                        error!(
-                            "Error inserting batched measurements into the DB: {:?} - using fallback",
-                            err
-                        );
-
-                        // Write to fallback hashmap
-                        fall_back_storage.append(&mut batched_data);
+                // Write measurements to DB when batch is filled
+                if batched_data.len() >= config().query_batch_len {
+                    let query: Vec<WriteQuery> = batched_data
+                        .clone()
+                        .into_iter()
+                        .map(|el| el.into_query(report.experiment.table_name.clone()))
+                        .collect();
+
+                    match db_client.query(query).await {
+                        Ok(_) => {
+                            info!("Inserted Batched measurements into the DB");
+                            pending_gaps = write_data_gaps(&db_client, pending_gaps).await;
+                        }
+                        Err(err) => {
+                            error!(
+                                "Error inserting batched measurements into the DB: {:?} - using fallback",
+                                err
+                            );
+
+                            // Record the hole this leaves in the experiment data
+                            let gap = DataGap {
+                                experiment_id: report.experiment.id,
+                                start: batch_start.unwrap_or(report.time),
+                                end: report.time,
+                                cause: GapCause::DbFailure,
+                            };
+                            if let Ok(mut quality) = data_quality.lock() {
+                                quality.record_db_failure(
+                                    gap.experiment_id,
+                                    batched_data.len() as u64,
+                                );
+                                quality.record_gap(&gap);
+                            }
+                            pending_gaps.push(gap);
+
+                            // Write to fallback hashmap
+                            fall_back_storage.append(&mut batched_data);
+                        }
                     }
+                    batched_data.clear();
+                    batch_start = None;
                 }
-                batched_data.clear();
             }
-        } else {
-            error!(
-                "DB write error: unable to receive report from controller task - Receiver is closed"
-            );
+            // Reports received before the experiment was started: log them in large batches
+            Some(DatabaseMessage::Backfill(reports)) => {
+                for chunk in reports.chunks(BACKFILL_BATCH_LEN) {
+                    let (Some(first), Some(last)) = (chunk.first(), chunk.last()) else {
+                        continue;
+                    };
+                    let query: Vec<WriteQuery> = chunk
+                        .iter()
+                        .map(|report| {
+                            DatabaseRecord::from(report.clone())
+                                .into_query(report.experiment.table_name.clone())
+                        })
+                        .collect();
+
+                    match db_client.query(query).await {
+                        Ok(_) => {
+                            info!("Inserted {} buffered measurements into the DB", chunk.len())
+                        }
+                        Err(err) => {
+                            error!(
+                                "Error inserting buffered measurements into the DB: {:?}",
+                                err
+                            );
+                            let gap = DataGap {
+                                experiment_id: first.experiment.id,
+                                start: first.time,
+                                end: last.time,
+                                cause: GapCause::DbFailure,
+                            };
+                            if let Ok(mut quality) = data_quality.lock() {
+                                quality.record_db_failure(gap.experiment_id, chunk.len() as u64);
+                                quality.record_gap(&gap);
+                            }
+                            pending_gaps.push(gap);
+                        }
+                    }
+                }
+            }
+            // Setpoint change received from the http handlers: log it immediately
+            Some(DatabaseMessage::SetpointEvent(event)) => {
+                let query = SetpointEventRecord::from(event).into_query(SETPOINT_EVENT_TABLE);
+                match db_client.query(query).await {
+                    Ok(_) => info!("Inserted setpoint event into the DB"),
+                    Err(err) => error!("Error inserting setpoint event into the DB: {:?}", err),
+                }
+            }
+            // Annotation received from the http handlers: log it immediately
+            Some(DatabaseMessage::Annotation(annotation)) => {
+                let query = AnnotationRecord::from(annotation).into_query(ANNOTATION_TABLE);
+                match db_client.query(query).await {
+                    Ok(_) => info!("Inserted annotation into the DB"),
+                    Err(err) => error!("Error inserting annotation into the DB: {:?}", err),
+                }
+            }
+            // Data gap detected by another task: log it immediately
+            Some(DatabaseMessage::DataGap(gap)) => {
+                pending_gaps.push(gap);
+                pending_gaps = write_data_gaps(&db_client, pending_gaps).await;
+            }
+            // Experiment metadata edited through the http handlers: log it immediately
+            Some(DatabaseMessage::Catalogue(entry)) => {
+                let query = CatalogueRecord::from(entry).into_query(CATALOGUE_TABLE);
+                match db_client.query(query).await {
+                    Ok(_) => info!("Inserted catalogue entry into the DB"),
+                    Err(err) => error!("Error inserting catalogue entry into the DB: {:?}", err),
+                }
+            }
+            // Summary of an experiment that just stopped: log it immediately
+            Some(DatabaseMessage::Summary(summary)) => {
+                let query = SummaryRecord::from(*summary).into_query(SUMMARY_TABLE);
+                match db_client.query(query).await {
+                    Ok(_) => info!("Inserted experiment summary into the DB"),
+                    Err(err) => error!("Error inserting experiment summary into the DB: {:?}", err),
+                }
+            }
+            None => {
+                error!(
+                    "DB write error: unable to receive message from other tasks - Receiver is closed"
+                );
+            }
         }
 
         // Loop timekeeping
         ticker.tick().await;
     }
 }
+
+/// Write data gaps to the DB, returns the gaps that could not be written
+async fn write_data_gaps(db_client: &Client, gaps: Vec<DataGap>) -> Vec<DataGap> {
+    if gaps.is_empty() {
+        return gaps;
+    }
+
+    let query: Vec<WriteQuery> = gaps
+        .iter()
+        .cloned()
+        .map(|gap| DataGapRecord::from(gap).into_query(DATA_GAP_TABLE))
+        .collect();
+    match db_client.query(query).await {
+        Ok(_) => {
+            info!("Inserted {} data gap(s) into the DB", gaps.len());
+            Vec::new()
+        }
+        Err(err) => {
+            error!(
+                "Error inserting data gaps into the DB: {:?} - retrying later",
+                err
+            );
+            gaps
+        }
+    }
+}
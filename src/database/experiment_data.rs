@@ -0,0 +1,150 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::database::query::{query_sql, quote_identifier};
+
+/// Sensor channels stored in every experiment table, returned when no columns are requested
+pub const SENSOR_COLUMNS: &[&str] = &[
+    "pulmonary_preload_pressure_mmhg",
+    "systemic_preload_pressure_mmhg",
+    "pulmonary_afterload_pressure_mmhg",
+    "systemic_afterload_pressure_mmhg",
+    "systemic_flow_l_per_min",
+    "pulmonary_flow_l_per_min",
+];
+
+/// All numeric columns stored in every experiment table, see `DatabaseRecord`
+pub const NUMERIC_COLUMNS: &[&str] = &[
+    "pulmonary_preload_pressure_mmhg",
+    "systemic_preload_pressure_mmhg",
+    "pulmonary_afterload_pressure_mmhg",
+    "systemic_afterload_pressure_mmhg",
+    "systemic_flow_l_per_min",
+    "pulmonary_flow_l_per_min",
+    "heart_rate",
+    "pressure",
+    "systole_ratio",
+    "systemic_resistance",
+    "pulmonary_resistance",
+    "systemic_afterload_compliance",
+    "pulmonary_afterload_compliance",
+    "simulation_time",
+    "mcu_timestamp_us",
+];
+
+/// Unit of a column stored in every experiment table, see `DatabaseRecord`
+/// Note the pressure channels are stored in bar, despite their column names
+pub fn column_unit(column: &str) -> Option<&'static str> {
+    match column {
+        "pulmonary_preload_pressure_mmhg"
+        | "systemic_preload_pressure_mmhg"
+        | "pulmonary_afterload_pressure_mmhg"
+        | "systemic_afterload_pressure_mmhg"
+        | "pressure" => Some("bar"),
+        "systemic_flow_l_per_min" | "pulmonary_flow_l_per_min" => Some("L/min"),
+        "heart_rate" => Some("1/min"),
+        "systole_ratio" => Some("1"),
+        "simulation_time" => Some("s"),
+        "mcu_timestamp_us" => Some("us"),
+        _ => None,
+    }
+}
+
+/// Parse a timestamp returned by InfluxDB, which may lack a timezone suffix
+pub fn parse_influx_time(time: &str) -> Option<DateTime<Utc>> {
+    let time = if time.ends_with('Z') {
+        time.to_string()
+    } else {
+        format!("{}Z", time)
+    };
+
+    DateTime::parse_from_rfc3339(&time)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// SQL expression for a timestamp, built from an integer so it is always safe to interpolate
+fn timestamp_literal(time: DateTime<Utc>) -> String {
+    format!(
+        "to_timestamp_nanos({})",
+        time.timestamp_nanos_opt().unwrap_or(0)
+    )
+}
+
+/// SQL `WHERE` clause selecting the samples from `from` up to, but excluding, `to`, or nothing if
+/// no range is given
+pub fn time_range_filter(range: Option<(DateTime<Utc>, DateTime<Utc>)>) -> String {
+    range
+        .map(|(from, to)| {
+            format!(
+                "WHERE time >= {} AND time < {}",
+                timestamp_literal(from),
+                timestamp_literal(to)
+            )
+        })
+        .unwrap_or_default()
+}
+
+/// Query the time of the first and last record in an experiment table
+pub async fn query_time_range(
+    client: &reqwest::Client,
+    table_name: &str,
+) -> Result<Option<(DateTime<Utc>, DateTime<Utc>)>, String> {
+    let query = format!(
+        r#"SELECT min(time) AS first, max(time) AS last FROM {}"#,
+        quote_identifier(table_name)
+    );
+
+    let data = query_sql(client, &query).await?;
+    let row = data.as_array().and_then(|rows| rows.first());
+    let time = |name: &str| {
+        row.and_then(|r| r.get(name))
+            .and_then(|t| t.as_str())
+            .and_then(parse_influx_time)
+    };
+
+    Ok(time("first").zip(time("last")))
+}
+
+/// Query `columns` of an experiment table between `from` and `to`, aggregated into buckets of
+/// `bucket_ms` milliseconds. Every returned row holds the bucket `time` and a `{column}_min`,
+/// `{column}_max` and `{column}_mean` value per column
+/// Columns must be taken from `NUMERIC_COLUMNS`
+pub async fn query_downsampled(
+    client: &reqwest::Client,
+    table_name: &str,
+    columns: &[&str],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    bucket_ms: i64,
+) -> Result<Vec<Value>, String> {
+    let aggregates: Vec<String> = columns
+        .iter()
+        .map(|column| {
+            let quoted = quote_identifier(column);
+            format!(
+                "min({quoted}) AS {}, max({quoted}) AS {}, avg({quoted}) AS {}",
+                quote_identifier(&format!("{column}_min")),
+                quote_identifier(&format!("{column}_max")),
+                quote_identifier(&format!("{column}_mean")),
+            )
+        })
+        .collect();
+
+    let query = format!(
+        r#"SELECT date_bin(INTERVAL '{bucket_ms} milliseconds', time, {from}) AS time, {aggregates}
+           FROM {table}
+           WHERE time >= {from} AND time <= {to}
+           GROUP BY 1
+           ORDER BY 1 ASC"#,
+        from = timestamp_literal(from),
+        to = timestamp_literal(to),
+        aggregates = aggregates.join(", "),
+        table = quote_identifier(table_name),
+    );
+
+    let data = query_sql(client, &query).await?;
+    data.as_array()
+        .cloned()
+        .ok_or_else(|| "Response is not an array".to_string())
+}
@@ -0,0 +1,71 @@
+use std::net::TcpListener;
+use std::sync::OnceLock;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::{Config, config, init};
+use crate::experiment::naming::EXPERIMENT_TABLE_PREFIX;
+
+/// First and last sample of every experiment table
+pub const FIRST_SAMPLE: &str = "2025-06-01T10:00:00";
+pub const LAST_SAMPLE: &str = "2025-06-01T10:05:00";
+
+#[derive(Deserialize)]
+struct SqlRequest {
+    q: String,
+    format: String,
+}
+
+/// Point the configuration at a stand-in for InfluxDB, starting it on first use
+/// It serves experiment tables, but none of the tables created when first written to, like the
+/// setpoint event or annotation table. It runs on its own thread, so it outlives every test
+pub fn start() {
+    static URI: OnceLock<String> = OnceLock::new();
+    let uri = URI.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let app = Router::new().route("/api/v3/query_sql", post(query_sql));
+                axum::serve(listener, app).await.unwrap();
+            });
+        });
+        init(Config {
+            db_uri: uri.clone(),
+            db_access_token: String::from("test"),
+            ..Config::default()
+        });
+        uri
+    });
+    assert_eq!(
+        &config().db_uri,
+        uri,
+        "configuration was set before the fake database"
+    );
+}
+
+async fn query_sql(Json(request): Json<SqlRequest>) -> Response {
+    let query = request.q;
+    if query.contains("information_schema") {
+        return Json(json!([])).into_response();
+    }
+    if !query.contains(&format!("FROM \"{EXPERIMENT_TABLE_PREFIX}")) {
+        // Like InfluxDB, querying a table that does not exist is an error
+        return (StatusCode::NOT_FOUND, "table not found").into_response();
+    }
+    if query.contains("min(time) AS first") {
+        return Json(json!([{ "first": FIRST_SAMPLE, "last": LAST_SAMPLE }])).into_response();
+    }
+    match request.format.as_str() {
+        "jsonl" => String::new().into_response(),
+        _ => Json(json!([])).into_response(),
+    }
+}
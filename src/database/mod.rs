@@ -1,2 +1,6 @@
 pub mod db_communication_task;
+pub mod experiment_data;
+#[cfg(test)]
+pub mod fake_influx;
+pub mod query;
 pub mod secrets;
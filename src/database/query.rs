@@ -0,0 +1,179 @@
+use futures_util::Stream;
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::*;
+
+use crate::config::config;
+
+/// Run a SQL query against InfluxDB and return the JSON-formatted result rows
+pub async fn query_sql(client: &reqwest::Client, query: &str) -> Result<Value, String> {
+    send_sql(client, query, "json")
+        .await?
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse InfluxDB response: {}", e))
+}
+
+/// Run a SQL query against InfluxDB and stream the result rows one by one, without ever holding
+/// the complete result in memory
+pub async fn stream_sql(
+    client: &reqwest::Client,
+    query: &str,
+) -> Result<impl Stream<Item = Result<Value, String>> + Send + 'static, String> {
+    let response = send_sql(client, query, "jsonl").await?;
+
+    // Split the newline delimited json response into rows as the chunks come in
+    Ok(stream::unfold(
+        Some((response, Vec::new(), 0)),
+        |state| async move {
+            let (mut response, mut buffer, mut start) = state?;
+            loop {
+                if let Some(len) = buffer[start..].iter().position(|b| *b == b'\n') {
+                    let line = &buffer[start..start + len];
+                    start += len + 1;
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    let row = serde_json::from_slice(line)
+                        .map_err(|e| format!("Failed to parse InfluxDB row: {}", e));
+                    return Some((row, Some((response, buffer, start))));
+                }
+
+                // Drop the rows handed out so far before reading the next chunk
+                buffer.drain(..start);
+                start = 0;
+
+                match response.chunk().await {
+                    Ok(Some(chunk)) => buffer.extend_from_slice(&chunk),
+                    // End of response, the last row may lack a trailing newline
+                    Ok(None) if buffer.iter().all(u8::is_ascii_whitespace) => return None,
+                    Ok(None) => buffer.push(b'\n'),
+                    Err(e) => {
+                        let err = format!("Failed to read InfluxDB response: {}", e);
+                        return Some((Err(err), None));
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// Column of an InfluxDB table as described by its schema
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableColumn {
+    pub name: String,
+    /// Arrow data type, e.g. "Float64", "Boolean" or "Timestamp(Nanosecond, None)"
+    pub data_type: String,
+}
+
+/// Query the columns of a table, in the order they are defined in the schema
+pub async fn query_table_columns(
+    client: &reqwest::Client,
+    table_name: &str,
+) -> Result<Vec<TableColumn>, String> {
+    let query = format!(
+        r#"SELECT column_name, data_type
+           FROM information_schema.columns
+           WHERE table_name = '{}' AND table_schema = 'iox'
+           ORDER BY ordinal_position ASC"#,
+        table_name.replace('\'', "''")
+    );
+
+    let data = query_sql(client, &query).await?;
+    let columns = data
+        .as_array()
+        .ok_or_else(|| "Response is not an array".to_string())?
+        .iter()
+        .filter_map(|row| {
+            let name = row.get("column_name")?.as_str()?;
+            let data_type = row.get("data_type").and_then(|t| t.as_str()).unwrap_or("");
+            Some(TableColumn {
+                name: name.to_string(),
+                data_type: data_type.to_string(),
+            })
+        })
+        .collect();
+
+    Ok(columns)
+}
+
+/// Permanently delete a table and all its data
+pub async fn delete_table(client: &reqwest::Client, table_name: &str) -> Result<(), String> {
+    let url = format!("{}/api/v3/configure/table", config().db_uri);
+
+    let response = client
+        .delete(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", config().db_access_token),
+        )
+        .query(&[("db", config().db_name.as_str()), ("table", table_name)])
+        .send()
+        .await
+        .map_err(|e| format!("Failed to delete table: {}", e))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        error!(
+            "InfluxDB table deletion failed with status {}: {}",
+            status, error_text
+        );
+        return Err(format!(
+            "InfluxDB table deletion failed with status: {}",
+            status
+        ));
+    }
+
+    Ok(())
+}
+
+/// Send a SQL query to InfluxDB, requesting the result in the given format
+async fn send_sql(
+    client: &reqwest::Client,
+    query: &str,
+    format: &str,
+) -> Result<reqwest::Response, String> {
+    let url = format!("{}/api/v3/query_sql", config().db_uri);
+
+    let response = client
+        .post(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", config().db_access_token),
+        )
+        .header("Content-Type", "application/json")
+        .json(&serde_json::json!({
+            "db": config().db_name,
+            "q": query,
+            "format": format
+        }))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to query InfluxDB: {}", e))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        error!(
+            "InfluxDB query failed with status {}: {}",
+            status, error_text
+        );
+        return Err(format!("InfluxDB query failed with status: {}", status));
+    }
+
+    Ok(response)
+}
+
+/// Quote a table or column name for use in an InfluxDB SQL query
+/// Any embedded double quote is escaped, so the name can never break out of the identifier
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
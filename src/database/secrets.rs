@@ -1,12 +1,10 @@
-// Default InfluxDB3 DB data
-pub const DB_URI: &str = "http://localhost:8181";
-pub const DB_NAME: &str = "mockloop_data";
+// Default InfluxDB3 DB data, see `Config` to override them. The access token has no default,
+// it has to be configured
+pub const DEFAULT_DB_URI: &str = "http://localhost:8181";
+pub const DEFAULT_DB_NAME: &str = "mockloop_data";
 pub const MEASUREMENT_ID_TABLE: &str = "measurement_id";
-
-// NOTE: this is the memory db token
-// pub const DB_ACCESS_TOKEN: &str =
-//     "apiv3_5zB9k-A7Eora5iMy3epTdWi6NjaRzTvF2jx1mprt98l2z4eOl2tyZTLdnjHzzmqB4kwD_z681ynKVaSXf4Lvcw";
-
-// NOTE: this is the docker db token
-pub const DB_ACCESS_TOKEN: &str =
-    "apiv3_hxTAC_uUvsKql1plgiYJWe0kY8UxiUQ0yPYPaEZyKYg2f_oUeYhkm0s4H2QLIofRzfGK3P09uIGazSd1JFDQxA";
+pub const SETPOINT_EVENT_TABLE: &str = "setpoint_events";
+pub const ANNOTATION_TABLE: &str = "annotations";
+pub const DATA_GAP_TABLE: &str = "data_gaps";
+pub const CATALOGUE_TABLE: &str = "catalogue";
+pub const SUMMARY_TABLE: &str = "summaries";
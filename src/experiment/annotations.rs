@@ -0,0 +1,97 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::*;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{axumstate::AxumState, messages::db_messages::DatabaseMessage};
+
+/// Marker the frontend wants to attach to the running experiment
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct AnnotationMessage {
+    /// Free-text description, e.g. "valve clamped"
+    pub text: String,
+    /// Optional category to group markers by, e.g. "intervention"
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Moment the marker refers to, between the experiment start and now, defaults to the time it
+    /// was received
+    #[serde(default)]
+    pub time: Option<DateTime<Utc>>,
+}
+
+/// Timestamped marker attached to an experiment
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Annotation {
+    pub time: DateTime<Utc>,
+    pub experiment_id: Uuid,
+    pub category: Option<String>,
+    pub text: String,
+}
+
+#[derive(Debug, Error)]
+pub enum AnnotationError {
+    #[error("text must not be empty")]
+    EmptyText,
+    #[error("time must lie between the experiment start {0} and now")]
+    TimeOutOfRange(DateTime<Utc>),
+    #[error("no experiment is running")]
+    NotRunning,
+    #[error("the {0} is unavailable")]
+    StateUnavailable(&'static str),
+    #[error("the {0} task is not running")]
+    TaskUnavailable(&'static str),
+}
+
+/// Attach an annotation to the running experiment and forward it to the DB task
+/// Shared by the POST /experiment/annotate handler and the websocket
+pub async fn annotate_running_experiment(
+    state: &AxumState,
+    message: AnnotationMessage,
+) -> Result<Annotation, AnnotationError> {
+    if message.text.trim().is_empty() {
+        warn!("Rejecting annotation without text");
+        return Err(AnnotationError::EmptyText);
+    }
+
+    let (experiment_id, start_time) = match state.current_experiment.lock() {
+        Ok(experiment) => match *experiment {
+            Some(ref exp) if exp.is_running => (exp.id, exp.start_time),
+            _ => {
+                warn!("Rejecting annotation, no experiment is running");
+                return Err(AnnotationError::NotRunning);
+            }
+        },
+        Err(_) => {
+            error!("Unable to fetch the current experiment while annotating");
+            return Err(AnnotationError::StateUnavailable("current experiment"));
+        }
+    };
+
+    let now = Utc::now();
+    let time = message.time.unwrap_or(now);
+    if time < start_time || time > now {
+        warn!("Rejecting annotation at {time}, outside of experiment {experiment_id}");
+        return Err(AnnotationError::TimeOutOfRange(start_time));
+    }
+
+    let annotation = Annotation {
+        time,
+        experiment_id,
+        category: message.category.filter(|c| !c.trim().is_empty()),
+        text: message.text,
+    };
+
+    if let Err(err) = state
+        .db_sender
+        .send(DatabaseMessage::Annotation(annotation.clone()))
+        .await
+    {
+        error!("Unable to send annotation to database task: {err}");
+        return Err(AnnotationError::TaskUnavailable("database"));
+    }
+
+    info!("Annotated experiment {experiment_id}: {:?}", annotation);
+    Ok(annotation)
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tokio::time::{self, Duration};
+use tracing::*;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::control::history::HISTORY_SECONDS;
+use crate::database::experiment_data::parse_influx_time;
+use crate::database::query::{delete_table, query_sql, query_table_columns, quote_identifier};
+use crate::database::secrets::CATALOGUE_TABLE;
+use crate::experiment::listing::{
+    ExperimentFromDB, find_experiment, query_experiments_from_influxdb,
+};
+use crate::experiment::naming::{ExperimentNameError, validate_experiment_name};
+use crate::{axumstate::AxumState, messages::db_messages::DatabaseMessage};
+
+/// Time a deleted experiment stays in the trash before its data is removed for good
+pub const TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Period at which the trash is checked for experiments to remove
+const TRASH_PURGE_PERIOD: Duration = Duration::from_secs(60 * 60);
+/// Maximum number of tags on a single experiment
+const MAX_TAGS: usize = 16;
+/// Maximum length of a tag, in characters
+const MAX_TAG_LEN: usize = 32;
+
+/// Editable metadata of an experiment, stored in the catalogue table
+/// The name and description tagged on the experiment data cannot be changed once written, so the
+/// latest catalogue entry of an experiment takes precedence over them
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct CatalogueEntry {
+    pub experiment_id: Uuid,
+    pub name: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    /// Time the experiment was moved to the trash, if it was
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Time of this change
+    pub time: DateTime<Utc>,
+}
+
+impl CatalogueEntry {
+    /// Current catalogue state of an experiment listed from the database
+    pub fn from_experiment(experiment: &ExperimentFromDB) -> Option<Self> {
+        Some(Self {
+            experiment_id: Uuid::parse_str(&experiment.experiment_id).ok()?,
+            name: experiment.experiment_name.clone(),
+            description: experiment.description.clone(),
+            tags: experiment.tags.clone(),
+            deleted_at: experiment.deleted_at,
+            time: Utc::now(),
+        })
+    }
+
+    /// Overwrite the metadata of an experiment listed from the database with this entry
+    pub fn apply(&self, experiment: &mut ExperimentFromDB) {
+        experiment.experiment_name = self.name.clone();
+        experiment.description = self.description.clone();
+        experiment.tags = self.tags.clone();
+        experiment.deleted_at = self.deleted_at;
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ExperimentEditError {
+    #[error(transparent)]
+    Name(#[from] ExperimentNameError),
+    #[error("an experiment can have at most {MAX_TAGS} tags")]
+    TooManyTags,
+    #[error("tags must not be empty")]
+    EmptyTag,
+    #[error("tags must be at most {MAX_TAG_LEN} characters long")]
+    TagTooLong,
+    #[error("tags must not contain control characters")]
+    TagControlCharacter,
+    #[error("an experiment can start at most {HISTORY_SECONDS} s in the past")]
+    BackfillTooLong,
+    #[error(
+        "stopping at the end of a protocol is not supported, no protocol is run by the service"
+    )]
+    EndOfProtocolUnsupported,
+}
+
+impl ExperimentEditError {
+    /// Request field the error is about
+    pub fn field(&self) -> &'static str {
+        match self {
+            ExperimentEditError::Name(_) => "name",
+            ExperimentEditError::TooManyTags
+            | ExperimentEditError::EmptyTag
+            | ExperimentEditError::TagTooLong
+            | ExperimentEditError::TagControlCharacter => "tags",
+            ExperimentEditError::BackfillTooLong => "backfill_seconds",
+            ExperimentEditError::EndOfProtocolUnsupported => "stop_conditions.end_of_protocol",
+        }
+    }
+}
+
+/// Error when loading or storing the catalogue entry of an experiment
+#[derive(Debug, Error)]
+pub enum CatalogueError {
+    #[error("experiment {0} not found")]
+    NotFound(Uuid),
+    #[error("experiment {0} has an invalid id")]
+    InvalidId(Uuid),
+    #[error("the {0} is unavailable")]
+    StateUnavailable(&'static str),
+    #[error("the {0} task is not running")]
+    TaskUnavailable(&'static str),
+    #[error("{0}")]
+    Database(String),
+}
+
+/// Requested changes to the metadata of an experiment, absent fields are left unchanged
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ExperimentEdit {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+impl ExperimentEdit {
+    /// Validate and normalize the requested name and tags
+    pub fn validate(&mut self) -> Result<(), ExperimentEditError> {
+        if let Some(ref name) = self.name {
+            self.name = Some(validate_experiment_name(name)?);
+        }
+        if let Some(ref tags) = self.tags {
+            self.tags = Some(validate_tags(tags)?);
+        }
+        Ok(())
+    }
+
+    /// Apply the requested changes to a catalogue entry
+    pub fn apply(&self, entry: &mut CatalogueEntry) {
+        if let Some(ref name) = self.name {
+            entry.name = name.clone();
+        }
+        if let Some(ref description) = self.description {
+            entry.description = description.clone();
+        }
+        if let Some(ref tags) = self.tags {
+            entry.tags = tags.clone();
+        }
+    }
+}
+
+/// Validate user supplied tags, returning them trimmed and without duplicates
+pub fn validate_tags(tags: &[String]) -> Result<Vec<String>, ExperimentEditError> {
+    let mut validated: Vec<String> = Vec::with_capacity(tags.len());
+
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() {
+            return Err(ExperimentEditError::EmptyTag);
+        }
+        if tag.chars().count() > MAX_TAG_LEN {
+            return Err(ExperimentEditError::TagTooLong);
+        }
+        if tag.chars().any(char::is_control) {
+            return Err(ExperimentEditError::TagControlCharacter);
+        }
+        if !validated.iter().any(|t| t == tag) {
+            validated.push(tag.to_string());
+        }
+    }
+
+    if validated.len() > MAX_TAGS {
+        return Err(ExperimentEditError::TooManyTags);
+    }
+    Ok(validated)
+}
+
+/// Query the latest catalogue entry of every experiment, or of a single experiment
+pub async fn query_catalogue(
+    client: &reqwest::Client,
+    experiment_id: Option<Uuid>,
+) -> Result<HashMap<Uuid, CatalogueEntry>, String> {
+    // The catalogue table only exists once an experiment was edited
+    if query_table_columns(client, CATALOGUE_TABLE)
+        .await?
+        .is_empty()
+    {
+        return Ok(HashMap::new());
+    }
+
+    // The experiment id is a parsed uuid, so it is safe to interpolate
+    let filter = experiment_id
+        .map(|id| format!("WHERE experiment_id = '{}'", id))
+        .unwrap_or_default();
+    let query = format!(
+        r#"SELECT time, experiment_id, name, description, tags, deleted_at
+           FROM {}
+           {}
+           ORDER BY time ASC"#,
+        quote_identifier(CATALOGUE_TABLE),
+        filter
+    );
+
+    let data = query_sql(client, &query).await?;
+    let records = data
+        .as_array()
+        .ok_or_else(|| "Response is not an array".to_string())?;
+
+    // Later entries replace earlier ones
+    let mut catalogue = HashMap::new();
+    for record in records {
+        let field = |name: &str| record.get(name).and_then(|v| v.as_str()).unwrap_or("");
+        let (Ok(id), Some(time)) = (
+            Uuid::parse_str(field("experiment_id")),
+            parse_influx_time(field("time")),
+        ) else {
+            warn!("Skipping malformed catalogue entry: {:?}", record);
+            continue;
+        };
+
+        catalogue.insert(
+            id,
+            CatalogueEntry {
+                experiment_id: id,
+                name: field("name").to_string(),
+                description: field("description").to_string(),
+                tags: serde_json::from_str(field("tags")).unwrap_or_default(),
+                deleted_at: DateTime::parse_from_rfc3339(field("deleted_at"))
+                    .ok()
+                    .map(|t| t.with_timezone(&Utc)),
+                time,
+            },
+        );
+    }
+
+    Ok(catalogue)
+}
+
+/// Overwrite the metadata of an experiment listed from the database with its catalogue entry
+pub async fn apply_catalogue(
+    client: &reqwest::Client,
+    experiment: &mut ExperimentFromDB,
+) -> Result<(), String> {
+    let Ok(id) = Uuid::parse_str(&experiment.experiment_id) else {
+        return Ok(());
+    };
+    if let Some(entry) = query_catalogue(client, Some(id)).await?.get(&id) {
+        entry.apply(experiment);
+    }
+    Ok(())
+}
+
+/// Overwrite the metadata tags of a data row with the catalogue metadata of its experiment
+/// InfluxDB cannot update rows in place, so the tags keep the name and description the data was
+/// recorded with, and are replaced whenever rows leave the server
+pub fn apply_catalogue_to_row(experiment: &ExperimentFromDB, row: &mut Value) {
+    let Some(row) = row.as_object_mut() else {
+        return;
+    };
+    for (column, value) in [
+        ("experiment_name", &experiment.experiment_name),
+        ("experiment_description", &experiment.description),
+    ] {
+        if let Some(tag) = row.get_mut(column) {
+            *tag = Value::String(value.clone());
+        }
+    }
+}
+
+/// Look up the current catalogue entry of the running or a recorded experiment
+/// Returns the entry and whether the experiment is running
+pub async fn load_catalogue_entry(
+    state: &AxumState,
+    experiment_id: Uuid,
+) -> Result<(CatalogueEntry, bool), CatalogueError> {
+    let running = match state.current_experiment.lock() {
+        Ok(experiment) => experiment
+            .as_ref()
+            .filter(|e| e.is_running && e.id == experiment_id)
+            .map(|e| CatalogueEntry {
+                experiment_id,
+                name: e.name.clone(),
+                description: e.description.clone(),
+                tags: e.tags.clone(),
+                deleted_at: None,
+                time: Utc::now(),
+            }),
+        Err(_) => {
+            error!("Unable to fetch the current experiment");
+            return Err(CatalogueError::StateUnavailable("current experiment"));
+        }
+    };
+    if let Some(entry) = running {
+        return Ok((entry, true));
+    }
+
+    match find_experiment(experiment_id).await {
+        Ok(Some(experiment)) => match CatalogueEntry::from_experiment(&experiment) {
+            Some(entry) => Ok((entry, false)),
+            None => Err(CatalogueError::InvalidId(experiment_id)),
+        },
+        Ok(None) => {
+            warn!("Experiment {} not found", experiment_id);
+            Err(CatalogueError::NotFound(experiment_id))
+        }
+        Err(e) => {
+            error!("Failed to retrieve experiments from InfluxDB: {}", e);
+            Err(CatalogueError::Database(e))
+        }
+    }
+}
+
+/// Forward a changed catalogue entry to the DB task
+pub async fn store_catalogue_entry(
+    state: &AxumState,
+    entry: CatalogueEntry,
+) -> Result<(), CatalogueError> {
+    if let Err(err) = state
+        .db_sender
+        .send(DatabaseMessage::Catalogue(entry))
+        .await
+    {
+        error!("Unable to send catalogue entry to database task: {err}");
+        return Err(CatalogueError::TaskUnavailable("database"));
+    }
+    Ok(())
+}
+
+/// Periodically remove the data of experiments that were in the trash for longer than
+/// `TRASH_RETENTION`
+/// Only the data table of an experiment is dropped. Its rows in the setpoint event, annotation,
+/// data gap, summary and catalogue tables are kept: InfluxDB can only drop whole tables, and
+/// rewriting a table shared with other experiments would lose whatever the running experiment
+/// writes to it meanwhile. The kept rows are never returned, as every query of those tables filters
+/// on the id of a listed experiment, and an archive of the purged experiment imported later
+/// overwrites them, as its rows have the same tags and timestamps
+pub async fn purge_trash() {
+    let mut ticker = time::interval(TRASH_PURGE_PERIOD);
+    let retention = chrono::Duration::from_std(TRASH_RETENTION).unwrap_or_default();
+
+    loop {
+        ticker.tick().await;
+
+        let experiments = match query_experiments_from_influxdb().await {
+            Ok(experiments) => experiments,
+            Err(err) => {
+                error!("Unable to list experiments to purge: {err}");
+                continue;
+            }
+        };
+
+        let client = reqwest::Client::new();
+        let now = Utc::now();
+        for experiment in experiments {
+            if !experiment.deleted_at.is_some_and(|t| t + retention <= now) {
+                continue;
+            }
+
+            match delete_table(&client, &experiment.table_name).await {
+                Ok(()) => info!(
+                    "Purged experiment {} - {} from the trash",
+                    experiment.experiment_id, experiment.experiment_name
+                ),
+                Err(err) => error!(
+                    "Unable to purge experiment {}: {err}",
+                    experiment.experiment_id
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::messages::frontend_messages::FrontendSetpoint;
+
+/// Origin of a setpoint change
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SetpointSource {
+    /// POST /control/loop
+    LoopEndpoint,
+    /// POST /control/heart
+    HeartEndpoint,
+    /// Setpoint command sent over the websocket
+    Websocket,
+}
+
+impl SetpointSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SetpointSource::LoopEndpoint => "loop_endpoint",
+            SetpointSource::HeartEndpoint => "heart_endpoint",
+            SetpointSource::Websocket => "websocket",
+        }
+    }
+}
+
+/// Discrete record of an accepted setpoint change, logged next to the experiment data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetpointEvent {
+    /// Time at which the setpoint change was accepted
+    pub time: DateTime<Utc>,
+    /// Experiment running at the time of the change, nil if none was running
+    pub experiment_id: Uuid,
+    /// What caused the setpoint to change
+    pub source: SetpointSource,
+    /// Address of the client that requested the change, if known
+    pub client: Option<String>,
+    /// User that requested the change, if it was requested by one
+    pub user: Option<String>,
+    /// Setpoint before the change
+    pub old: FrontendSetpoint,
+    /// Setpoint after the change
+    pub new: FrontendSetpoint,
+}
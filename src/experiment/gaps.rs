@@ -0,0 +1,41 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Reason why no data was recorded for part of an experiment
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GapCause {
+    /// The service was not running, e.g. after a crash
+    ServiceRestart,
+    /// No report arrived from the MCU within the communication timeout
+    McuTimeout,
+    /// The MCU restarted and lost the reports it had not sent yet
+    McuReset,
+    /// Reports from the MCU went missing without a timeout
+    SampleLoss,
+    /// Recorded samples could not be written to the DB
+    DbFailure,
+}
+
+impl GapCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GapCause::ServiceRestart => "service_restart",
+            GapCause::McuTimeout => "mcu_timeout",
+            GapCause::McuReset => "mcu_reset",
+            GapCause::SampleLoss => "sample_loss",
+            GapCause::DbFailure => "db_failure",
+        }
+    }
+}
+
+/// Period of an experiment during which no data was recorded
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DataGap {
+    pub experiment_id: Uuid,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub cause: GapCause,
+}
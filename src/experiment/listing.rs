@@ -0,0 +1,537 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::*;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::config;
+use crate::database::query::{query_sql, query_table_columns, quote_identifier};
+use crate::database::secrets::{ANNOTATION_TABLE, DATA_GAP_TABLE, SETPOINT_EVENT_TABLE};
+use crate::experiment::catalogue::query_catalogue;
+use crate::experiment::naming::table_name_for;
+use crate::http::messages::{AnnotationFromDB, DataGapFromDB, SetpointEventFromDB};
+
+/// Individual experiment details from the database
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ExperimentFromDB {
+    pub table_name: String,
+    pub experiment_id: String,
+    pub experiment_name: String,
+    pub description: String,
+    pub start_time: Option<String>,
+    pub duration_seconds: f64,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// User that started the experiment, None for experiments recorded before users were introduced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_by: Option<String>,
+    /// Time the experiment was moved to the trash, if it was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Find the data table of an experiment, either named after its id or, for experiments recorded
+/// before tables were named after ids, by looking at the experiment id stored in every table
+pub async fn resolve_experiment_table(
+    client: &reqwest::Client,
+    experiment_id: Uuid,
+) -> Result<Option<String>, String> {
+    let table_name = table_name_for(experiment_id);
+
+    // The table name is derived from a uuid, so it is safe to interpolate
+    let query = format!(
+        r#"SELECT table_name
+           FROM information_schema.tables
+           WHERE table_name = '{}' AND table_schema = 'iox'"#,
+        table_name
+    );
+    let data = query_sql(client, &query).await?;
+    if data.as_array().is_some_and(|tables| !tables.is_empty()) {
+        return Ok(Some(table_name));
+    }
+
+    Ok(find_experiment(experiment_id).await?.map(|e| e.table_name))
+}
+
+/// Find a single experiment and its metadata, including experiments in the trash
+pub async fn find_experiment(experiment_id: Uuid) -> Result<Option<ExperimentFromDB>, String> {
+    Ok(query_experiments_from_influxdb()
+        .await?
+        .into_iter()
+        .find(|e| e.experiment_id == experiment_id.to_string()))
+}
+
+/// Query InfluxDB for all experiment tables and their metadata, including experiments in the
+/// trash. Metadata edited after the experiment was recorded is taken from the catalogue
+pub async fn query_experiments_from_influxdb() -> Result<Vec<ExperimentFromDB>, String> {
+    // Create HTTP client
+    let client = reqwest::Client::new();
+
+    // Query to list all tables starting with 'experiment_' in the iox schema
+    let list_tables_query = r#"
+        SELECT DISTINCT table_name 
+        FROM information_schema.tables 
+        WHERE starts_with(table_name, 'experiment_') AND table_schema = 'iox'
+        ORDER BY table_name DESC
+    "#;
+
+    // Execute query to get table names
+    let url = format!("{}/api/v3/query_sql", config().db_uri);
+    info!("Querying InfluxDB at: {}", url);
+    info!("Query: {}", list_tables_query);
+
+    let response = client
+        .post(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", config().db_access_token),
+        )
+        .header("Content-Type", "application/json")
+        .json(&serde_json::json!({
+            "db": config().db_name,
+            "q": list_tables_query,
+            "format": "json"
+        }))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to query InfluxDB: {}", e))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        error!(
+            "InfluxDB query failed with status {}: {}",
+            status, error_text
+        );
+        return Err(format!("InfluxDB query failed with status: {}", status));
+    }
+
+    let tables_result: Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse InfluxDB response: {}", e))?;
+
+    info!(
+        "InfluxDB response: {}",
+        serde_json::to_string_pretty(&tables_result)
+            .unwrap_or_else(|_| "Failed to serialize".to_string())
+    );
+
+    // Extract table names from response
+    let table_names = extract_table_names(&tables_result).ok_or_else(|| {
+        format!(
+            "Failed to extract table names from response: {:?}",
+            tables_result
+        )
+    })?;
+
+    info!(
+        "Found {} experiment tables: {:?}",
+        table_names.len(),
+        table_names
+    );
+
+    if table_names.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // For each table, query first and last record to get metadata
+    let mut experiments = Vec::new();
+    for table_name in table_names {
+        match get_experiment_metadata(&client, &table_name).await {
+            Ok(Some(experiment)) => experiments.push(experiment),
+            Ok(None) => {
+                warn!("No metadata found for table: {}", table_name);
+            }
+            Err(e) => {
+                error!("Failed to get metadata for table {}: {}", table_name, e);
+                // Continue with other tables instead of failing completely
+            }
+        }
+    }
+
+    let catalogue = query_catalogue(&client, None).await?;
+    for experiment in experiments.iter_mut() {
+        let entry = Uuid::parse_str(&experiment.experiment_id)
+            .ok()
+            .and_then(|id| catalogue.get(&id));
+        if let Some(entry) = entry {
+            entry.apply(experiment);
+        }
+    }
+
+    Ok(experiments)
+}
+
+/// Extract table names from InfluxDB query response
+fn extract_table_names(response: &Value) -> Option<Vec<String>> {
+    // InfluxDB 3.0 returns results as an array of objects: [{"table_name": "..."}]
+    if let Some(results) = response.as_array() {
+        let tables: Vec<String> = results
+            .iter()
+            .filter_map(|result| {
+                result
+                    .get("table_name")
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string())
+            })
+            .collect();
+
+        info!("Extracted {} table names from response", tables.len());
+        if !tables.is_empty() {
+            return Some(tables);
+        }
+    } else {
+        warn!("Response is not an array: {:?}", response);
+    }
+
+    None
+}
+
+/// Get metadata for a specific experiment table
+pub async fn get_experiment_metadata(
+    client: &reqwest::Client,
+    table_name: &str,
+) -> Result<Option<ExperimentFromDB>, String> {
+    // Query first record, selecting all columns as tables written before users were introduced
+    // have no started_by column
+    let first_query = format!(
+        r#"SELECT *
+           FROM {}
+           ORDER BY time ASC
+           LIMIT 1"#,
+        quote_identifier(table_name)
+    );
+
+    // Query last record
+    let last_query = format!(
+        r#"SELECT time
+           FROM {}
+           ORDER BY time DESC
+           LIMIT 1"#,
+        quote_identifier(table_name)
+    );
+
+    let url = format!("{}/api/v3/query_sql", config().db_uri);
+
+    // Get first record
+    let first_response = client
+        .post(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", config().db_access_token),
+        )
+        .header("Content-Type", "application/json")
+        .json(&serde_json::json!({
+            "db": config().db_name,
+            "q": first_query,
+            "format": "json"
+        }))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to query first record: {}", e))?;
+
+    if !first_response.status().is_success() {
+        return Err(format!(
+            "First record query failed: {}",
+            first_response.status()
+        ));
+    }
+
+    let first_data: Value = first_response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse first record: {}", e))?;
+
+    // Get last record
+    let last_response = client
+        .post(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", config().db_access_token),
+        )
+        .header("Content-Type", "application/json")
+        .json(&serde_json::json!({
+            "db": config().db_name,
+            "q": last_query,
+            "format": "json"
+        }))
+        .send()
+        .await
+        .map_err(|e| format!("Failed to query last record: {}", e))?;
+
+    if !last_response.status().is_success() {
+        return Err(format!(
+            "Last record query failed: {}",
+            last_response.status()
+        ));
+    }
+
+    let last_data: Value = last_response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse last record: {}", e))?;
+
+    // Extract metadata from responses
+    let first_record = extract_first_record(&first_data);
+    let last_time = extract_last_time(&last_data);
+
+    if let (Some(first), Some(last_time)) = (first_record, last_time) {
+        // InfluxDB timestamps may not have timezone suffix, add 'Z' if missing
+        let start_time_str = if first.start_time.ends_with('Z') {
+            first.start_time.clone()
+        } else {
+            format!("{}Z", first.start_time)
+        };
+        let end_time_str = if last_time.ends_with('Z') {
+            last_time.clone()
+        } else {
+            format!("{}Z", last_time)
+        };
+
+        // Calculate duration
+        let start_time = chrono::DateTime::parse_from_rfc3339(&start_time_str)
+            .map_err(|e| format!("Failed to parse start time '{}': {}", start_time_str, e))?;
+        let end_time = chrono::DateTime::parse_from_rfc3339(&end_time_str)
+            .map_err(|e| format!("Failed to parse end time '{}': {}", end_time_str, e))?;
+
+        let duration_seconds = (end_time - start_time).num_milliseconds() as f64 / 1000.0;
+
+        Ok(Some(ExperimentFromDB {
+            table_name: table_name.to_string(),
+            experiment_id: first.experiment_id,
+            experiment_name: first.experiment_name,
+            description: first.description,
+            start_time: Some(first.start_time),
+            duration_seconds,
+            tags: Vec::new(),
+            started_by: first.started_by,
+            deleted_at: None,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Debug)]
+struct FirstRecordData {
+    experiment_id: String,
+    experiment_name: String,
+    description: String,
+    start_time: String,
+    started_by: Option<String>,
+}
+
+/// Extract first record data from query response
+fn extract_first_record(response: &Value) -> Option<FirstRecordData> {
+    // InfluxDB returns array format: [{"experiment_id": "...", "experiment_name": "...", ...}]
+    if let Some(results) = response.as_array() {
+        if let Some(first) = results.first() {
+            let experiment_id = first.get("experiment_id")?.as_str()?.to_string();
+            let experiment_name = first.get("experiment_name")?.as_str()?.to_string();
+            let description = first
+                .get("experiment_description")
+                .and_then(|d| d.as_str())
+                .unwrap_or("")
+                .to_string();
+            let start_time = first.get("time")?.as_str()?.to_string();
+            let started_by = first
+                .get("started_by")
+                .and_then(|s| s.as_str())
+                .map(String::from);
+
+            info!(
+                "Extracted first record: id={}, name={}, time={}",
+                experiment_id, experiment_name, start_time
+            );
+            return Some(FirstRecordData {
+                experiment_id,
+                experiment_name,
+                description,
+                start_time,
+                started_by,
+            });
+        } else {
+            warn!("First record array is empty");
+        }
+    } else {
+        warn!("First record response is not an array: {:?}", response);
+    }
+
+    None
+}
+
+/// Extract last time from query response
+fn extract_last_time(response: &Value) -> Option<String> {
+    // InfluxDB returns array format: [{"time": "..."}]
+    if let Some(results) = response.as_array() {
+        if let Some(last) = results.first() {
+            let time = last.get("time")?.as_str()?.to_string();
+            info!("Extracted last time: {}", time);
+            return Some(time);
+        } else {
+            warn!("Last time array is empty");
+        }
+    } else {
+        warn!("Last time response is not an array: {:?}", response);
+    }
+
+    None
+}
+
+/// Query InfluxDB for all annotations tagged with the given experiment id
+pub async fn query_annotations(
+    client: &reqwest::Client,
+    experiment_id: Uuid,
+) -> Result<Vec<AnnotationFromDB>, String> {
+    // The annotation table only exists once an annotation was recorded
+    if query_table_columns(client, ANNOTATION_TABLE)
+        .await?
+        .is_empty()
+    {
+        return Ok(Vec::new());
+    }
+
+    // The experiment id is a parsed uuid, so it is safe to interpolate
+    let query = format!(
+        r#"SELECT time, category, text
+           FROM {}
+           WHERE experiment_id = '{}'
+           ORDER BY time ASC"#,
+        quote_identifier(ANNOTATION_TABLE),
+        experiment_id
+    );
+
+    let data = query_sql(client, &query).await?;
+    let records = data
+        .as_array()
+        .ok_or_else(|| "Response is not an array".to_string())?;
+
+    Ok(records
+        .iter()
+        .map(|record| {
+            let field = |name: &str| {
+                record
+                    .get(name)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("")
+                    .to_string()
+            };
+
+            AnnotationFromDB {
+                time: field("time"),
+                category: field("category"),
+                text: field("text"),
+            }
+        })
+        .collect())
+}
+
+/// Query InfluxDB for all data gaps tagged with the given experiment id
+pub async fn query_data_gaps(
+    client: &reqwest::Client,
+    experiment_id: Uuid,
+) -> Result<Vec<DataGapFromDB>, String> {
+    // The gap table only exists once a gap was recorded
+    if query_table_columns(client, DATA_GAP_TABLE)
+        .await?
+        .is_empty()
+    {
+        return Ok(Vec::new());
+    }
+
+    // The experiment id is a parsed uuid, so it is safe to interpolate
+    let query = format!(
+        r#"SELECT time, end_time, duration_seconds, cause
+           FROM {}
+           WHERE experiment_id = '{}'
+           ORDER BY time ASC"#,
+        quote_identifier(DATA_GAP_TABLE),
+        experiment_id
+    );
+
+    let data = query_sql(client, &query).await?;
+    let records = data
+        .as_array()
+        .ok_or_else(|| "Response is not an array".to_string())?;
+
+    Ok(records
+        .iter()
+        .map(|record| {
+            let field = |name: &str| {
+                record
+                    .get(name)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("")
+                    .to_string()
+            };
+
+            DataGapFromDB {
+                start: field("time"),
+                end: field("end_time"),
+                duration_seconds: record
+                    .get("duration_seconds")
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or_default(),
+                cause: field("cause"),
+            }
+        })
+        .collect())
+}
+
+/// Query InfluxDB for all setpoint events tagged with the given experiment id
+pub async fn query_setpoint_events(
+    client: &reqwest::Client,
+    experiment_id: Uuid,
+) -> Result<Vec<SetpointEventFromDB>, String> {
+    // The setpoint event table only exists once a setpoint was changed
+    if query_table_columns(client, SETPOINT_EVENT_TABLE)
+        .await?
+        .is_empty()
+    {
+        return Ok(Vec::new());
+    }
+
+    // The experiment id is a parsed uuid, so it is safe to interpolate
+    // All columns are selected, as tables written before users were introduced have no user column
+    let query = format!(
+        r#"SELECT *
+           FROM {}
+           WHERE experiment_id = '{}'
+           ORDER BY time ASC"#,
+        quote_identifier(SETPOINT_EVENT_TABLE),
+        experiment_id
+    );
+
+    let data = query_sql(client, &query).await?;
+    let records = data
+        .as_array()
+        .ok_or_else(|| "Response is not an array".to_string())?;
+
+    Ok(records
+        .iter()
+        .map(|record| {
+            let field = |name: &str| {
+                record
+                    .get(name)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("")
+                    .to_string()
+            };
+            let setpoint = |name: &str| serde_json::from_str(&field(name)).unwrap_or(Value::Null);
+
+            SetpointEventFromDB {
+                time: field("time"),
+                source: field("source"),
+                client: field("client"),
+                user: field("user"),
+                old: setpoint("old_setpoint"),
+                new: setpoint("new_setpoint"),
+            }
+        })
+        .collect())
+}
@@ -1,52 +1,285 @@
 use chrono::Utc;
-use tokio::sync::watch::{Receiver, Sender};
+use tokio::sync::watch::Sender;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task;
+use tokio::time::{self, Duration};
 use tracing::*;
 use uuid::Uuid;
 
-use crate::experiment::{Experiment, ExperimentStartMessage};
+use crate::analysis::summary::summarise_stopped_experiment;
+use crate::experiment::annotations::Annotation;
+use crate::experiment::catalogue::CatalogueEntry;
+use crate::experiment::gaps::{DataGap, GapCause};
+use crate::experiment::naming::table_name_for;
+use crate::experiment::persist::{clear_experiment, load_experiment, save_experiment};
+use crate::experiment::{Experiment, ExperimentCommand, ExperimentStartMessage};
+use crate::messages::db_messages::DatabaseMessage;
+use crate::messages::system_events::{SystemEvent, SystemEventMessage, publish_event};
+
+/// Period at which the running experiment is stored on disk, bounds the recorded restart gap
+const EXPERIMENT_SAVE_PERIOD: Duration = Duration::from_secs(5);
+/// Longest service downtime after which a running experiment is resumed instead of aborted
+const MAX_RECOVERY_GAP: Duration = Duration::from_secs(5 * 60);
 
 /// Backend of the "experiment manager" HHH frontend feature
-/// Responsible for responding to experiment status changes, like starting or stopping an
+/// Responsible for responding to experiment status changes, like starting, pausing or stopping an
 /// experiment.
-/// Also responsible for generating a new uuid when an experiment is started
+/// Also responsible for generating a new uuid when an experiment is started, and for recovering
+/// the running experiment after a service restart
 pub async fn manage_experiments(
-    mut experiment_started_receiver: Receiver<Option<ExperimentStartMessage>>,
+    mut experiment_command_receiver: mpsc::Receiver<ExperimentCommand>,
     experiment_sender: Sender<Option<Experiment>>,
+    db_sender: mpsc::Sender<DatabaseMessage>,
+    event_sender: broadcast::Sender<SystemEventMessage>,
 ) {
+    // Pick up the experiment that was running when the service stopped, if any
+    let mut current_experiment = recover_experiment(&db_sender).await;
+    if current_experiment.is_some() {
+        if let Err(err) = experiment_sender.send(current_experiment.clone()) {
+            error!("Unable to notify control loop of recovered experiment: {err}");
+        }
+    }
+
+    let mut save_ticker = time::interval(EXPERIMENT_SAVE_PERIOD);
+
     loop {
-        // Wait until a new experiment is started
-        if experiment_started_receiver.changed().await.is_ok() {
-            match experiment_started_receiver.borrow_and_update().clone() {
-                Some(ExperimentStartMessage { name, description }) => {
-                    // Construct a new experiment
-                    let new_experiment = Experiment {
-                        is_running: true,
-                        id: Uuid::new_v4(),
-                        name: name.to_string(),
-                        description,
-                        table_name: create_table_from_experiment(&name),
-                        start_time: Utc::now(),
-                        duration_seconds: chrono::Duration::zero(),
-                    };
-
-                    info!("New experiment started: {:?}", new_experiment);
-
-                    // Notify control loop
-                    if let Err(err) = experiment_sender.send(Some(new_experiment)) {
-                        error!("Unable to notify control loop of new experiment: {err}");
-                    }
-                }
-                None => {
-                    // Experiment stopped, notify control loop
-                    if let Err(err) = experiment_sender.send(None) {
-                        error!("Unable to notify control loop of new experiment: {err}");
-                    }
+        tokio::select! {
+            // Wait for the next command, commands are applied in the order they were sent
+            Some(command) = experiment_command_receiver.recv() => {
+                let is_start = matches!(command, ExperimentCommand::Start(..));
+                let previous_experiment = current_experiment.clone();
+                if !apply_command(&mut current_experiment, command) {
+                    continue;
+                }
+
+                // Summarise the experiment that was stopped or replaced by a new one
+                if let Some(ref previous) = previous_experiment
+                    && current_experiment.as_ref().map(|e| e.id) != Some(previous.id)
+                {
+                    task::spawn(summarise_stopped_experiment(
+                        previous.clone(),
+                        Utc::now(),
+                        db_sender.clone(),
+                    ));
                 }
+
+                if is_start && let Some(ref experiment) = current_experiment {
+                    record_tags(experiment, &db_sender).await;
+                }
+
+                for event in SystemEvent::experiment_changes(&previous_experiment, &current_experiment) {
+                    publish_event(&event_sender, event);
+                }
+
+                // Notify control loop
+                if let Err(err) = experiment_sender.send(current_experiment.clone()) {
+                    error!("Unable to notify control loop of new experiment: {err}");
+                }
+
+                persist_experiment(&current_experiment).await;
             }
+            // Periodically refresh the stored experiment, so we know when the service went down
+            _ = save_ticker.tick() => {
+                if current_experiment.is_some() {
+                    persist_experiment(&current_experiment).await;
+                }
+            }
+        }
+    }
+}
+
+/// Apply an experiment lifecycle command to the current experiment
+/// Returns false if the command does not apply to the current experiment state
+fn apply_command(current_experiment: &mut Option<Experiment>, command: ExperimentCommand) -> bool {
+    match command {
+        ExperimentCommand::Start(
+            ExperimentStartMessage {
+                name,
+                description,
+                stop_conditions,
+                tags,
+                backfill_seconds,
+            },
+            started_by,
+        ) => {
+            // Construct a new experiment
+            let id = Uuid::new_v4();
+            let new_experiment = Experiment {
+                is_running: true,
+                id,
+                name: name.to_string(),
+                description,
+                table_name: table_name_for(id),
+                start_time: Utc::now() - chrono::Duration::seconds(backfill_seconds as i64),
+                duration_seconds: chrono::Duration::zero(),
+                stop_conditions,
+                tags,
+                started_by: Some(started_by),
+                ..Default::default()
+            };
+
+            info!("New experiment started: {:?}", new_experiment);
+            *current_experiment = Some(new_experiment);
         }
+        ExperimentCommand::Stop => {
+            info!("Experiment stopped");
+            *current_experiment = None;
+        }
+        ExperimentCommand::Pause => match current_experiment {
+            Some(experiment) if !experiment.is_paused => {
+                // Stop logging, but keep the experiment around
+                experiment.is_paused = true;
+                experiment.paused_since = Some(Utc::now());
+                info!("Experiment {} paused", experiment.id);
+            }
+            _ => {
+                warn!("Pause requested but no unpaused experiment is running");
+                return false;
+            }
+        },
+        ExperimentCommand::Resume => match current_experiment {
+            Some(experiment) if experiment.is_paused => {
+                // Book the finished pause and continue logging
+                experiment.paused_duration = experiment.paused_duration_at(Utc::now());
+                experiment.paused_since = None;
+                experiment.is_paused = false;
+                info!("Experiment {} resumed", experiment.id);
+            }
+            _ => {
+                warn!("Resume requested but no paused experiment is running");
+                return false;
+            }
+        },
+        ExperimentCommand::Edit(id, edit) => match current_experiment {
+            Some(experiment) if experiment.id == id => {
+                // Data logged from now on is tagged with the new metadata
+                if let Some(name) = edit.name {
+                    experiment.name = name;
+                }
+                if let Some(description) = edit.description {
+                    experiment.description = description;
+                }
+                if let Some(tags) = edit.tags {
+                    experiment.tags = tags;
+                }
+                info!("Experiment {} edited", experiment.id);
+            }
+            _ => {
+                warn!("Edit requested for experiment {id}, which is not running");
+                return false;
+            }
+        },
+    }
+
+    true
+}
+
+/// Record the tags a new experiment was started with in the catalogue, as the experiment data
+/// only carries its name and description
+async fn record_tags(experiment: &Experiment, db_sender: &mpsc::Sender<DatabaseMessage>) {
+    // Untagged experiments need no catalogue entry
+    if experiment.tags.is_empty() {
+        return;
+    }
+
+    let entry = CatalogueEntry {
+        experiment_id: experiment.id,
+        name: experiment.name.clone(),
+        description: experiment.description.clone(),
+        tags: experiment.tags.clone(),
+        deleted_at: None,
+        time: experiment.start_time,
+    };
+    if let Err(err) = db_sender.send(DatabaseMessage::Catalogue(entry)).await {
+        error!("Unable to send experiment tags to database task: {err}");
+    }
+}
+
+/// Store the running experiment on disk, or remove it once no experiment is running
+async fn persist_experiment(current_experiment: &Option<Experiment>) {
+    let result = match current_experiment {
+        Some(experiment) => save_experiment(experiment).await,
+        None => clear_experiment().await,
+    };
+
+    if let Err(err) = result {
+        error!("Unable to persist the current experiment: {err}");
     }
 }
 
-fn create_table_from_experiment(name: &str) -> String {
-    format!("experiment_{}_{}", name, Utc::now().format("%Y%m%d_%H%M%S"))
+/// Resume the experiment that was running when the service stopped, or abort it if the service
+/// was down for too long. Either way the downtime is recorded as a data gap
+async fn recover_experiment(db_sender: &mpsc::Sender<DatabaseMessage>) -> Option<Experiment> {
+    let persisted = match load_experiment().await {
+        Ok(Some(persisted)) => persisted,
+        Ok(None) => return None,
+        Err(err) => {
+            error!("Unable to load the experiment running before the restart: {err}");
+            return None;
+        }
+    };
+
+    let mut experiment = persisted.experiment;
+    let now = Utc::now();
+    let downtime = now.signed_duration_since(persisted.last_seen);
+
+    // Record the period during which the service was down
+    let gap = DataGap {
+        experiment_id: experiment.id,
+        start: persisted.last_seen,
+        end: now,
+        cause: GapCause::ServiceRestart,
+    };
+    if let Err(err) = db_sender.send(DatabaseMessage::DataGap(gap)).await {
+        error!("Unable to send restart gap to database task: {err}");
+    }
+
+    if downtime.to_std().unwrap_or_default() <= MAX_RECOVERY_GAP {
+        info!(
+            "Resuming experiment {} - {} after {}s of downtime",
+            experiment.id,
+            experiment.name,
+            downtime.num_seconds()
+        );
+        experiment.restarts += 1;
+        return Some(experiment);
+    }
+
+    warn!(
+        "Aborting experiment {} - {} after {}s of downtime",
+        experiment.id,
+        experiment.name,
+        downtime.num_seconds()
+    );
+
+    // Mark the experiment as aborted, so the reason shows up next to its data
+    let annotation = Annotation {
+        time: now,
+        experiment_id: experiment.id,
+        category: Some(String::from("system")),
+        text: format!(
+            "Experiment aborted: service was down for {}s, longer than the {}s recovery window",
+            downtime.num_seconds(),
+            MAX_RECOVERY_GAP.as_secs()
+        ),
+    };
+    if let Err(err) = db_sender
+        .send(DatabaseMessage::Annotation(annotation))
+        .await
+    {
+        error!("Unable to send abort annotation to database task: {err}");
+    }
+
+    if let Err(err) = clear_experiment().await {
+        error!("Unable to clear the aborted experiment: {err}");
+    }
+
+    // The service was down since it was last seen, so that is when the experiment stopped
+    task::spawn(summarise_stopped_experiment(
+        experiment,
+        persisted.last_seen,
+        db_sender.clone(),
+    ));
+
+    None
 }
@@ -1,9 +1,21 @@
+pub mod annotations;
+pub mod catalogue;
+pub mod events;
+pub mod gaps;
+pub mod listing;
 pub mod manage;
+pub mod naming;
+pub mod persist;
+pub mod quality;
+pub mod search;
 
 use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
+use crate::control::history::HISTORY_SECONDS;
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct Experiment {
     pub is_running: bool,
@@ -13,42 +25,166 @@ pub struct Experiment {
     pub table_name: String,
     pub start_time: DateTime<Utc>,
     pub duration_seconds: Duration,
+    /// Is DB logging currently paused?
+    pub is_paused: bool,
+    /// Time at which the current pause started
+    pub paused_since: Option<DateTime<Utc>>,
+    /// Accumulated duration of all finished pauses
+    pub paused_duration: Duration,
+    /// Conditions that automatically stop this experiment
+    pub stop_conditions: StopConditions,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Number of times the service restarted and resumed this experiment
+    #[serde(default)]
+    pub restarts: u32,
+    /// User that started this experiment, None for experiments started before users were introduced
+    #[serde(default)]
+    pub started_by: Option<String>,
+}
+
+impl Experiment {
+    /// Total time this experiment spent paused, including a pause that is still ongoing
+    pub fn paused_duration_at(&self, now: DateTime<Utc>) -> Duration {
+        match self.paused_since {
+            Some(since) => self.paused_duration + now.signed_duration_since(since),
+            None => self.paused_duration,
+        }
+    }
+
+    /// Time this experiment spent logging, i.e. excluding pauses
+    pub fn active_duration_at(&self, now: DateTime<Utc>) -> Duration {
+        now.signed_duration_since(self.start_time) - self.paused_duration_at(now)
+    }
+}
+
+/// Optional conditions that automatically stop a running experiment
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, ToSchema)]
+pub struct StopConditions {
+    /// Stop after logging for this many seconds, pauses excluded
+    #[serde(default)]
+    pub max_duration_seconds: Option<u64>,
+    /// Stop after this many samples have been logged
+    #[serde(default)]
+    pub max_samples: Option<u64>,
+    /// Stop at the end of a protocol. Not supported, as no protocol is run by the service, so
+    /// starting an experiment with this set is rejected
+    #[serde(default)]
+    pub end_of_protocol: bool,
+}
+
+impl StopConditions {
+    /// Should an experiment with the given active duration and logged samples be stopped?
+    pub fn is_met(&self, active_duration: Duration, logged_samples: u64) -> bool {
+        let duration_met = self
+            .max_duration_seconds
+            .is_some_and(|max| active_duration.num_seconds() >= max as i64);
+        let samples_met = self.max_samples.is_some_and(|max| logged_samples >= max);
+
+        duration_met || samples_met
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Default)]
+#[derive(Debug, Clone, Serialize, Default, ToSchema)]
 pub struct ExperimentStatus {
     is_running: bool,
+    is_paused: bool,
     experiment_id: Uuid,
     experiment_name: String,
     description: String,
     table_name: String,
     start_time: DateTime<Utc>,
+    /// Time spent logging, pauses excluded
     duration_seconds: i64,
+    /// Time spent paused
+    paused_seconds: i64,
+    stop_conditions: StopConditions,
+    tags: Vec<String>,
+    data_quality: quality::DataQuality,
+    data_quality_flags: Vec<quality::QualityFlag>,
+    restarts: u32,
+    started_by: Option<String>,
+}
+
+impl ExperimentStatus {
+    /// Attach the data-quality counters of the running experiment
+    pub fn with_quality(mut self, data_quality: quality::DataQuality) -> Self {
+        self.data_quality_flags = data_quality.flags(self.restarts);
+        self.data_quality = data_quality;
+        self
+    }
 }
 
 impl From<&Experiment> for ExperimentStatus {
     fn from(exp: &Experiment) -> Self {
-        // Calculate duration dynamically based on current time
+        // Calculate the logging duration dynamically based on current time, pauses excluded
+        let now = Utc::now();
         let duration = if exp.is_running {
-            Utc::now().signed_duration_since(exp.start_time)
+            exp.active_duration_at(now)
         } else {
             exp.duration_seconds
         };
 
         Self {
             is_running: exp.is_running,
+            is_paused: exp.is_paused,
             experiment_id: exp.id,
             experiment_name: exp.name.clone(),
             description: exp.description.clone(),
             table_name: exp.table_name.clone(),
             start_time: exp.start_time,
             duration_seconds: duration.num_seconds(),
+            paused_seconds: exp.paused_duration_at(now).num_seconds(),
+            stop_conditions: exp.stop_conditions.clone(),
+            tags: exp.tags.clone(),
+            restarts: exp.restarts,
+            started_by: exp.started_by.clone(),
+            ..Default::default()
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ExperimentStartMessage {
     name: String,
     description: String,
+    #[serde(default)]
+    stop_conditions: StopConditions,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Start the experiment this many seconds ago, logging the reports kept in memory since
+    #[serde(default)]
+    backfill_seconds: u64,
+}
+
+impl ExperimentStartMessage {
+    /// Validate and normalize the requested experiment name and tags
+    pub fn validate(&mut self) -> Result<(), catalogue::ExperimentEditError> {
+        self.name = naming::validate_experiment_name(&self.name)?;
+        self.tags = catalogue::validate_tags(&self.tags)?;
+        if self.backfill_seconds > HISTORY_SECONDS {
+            return Err(catalogue::ExperimentEditError::BackfillTooLong);
+        }
+        if self.stop_conditions.end_of_protocol {
+            return Err(catalogue::ExperimentEditError::EndOfProtocolUnsupported);
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Experiment lifecycle changes requested by the frontend or the control loop
+/// Queued in order, every command reaches the experiment manager
+#[derive(Debug, Clone)]
+pub enum ExperimentCommand {
+    /// Start an experiment, on behalf of the named user
+    Start(ExperimentStartMessage, String),
+    Stop,
+    Pause,
+    Resume,
+    /// Change the metadata of the running experiment with the given id
+    Edit(Uuid, catalogue::ExperimentEdit),
 }
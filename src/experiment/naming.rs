@@ -0,0 +1,73 @@
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Prefix shared by all experiment data tables
+pub const EXPERIMENT_TABLE_PREFIX: &str = "experiment_";
+/// Maximum length of an experiment name, in characters
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ExperimentNameError {
+    #[error("experiment name must not be empty")]
+    Empty,
+    #[error("experiment name must be at most {MAX_NAME_LEN} characters long")]
+    TooLong,
+    #[error("experiment name must not contain control characters")]
+    ControlCharacter,
+    #[error("experiment name must contain at least one letter or digit")]
+    NoAlphanumeric,
+    #[error("an experiment named '{0}' already exists")]
+    Duplicate(String),
+}
+
+/// Validate a user supplied experiment name, returning the trimmed name
+pub fn validate_experiment_name(name: &str) -> Result<String, ExperimentNameError> {
+    let name = name.trim();
+
+    if name.is_empty() {
+        return Err(ExperimentNameError::Empty);
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(ExperimentNameError::TooLong);
+    }
+    if name.chars().any(char::is_control) {
+        return Err(ExperimentNameError::ControlCharacter);
+    }
+    if slugify(name).is_empty() {
+        return Err(ExperimentNameError::NoAlphanumeric);
+    }
+
+    Ok(name.to_string())
+}
+
+/// Reduce an experiment name to lowercase letters and digits separated by single dashes,
+/// e.g. "Prototype 7: Run #2" becomes "prototype-7-run-2" and "Müller/Ω" becomes "müller-ω"
+/// Used to compare names, so any script is kept
+pub fn slugify(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Name of the data table of an experiment, derived from its id so it never contains user input
+pub fn table_name_for(id: Uuid) -> String {
+    format!("{EXPERIMENT_TABLE_PREFIX}{}", id.simple())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_ascii_names_are_valid() {
+        assert_eq!(validate_experiment_name(" Тест ").as_deref(), Ok("Тест"));
+        assert_eq!(slugify("Prototype 7: Run #2"), "prototype-7-run-2");
+        assert_eq!(slugify("Müller/Ω"), "müller-ω");
+        assert_eq!(
+            validate_experiment_name("#!?"),
+            Err(ExperimentNameError::NoAlphanumeric)
+        );
+    }
+}
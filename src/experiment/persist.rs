@@ -0,0 +1,50 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::experiment::Experiment;
+
+/// File storing the running experiment, relative to the service working directory
+const EXPERIMENT_STATE_PATH: &str = "experiment_state.json";
+
+/// Running experiment as stored on disk, so it survives a service restart
+/// No protocol position is stored, as the service runs no protocols: the protocol steps of an
+/// experiment are derived from its setpoint events, which are already in the database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistedExperiment {
+    pub experiment: Experiment,
+    /// Last time the service was known to be alive while running this experiment
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Store the running experiment on disk
+pub async fn save_experiment(experiment: &Experiment) -> Result<()> {
+    let persisted = PersistedExperiment {
+        experiment: experiment.clone(),
+        last_seen: Utc::now(),
+    };
+
+    // Write to a temporary file first, so a crash halfway through never leaves a corrupt file
+    let tmp_path = format!("{EXPERIMENT_STATE_PATH}.tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&persisted)?).await?;
+    fs::rename(&tmp_path, EXPERIMENT_STATE_PATH).await?;
+    Ok(())
+}
+
+/// Load the experiment that was running when the service last stopped, if any
+pub async fn load_experiment() -> Result<Option<PersistedExperiment>> {
+    match fs::read(EXPERIMENT_STATE_PATH).await {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Forget the stored experiment, e.g. because it was stopped
+pub async fn clear_experiment() -> Result<()> {
+    match fs::remove_file(EXPERIMENT_STATE_PATH).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
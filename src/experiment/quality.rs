@@ -0,0 +1,204 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::experiment::gaps::{DataGap, GapCause};
+
+/// Period at which the MCU sends reports
+pub const REPORT_PERIOD: Duration = Duration::from_millis(10);
+/// A report arriving this many periods after the previous one means samples were lost
+const GAP_THRESHOLD_PERIODS: f64 = 1.5;
+/// Weight of a new interval in the measured report period
+const PERIOD_SMOOTHING: f64 = 0.01;
+
+/// Data-quality counters of the running experiment, reset when a new experiment starts
+#[derive(Debug, Clone, Serialize, Default, ToSchema)]
+pub struct DataQuality {
+    #[serde(skip)]
+    pub experiment_id: Uuid,
+    /// Reports checked against the expected cadence
+    pub samples: u64,
+    /// Samples the MCU should have sent but never arrived
+    pub missing_samples: u64,
+    /// Reports repeating an MCU timestamp that was already recorded, these are not written
+    pub duplicate_samples: u64,
+    /// Recorded samples that never made it into the DB
+    pub lost_samples: u64,
+    pub gaps: u64,
+    pub db_failures: u64,
+    pub last_gap: Option<DataGap>,
+    /// Measured interval between MCU reports, in ms
+    pub report_period_ms: f64,
+}
+
+/// Problem with the recorded data, surfaced in the experiment status
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityFlag {
+    MissingSamples,
+    DuplicateSamples,
+    DatabaseFailure,
+    ServiceRestart,
+}
+
+impl DataQuality {
+    pub fn new(experiment_id: Uuid) -> Self {
+        Self {
+            experiment_id,
+            report_period_ms: REPORT_PERIOD.as_secs_f64() * 1000.0,
+            ..Default::default()
+        }
+    }
+
+    /// Note a gap in the data of this experiment
+    pub fn record_gap(&mut self, gap: &DataGap) {
+        if gap.experiment_id != self.experiment_id {
+            return;
+        }
+        self.gaps += 1;
+        self.last_gap = Some(gap.clone());
+    }
+
+    /// Note a batch of `samples` of this experiment the DB task failed to write
+    pub fn record_db_failure(&mut self, experiment_id: Uuid, samples: u64) {
+        if experiment_id != self.experiment_id {
+            return;
+        }
+        self.db_failures += 1;
+        self.lost_samples += samples;
+    }
+
+    /// Problems with the data recorded so far, `restarts` is the number of times the service
+    /// restarted during the experiment
+    pub fn flags(&self, restarts: u32) -> Vec<QualityFlag> {
+        let mut flags = Vec::new();
+        if self.missing_samples > 0 {
+            flags.push(QualityFlag::MissingSamples);
+        }
+        if self.duplicate_samples > 0 {
+            flags.push(QualityFlag::DuplicateSamples);
+        }
+        if self.db_failures > 0 || self.lost_samples > 0 {
+            flags.push(QualityFlag::DatabaseFailure);
+        }
+        if restarts > 0 {
+            flags.push(QualityFlag::ServiceRestart);
+        }
+        flags
+    }
+}
+
+/// Outcome of checking a report against the expected cadence
+#[derive(Debug, Clone)]
+pub enum Cadence {
+    OnTime,
+    /// The report repeats or precedes an MCU timestamp that was already seen
+    Duplicate,
+    /// Samples were lost between the previous report and this one
+    Gap(DataGap),
+}
+
+/// Tracks the cadence of the MCU reports of an experiment using the MCU timestamps
+#[derive(Debug, Clone)]
+pub struct CadenceTracker {
+    experiment_id: Uuid,
+    /// MCU timestamp in µs and time of the last report
+    last: Option<(u64, DateTime<Utc>)>,
+    /// Measured report period in µs
+    period_us: f64,
+    /// Did waiting on the MCU time out since the last report?
+    timed_out: bool,
+    /// Did the MCU restart its clock since the last report?
+    reset: bool,
+}
+
+impl CadenceTracker {
+    pub fn new(experiment_id: Uuid) -> Self {
+        Self {
+            experiment_id,
+            last: None,
+            period_us: REPORT_PERIOD.as_micros() as f64,
+            timed_out: false,
+            reset: false,
+        }
+    }
+
+    /// Note that no report arrived within the communication timeout
+    pub fn timeout(&mut self) {
+        self.timed_out = true;
+    }
+
+    /// Note that the MCU restarted its clock, so the next timestamp starts over
+    pub fn mcu_reset(&mut self) {
+        self.reset = true;
+    }
+
+    /// Forget the last report, e.g. because logging was paused, so the next one is never a gap
+    pub fn pause(&mut self) {
+        self.last = None;
+        self.timed_out = false;
+        self.reset = false;
+    }
+
+    /// Check the next report of the experiment and update `quality`
+    pub fn check(
+        &mut self,
+        mcu_timestamp_us: u64,
+        time: DateTime<Utc>,
+        quality: &mut DataQuality,
+    ) -> Cadence {
+        let Some((last_timestamp, last_time)) = self.last else {
+            self.last = Some((mcu_timestamp_us, time));
+            self.timed_out = false;
+            self.reset = false;
+            quality.samples += 1;
+            return Cadence::OnTime;
+        };
+
+        // Timestamps before and after a reset can not be compared, fall back to the host time
+        if std::mem::take(&mut self.reset) {
+            self.last = Some((mcu_timestamp_us, time));
+            self.timed_out = false;
+            quality.samples += 1;
+            let elapsed_us = (time - last_time).num_microseconds().unwrap_or(0) as f64;
+            quality.missing_samples +=
+                ((elapsed_us / self.period_us).round() as u64).saturating_sub(1);
+            return Cadence::Gap(DataGap {
+                experiment_id: self.experiment_id,
+                start: last_time,
+                end: time,
+                cause: GapCause::McuReset,
+            });
+        }
+
+        if mcu_timestamp_us <= last_timestamp {
+            quality.duplicate_samples += 1;
+            return Cadence::Duplicate;
+        }
+
+        self.last = Some((mcu_timestamp_us, time));
+        let timed_out = std::mem::take(&mut self.timed_out);
+        quality.samples += 1;
+
+        let interval = (mcu_timestamp_us - last_timestamp) as f64;
+        if interval <= GAP_THRESHOLD_PERIODS * self.period_us {
+            self.period_us += PERIOD_SMOOTHING * (interval - self.period_us);
+            quality.report_period_ms = self.period_us / 1000.0;
+            return Cadence::OnTime;
+        }
+
+        quality.missing_samples += ((interval / self.period_us).round() as u64).saturating_sub(1);
+        Cadence::Gap(DataGap {
+            experiment_id: self.experiment_id,
+            start: last_time,
+            end: time,
+            cause: if timed_out {
+                GapCause::McuTimeout
+            } else {
+                GapCause::SampleLoss
+            },
+        })
+    }
+}
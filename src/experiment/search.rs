@@ -0,0 +1,153 @@
+use std::cmp::Ordering;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::database::experiment_data::parse_influx_time;
+use crate::experiment::listing::ExperimentFromDB;
+use crate::http::messages::ExperimentListQuery;
+
+/// Maximum number of experiments returned in a single page
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Field the experiment list is sorted by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExperimentSort {
+    #[default]
+    StartTime,
+    Name,
+    Duration,
+}
+
+/// Direction the experiment list is sorted in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ExperimentSearchError {
+    #[error("the start of the date range lies after its end")]
+    InvalidDateRange,
+    #[error("the minimum duration must not be negative")]
+    NegativeDuration,
+    #[error("the page size must be between 1 and {MAX_PAGE_SIZE}")]
+    InvalidPageSize,
+}
+
+impl ExperimentSearchError {
+    /// Query parameter the error is about
+    pub fn field(&self) -> &'static str {
+        match self {
+            ExperimentSearchError::InvalidDateRange => "from",
+            ExperimentSearchError::NegativeDuration => "min_duration_seconds",
+            ExperimentSearchError::InvalidPageSize => "limit",
+        }
+    }
+}
+
+impl ExperimentListQuery {
+    /// Check the query for contradicting or out of range parameters
+    pub fn validate(&self) -> Result<(), ExperimentSearchError> {
+        if let (Some(from), Some(to)) = (self.from, self.to)
+            && from > to
+        {
+            return Err(ExperimentSearchError::InvalidDateRange);
+        }
+        if self.min_duration_seconds.is_some_and(|d| d < 0.0) {
+            return Err(ExperimentSearchError::NegativeDuration);
+        }
+        if self
+            .limit
+            .is_some_and(|limit| limit == 0 || limit > MAX_PAGE_SIZE)
+        {
+            return Err(ExperimentSearchError::InvalidPageSize);
+        }
+        Ok(())
+    }
+
+    /// Does an experiment match all filters of this query?
+    fn matches(&self, experiment: &ExperimentFromDB, tags: &[String], search: &str) -> bool {
+        // Tags are compared case insensitive, an experiment has to carry every requested tag
+        let has_tags = tags.iter().all(|tag| {
+            experiment
+                .tags
+                .iter()
+                .any(|t| t.to_lowercase() == tag.as_str())
+        });
+        let has_text = search.is_empty()
+            || experiment.experiment_name.to_lowercase().contains(search)
+            || experiment.description.to_lowercase().contains(search);
+
+        // Experiments without a known start time never match a date range
+        let start_time = start_time_of(experiment);
+        let after_from = self
+            .from
+            .is_none_or(|from| start_time.is_some_and(|t| t >= from));
+        let before_to = self.to.is_none_or(|to| start_time.is_some_and(|t| t <= to));
+        let long_enough = self
+            .min_duration_seconds
+            .is_none_or(|min| experiment.duration_seconds >= min);
+
+        has_tags && has_text && after_from && before_to && long_enough
+    }
+
+    /// Filter, sort and paginate a list of experiments
+    /// Returns the requested page and the number of experiments matching the filters
+    pub fn apply(&self, experiments: Vec<ExperimentFromDB>) -> (Vec<ExperimentFromDB>, usize) {
+        let tags: Vec<String> = self
+            .tags
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        let search = self.search.as_deref().unwrap_or("").trim().to_lowercase();
+
+        let mut experiments: Vec<ExperimentFromDB> = experiments
+            .into_iter()
+            .filter(|e| self.matches(e, &tags, &search))
+            .collect();
+        let total = experiments.len();
+
+        let sort = self.sort.unwrap_or_default();
+        experiments.sort_by(|a, b| {
+            let ordering = compare(sort, a, b);
+            match self.order.unwrap_or_default() {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        });
+
+        let page = experiments
+            .into_iter()
+            .skip(self.offset.unwrap_or(0))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        (page, total)
+    }
+}
+
+/// Start time of an experiment listed from the database, if it is known
+fn start_time_of(experiment: &ExperimentFromDB) -> Option<DateTime<Utc>> {
+    experiment.start_time.as_deref().and_then(parse_influx_time)
+}
+
+/// Compare two experiments by the given field, in ascending order
+fn compare(sort: ExperimentSort, a: &ExperimentFromDB, b: &ExperimentFromDB) -> Ordering {
+    match sort {
+        ExperimentSort::StartTime => start_time_of(a).cmp(&start_time_of(b)),
+        ExperimentSort::Name => a
+            .experiment_name
+            .to_lowercase()
+            .cmp(&b.experiment_name.to_lowercase()),
+        ExperimentSort::Duration => a.duration_seconds.total_cmp(&b.duration_seconds),
+    }
+}
@@ -0,0 +1,586 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+
+use axum::body::Body;
+use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::write::{GzDecoder, GzEncoder};
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt, stream};
+use influxdb::{Client, InfluxDbWriteable as _, Timestamp, Type, WriteQuery};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::*;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::config;
+use crate::database::experiment_data::parse_influx_time;
+use crate::database::query::{
+    TableColumn, delete_table, query_table_columns, quote_identifier, stream_sql,
+};
+use crate::database::secrets::*;
+use crate::experiment::catalogue::{CatalogueEntry, apply_catalogue, apply_catalogue_to_row};
+use crate::experiment::listing::{
+    ExperimentFromDB, get_experiment_metadata, resolve_experiment_table,
+};
+use crate::experiment::naming::table_name_for;
+use crate::export::ExportError;
+use crate::messages::db_messages::CatalogueRecord;
+
+/// Version of the archive layout, bumped on incompatible changes
+const ARCHIVE_VERSION: u32 = 1;
+/// Number of archive lines compressed into every chunk of a streamed archive
+const ARCHIVE_CHUNK_LINES: usize = 500;
+/// Number of rows written to InfluxDB at once during an import
+const IMPORT_BATCH_LEN: usize = 1000;
+/// Longest line accepted in an imported archive, rows and metadata are far shorter
+const MAX_ARCHIVE_LINE_LEN: usize = 1024 * 1024;
+/// Decompressed size a single chunk of the request body may expand to, far above what archive
+/// data compresses to but well below what a gzip bomb expands to
+const MAX_IMPORT_CHUNK_EXPANSION: usize = 64 * 1024 * 1024;
+/// Decompressed size of an imported archive
+const MAX_IMPORT_LEN: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Part of an experiment stored in an archive
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveTable {
+    /// Measurements, stored in the experiment table
+    Data,
+    SetpointEvents,
+    Annotations,
+    DataGaps,
+    Summaries,
+}
+
+impl ArchiveTable {
+    const ALL: [ArchiveTable; 5] = [
+        ArchiveTable::Data,
+        ArchiveTable::SetpointEvents,
+        ArchiveTable::Annotations,
+        ArchiveTable::DataGaps,
+        ArchiveTable::Summaries,
+    ];
+
+    /// Name of the shared InfluxDB table holding this part of every experiment, tagged with the
+    /// experiment id. Measurements are stored in a table per experiment instead
+    fn shared_table(&self) -> Option<&'static str> {
+        match self {
+            ArchiveTable::Data => None,
+            ArchiveTable::SetpointEvents => Some(SETPOINT_EVENT_TABLE),
+            ArchiveTable::Annotations => Some(ANNOTATION_TABLE),
+            ArchiveTable::DataGaps => Some(DATA_GAP_TABLE),
+            ArchiveTable::Summaries => Some(SUMMARY_TABLE),
+        }
+    }
+}
+
+/// Single line of an archive, which is a gzip compressed JSON Lines file
+/// Every archive starts with a manifest, followed by an `Experiment` line per experiment. Every
+/// experiment is followed by its tables, every table by its rows
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArchiveRecord {
+    Manifest {
+        version: u32,
+        created: DateTime<Utc>,
+        experiments: Vec<Uuid>,
+    },
+    Experiment {
+        metadata: ExperimentFromDB,
+    },
+    Table {
+        table: ArchiveTable,
+        columns: Vec<TableColumn>,
+    },
+    Row {
+        row: Value,
+    },
+}
+
+/// Outcome of an archive import
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ImportSummary {
+    /// Experiments recreated from the archive
+    pub imported: Vec<Uuid>,
+    /// Experiments skipped because they already exist
+    pub duplicates: Vec<Uuid>,
+    /// Number of rows written, over all tables
+    pub rows: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("database error: {0}")]
+    Database(String),
+}
+
+/// Part of an archive still to be written
+enum Segment {
+    Record(ArchiveRecord),
+    /// Rows returned by a query, written as `ArchiveRecord::Row`
+    /// Data rows carry the metadata of their experiment, to update their tags after an edit
+    Rows(String, Option<ExperimentFromDB>),
+}
+
+/// Package experiments into an archive, streamed chunk by chunk
+/// The metadata and table schemas are looked up front, so unknown experiments are reported before
+/// anything is sent. Rows are only queried once they are written
+pub async fn stream_archive(
+    experiment_ids: Vec<Uuid>,
+) -> Result<impl Stream<Item = Result<Vec<u8>, String>> + Send + 'static, ExportError> {
+    let client = reqwest::Client::new();
+
+    let mut segments = VecDeque::new();
+    segments.push_back(Segment::Record(ArchiveRecord::Manifest {
+        version: ARCHIVE_VERSION,
+        created: Utc::now(),
+        experiments: experiment_ids.clone(),
+    }));
+
+    for id in experiment_ids {
+        let table_name = resolve_experiment_table(&client, id)
+            .await?
+            .ok_or_else(|| ExportError::NotFound(id.to_string()))?;
+        let mut metadata = get_experiment_metadata(&client, &table_name)
+            .await?
+            .ok_or_else(|| ExportError::NoData(id.to_string()))?;
+        apply_catalogue(&client, &mut metadata).await?;
+        segments.push_back(Segment::Record(ArchiveRecord::Experiment {
+            metadata: metadata.clone(),
+        }));
+
+        for table in ArchiveTable::ALL {
+            let source = table.shared_table().unwrap_or(&table_name);
+            let columns = query_table_columns(&client, source).await?;
+            // Shared tables only exist once something was written to them
+            if columns.is_empty() {
+                continue;
+            }
+
+            // The experiment id is a parsed uuid, so it is safe to interpolate
+            let query = match table.shared_table() {
+                Some(_) => format!(
+                    r#"SELECT * FROM {} WHERE experiment_id = '{}' ORDER BY time ASC"#,
+                    quote_identifier(source),
+                    id
+                ),
+                None => format!(
+                    r#"SELECT * FROM {} ORDER BY time ASC"#,
+                    quote_identifier(source)
+                ),
+            };
+            segments.push_back(Segment::Record(ArchiveRecord::Table { table, columns }));
+            let row_metadata = table.shared_table().is_none().then(|| metadata.clone());
+            segments.push_back(Segment::Rows(query, row_metadata));
+        }
+    }
+
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let rows: Option<BoxStream<'static, Result<Value, String>>> = None;
+
+    Ok(stream::unfold(
+        Some((client, segments, rows, encoder)),
+        |state| async move {
+            let (client, mut segments, mut rows, mut encoder) = state?;
+
+            for _ in 0..ARCHIVE_CHUNK_LINES {
+                let record = match rows.as_mut() {
+                    Some(current) => match current.next().await {
+                        Some(Ok(row)) => ArchiveRecord::Row { row },
+                        Some(Err(e)) => return Some((Err(e), None)),
+                        None => {
+                            rows = None;
+                            continue;
+                        }
+                    },
+                    None => match segments.pop_front() {
+                        Some(Segment::Record(record)) => record,
+                        Some(Segment::Rows(query, metadata)) => {
+                            match stream_sql(&client, &query).await {
+                                Ok(stream) => {
+                                    rows = Some(match metadata {
+                                        Some(metadata) => stream
+                                            .map(move |row| {
+                                                row.map(|mut row| {
+                                                    apply_catalogue_to_row(&metadata, &mut row);
+                                                    row
+                                                })
+                                            })
+                                            .boxed(),
+                                        None => stream.boxed(),
+                                    });
+                                    continue;
+                                }
+                                Err(e) => return Some((Err(e), None)),
+                            }
+                        }
+                        None => {
+                            let result = encoder
+                                .finish()
+                                .map_err(|e| format!("Failed to compress archive: {}", e));
+                            return Some((result, None));
+                        }
+                    },
+                };
+
+                if let Err(e) = write_record(&mut encoder, &record) {
+                    return Some((Err(e), None));
+                }
+            }
+
+            // Hand out whatever was compressed so far
+            let chunk = std::mem::take(encoder.get_mut());
+            Some((Ok(chunk), Some((client, segments, rows, encoder))))
+        },
+    ))
+}
+
+fn write_record(encoder: &mut GzEncoder<Vec<u8>>, record: &ArchiveRecord) -> Result<(), String> {
+    serde_json::to_writer(&mut *encoder, record)
+        .map_err(|e| format!("Failed to serialize archive record: {}", e))?;
+    encoder
+        .write_all(b"\n")
+        .map_err(|e| format!("Failed to compress archive: {}", e))
+}
+
+/// Recreate the experiments in an archive under their original ids
+/// Experiments which already exist are skipped and reported as duplicates
+/// The archive is decompressed and written to InfluxDB as it comes in. An import failing halfway
+/// removes the experiment it was importing, so the archive can be imported again
+pub async fn import_archive(body: Body) -> Result<ImportSummary, ImportError> {
+    let mut importer = Importer::new();
+    let result = import_records(body, &mut importer).await;
+    if result.is_err() {
+        importer.abort().await;
+    }
+    result
+}
+
+async fn import_records(body: Body, importer: &mut Importer) -> Result<ImportSummary, ImportError> {
+    let mut reader = ArchiveReader::new();
+    let mut chunks = body.into_data_stream();
+
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| {
+            ImportError::InvalidArchive(format!("failed to read request body: {}", e))
+        })?;
+
+        // Decompress a bit at a time, so only a few lines are held at once
+        reader.start_chunk();
+        let mut input = &chunk[..];
+        while !input.is_empty() {
+            let consumed = reader.decompress(input)?;
+            input = &input[consumed..];
+            for record in reader.records()? {
+                importer.handle(record).await?;
+            }
+        }
+    }
+
+    reader.finish()?;
+    for record in reader.records()? {
+        importer.handle(record).await?;
+    }
+    importer.finish().await
+}
+
+/// Decompresses an archive and splits it into records, bounding the memory an import takes
+/// whatever the archive holds
+struct ArchiveReader {
+    decoder: GzDecoder<Vec<u8>>,
+    /// Decompressed data not split into lines yet
+    buffer: Vec<u8>,
+    /// Decompressed bytes since the start of the current request chunk
+    chunk_len: usize,
+    /// Decompressed bytes since the start of the archive
+    total_len: u64,
+}
+
+impl ArchiveReader {
+    fn new() -> Self {
+        Self {
+            decoder: GzDecoder::new(Vec::new()),
+            buffer: Vec::new(),
+            chunk_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn start_chunk(&mut self) {
+        self.chunk_len = 0;
+    }
+
+    /// Decompress the start of `input`, returning the number of bytes consumed
+    /// Every call decompresses at most a few tens of kB
+    fn decompress(&mut self, input: &[u8]) -> Result<usize, ImportError> {
+        let consumed = self
+            .decoder
+            .write(input)
+            .map_err(|e| ImportError::InvalidArchive(format!("failed to decompress: {}", e)))?;
+        if consumed == 0 {
+            return Err(ImportError::InvalidArchive(
+                "unexpected data after the end of the archive".to_string(),
+            ));
+        }
+        self.take_output()?;
+        Ok(consumed)
+    }
+
+    /// Decompress whatever is left once the whole archive was received
+    fn finish(&mut self) -> Result<(), ImportError> {
+        self.decoder
+            .try_finish()
+            .map_err(|e| ImportError::InvalidArchive(format!("failed to decompress: {}", e)))?;
+        self.take_output()?;
+        self.buffer.push(b'\n');
+        Ok(())
+    }
+
+    fn take_output(&mut self) -> Result<(), ImportError> {
+        let output = self.decoder.get_mut();
+        self.chunk_len += output.len();
+        self.total_len += output.len() as u64;
+        if self.chunk_len > MAX_IMPORT_CHUNK_EXPANSION || self.total_len > MAX_IMPORT_LEN {
+            return Err(ImportError::InvalidArchive(
+                "archive decompresses to more data than an import may hold".to_string(),
+            ));
+        }
+        self.buffer.append(output);
+        Ok(())
+    }
+
+    /// Parse all complete lines, keeping the rest until more data is decompressed
+    fn records(&mut self) -> Result<Vec<ArchiveRecord>, ImportError> {
+        let too_long = || {
+            ImportError::InvalidArchive(format!("line longer than {} bytes", MAX_ARCHIVE_LINE_LEN))
+        };
+
+        let mut records = Vec::new();
+        let mut start = 0;
+        while let Some(len) = self.buffer[start..].iter().position(|b| *b == b'\n') {
+            if len > MAX_ARCHIVE_LINE_LEN {
+                return Err(too_long());
+            }
+            let line = &self.buffer[start..start + len];
+            start += len + 1;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let record = serde_json::from_slice(line)
+                .map_err(|e| ImportError::InvalidArchive(format!("invalid line: {}", e)))?;
+            records.push(record);
+        }
+        self.buffer.drain(..start);
+
+        // What is left is the start of the next line
+        if self.buffer.len() > MAX_ARCHIVE_LINE_LEN {
+            return Err(too_long());
+        }
+        Ok(records)
+    }
+}
+
+/// Writes the lines of an archive to InfluxDB, one at a time
+struct Importer {
+    db_client: Client,
+    client: reqwest::Client,
+    manifest_seen: bool,
+    /// Experiments already handled by this import
+    seen: HashSet<Uuid>,
+    /// Experiment whose tables are being imported, None while skipping a duplicate
+    experiment: Option<Uuid>,
+    /// Destination and column types of the rows being imported
+    table: Option<(String, HashMap<String, String>)>,
+    batch: Vec<WriteQuery>,
+    summary: ImportSummary,
+}
+
+impl Importer {
+    fn new() -> Self {
+        Self {
+            db_client: Client::new(&config().db_uri, &config().db_name)
+                .with_token(&config().db_access_token),
+            client: reqwest::Client::new(),
+            manifest_seen: false,
+            seen: HashSet::new(),
+            experiment: None,
+            table: None,
+            batch: Vec::with_capacity(IMPORT_BATCH_LEN),
+            summary: ImportSummary::default(),
+        }
+    }
+
+    async fn handle(&mut self, record: ArchiveRecord) -> Result<(), ImportError> {
+        let invalid = |message: &str| Err(ImportError::InvalidArchive(message.to_string()));
+
+        match record {
+            ArchiveRecord::Manifest { version, .. } => {
+                if self.manifest_seen {
+                    return invalid("duplicate manifest");
+                }
+                if version != ARCHIVE_VERSION {
+                    return Err(ImportError::InvalidArchive(format!(
+                        "unsupported archive version {}",
+                        version
+                    )));
+                }
+                self.manifest_seen = true;
+            }
+            _ if !self.manifest_seen => return invalid("archive does not start with a manifest"),
+            ArchiveRecord::Experiment { metadata } => {
+                self.flush().await?;
+                self.table = None;
+
+                let id = Uuid::parse_str(&metadata.experiment_id)
+                    .map_err(|e| ImportError::InvalidArchive(format!("invalid id: {}", e)))?;
+                let exists = resolve_experiment_table(&self.client, id)
+                    .await
+                    .map_err(ImportError::Database)?
+                    .is_some();
+
+                if exists || !self.seen.insert(id) {
+                    warn!("Skipping import of existing experiment {}", id);
+                    self.summary.duplicates.push(id);
+                    self.experiment = None;
+                } else {
+                    info!("Importing experiment {} - {}", id, metadata.experiment_name);
+                    self.summary.imported.push(id);
+                    self.experiment = Some(id);
+
+                    // Keep metadata edited after recording, imported experiments are never in
+                    // the trash
+                    if let Some(mut entry) = CatalogueEntry::from_experiment(&metadata) {
+                        entry.deleted_at = None;
+                        self.batch
+                            .push(CatalogueRecord::from(entry).into_query(CATALOGUE_TABLE));
+                    }
+                }
+            }
+            ArchiveRecord::Table { table, columns } => {
+                self.flush().await?;
+                self.table = self.experiment.map(|id| {
+                    let name = match table.shared_table() {
+                        Some(name) => name.to_string(),
+                        None => table_name_for(id),
+                    };
+                    let types = columns.into_iter().map(|c| (c.name, c.data_type)).collect();
+                    (name, types)
+                });
+            }
+            ArchiveRecord::Row { row } => {
+                // Rows of skipped experiments are dropped
+                let Some((table_name, types)) = &self.table else {
+                    return Ok(());
+                };
+                if let Some(query) = row_to_query(table_name, types, &row)? {
+                    self.batch.push(query);
+                    self.summary.rows += 1;
+                }
+                if self.batch.len() >= IMPORT_BATCH_LEN {
+                    self.flush().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), ImportError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(IMPORT_BATCH_LEN));
+        self.db_client
+            .query(batch)
+            .await
+            .map(|_| ())
+            .map_err(|e| ImportError::Database(e.to_string()))
+    }
+
+    async fn finish(&mut self) -> Result<ImportSummary, ImportError> {
+        if !self.manifest_seen {
+            return Err(ImportError::InvalidArchive("archive is empty".to_string()));
+        }
+        self.flush().await?;
+        Ok(std::mem::take(&mut self.summary))
+    }
+
+    /// Remove the experiment that was being imported when the import failed, so importing the
+    /// archive again does not skip it as a duplicate. Rows of the shared tables are left, they are
+    /// overwritten by the next import
+    async fn abort(&mut self) {
+        let Some(id) = self.experiment.take() else {
+            return;
+        };
+        warn!("Removing partially imported experiment {}", id);
+        if let Err(err) = delete_table(&self.client, &table_name_for(id)).await {
+            error!(
+                "Unable to remove partially imported experiment {}: {}",
+                id, err
+            );
+        }
+    }
+}
+
+/// Convert an archived row back into a write query, using the column types of its table
+/// Tags are stored as dictionary encoded strings. Returns None for rows without fields, which
+/// InfluxDB would reject
+fn row_to_query(
+    table_name: &str,
+    types: &HashMap<String, String>,
+    row: &Value,
+) -> Result<Option<WriteQuery>, ImportError> {
+    let invalid = |message: String| ImportError::InvalidArchive(message);
+
+    let row = row
+        .as_object()
+        .ok_or_else(|| invalid("row is not an object".to_string()))?;
+    let time = row
+        .get("time")
+        .and_then(|t| t.as_str())
+        .and_then(parse_influx_time)
+        .and_then(|t| t.timestamp_nanos_opt())
+        .and_then(|nanos| u128::try_from(nanos).ok())
+        .ok_or_else(|| invalid("row without a valid time".to_string()))?;
+
+    let mut query = WriteQuery::new(Timestamp::Nanoseconds(time), table_name);
+    let mut has_fields = false;
+
+    for (column, value) in row {
+        if column == "time" || value.is_null() {
+            continue;
+        }
+        let data_type = types.get(column).map(String::as_str).unwrap_or("");
+
+        if data_type.starts_with("Dictionary") {
+            let tag = value.as_str().map(str::to_string);
+            let tag = tag.unwrap_or_else(|| value.to_string());
+            query = query.add_tag(column.as_str(), tag);
+            continue;
+        }
+
+        // Keep the stored type, so the recreated table matches the original
+        let field: Option<Type> = match data_type {
+            "Float64" | "Float32" => value.as_f64().map(Type::from),
+            "Int64" | "Int32" => value.as_i64().map(Type::from),
+            "UInt64" | "UInt32" => value.as_u64().map(Type::from),
+            "Boolean" => value.as_bool().map(Type::from),
+            _ => Some(Type::from(
+                value
+                    .as_str()
+                    .map_or_else(|| value.to_string(), str::to_string),
+            )),
+        };
+        let field = field
+            .ok_or_else(|| invalid(format!("value of '{}' is not a {}", column, data_type)))?;
+        query = query.add_field(column.as_str(), field);
+        has_fields = true;
+    }
+
+    Ok(has_fields.then_some(query))
+}
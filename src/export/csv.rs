@@ -0,0 +1,117 @@
+use serde_json::Value;
+
+use crate::analysis::summary::ExperimentSummary;
+use crate::export::{AnnotationQueue, ExportInfo, RowEncoder};
+
+/// Incrementally converts InfluxDB rows to CSV
+/// The experiment summary is written first as `#` comment lines. Annotations are written to an
+/// extra `annotation` column, annotations after the last row are written to extra rows at the end
+pub struct CsvEncoder {
+    columns: Vec<String>,
+    annotations: AnnotationQueue,
+    summary: Option<ExperimentSummary>,
+    header_written: bool,
+    rows: usize,
+}
+
+impl CsvEncoder {
+    pub fn new(export: ExportInfo) -> Self {
+        Self {
+            columns: export.columns.into_iter().map(|c| c.name).collect(),
+            annotations: AnnotationQueue::new(export.annotations),
+            summary: export.summary,
+            header_written: false,
+            rows: 0,
+        }
+    }
+
+    fn write_header(&mut self, csv: &mut Vec<u8>) {
+        if self.header_written {
+            return;
+        }
+        if let Some(summary) = self.summary.take() {
+            for line in summary.report_lines() {
+                // A line break in a name or annotation would end the comment
+                let line = line.replace(['\r', '\n'], " ");
+                csv.extend_from_slice(format!("# {line}\n").as_bytes());
+            }
+        }
+        csv.extend_from_slice(self.columns.join(",").as_bytes());
+        csv.extend_from_slice(b",annotation\n");
+        self.header_written = true;
+    }
+
+    fn write_line(&self, fields: Vec<String>, markers: String, csv: &mut Vec<u8>) {
+        csv.extend_from_slice(fields.join(",").as_bytes());
+        csv.push(b',');
+        csv.extend_from_slice(value_to_csv_field(&Value::String(markers)).as_bytes());
+        csv.push(b'\n');
+    }
+}
+
+impl RowEncoder for CsvEncoder {
+    fn write_row(&mut self, record: &Value, csv: &mut Vec<u8>) -> Result<(), String> {
+        self.write_header(csv);
+
+        let time = record.get("time").and_then(|t| t.as_str()).unwrap_or("");
+        let markers = self.annotations.take_until(time);
+
+        let fields = self
+            .columns
+            .iter()
+            .map(|col| record.get(col).map(value_to_csv_field).unwrap_or_default())
+            .collect();
+
+        self.write_line(fields, markers, csv);
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Write the annotations that came after the last row
+    fn finish(&mut self, csv: &mut Vec<u8>) -> Result<(), String> {
+        self.write_header(csv);
+
+        while let Some((time, markers)) = self.annotations.take_next() {
+            let fields = self
+                .columns
+                .iter()
+                .map(|col| {
+                    if col == "time" {
+                        time.clone()
+                    } else {
+                        String::new()
+                    }
+                })
+                .collect();
+
+            self.write_line(fields, markers, csv);
+        }
+        Ok(())
+    }
+
+    fn rows(&self) -> usize {
+        self.rows
+    }
+}
+
+/// Convert a JSON value to a CSV field, handling quotes and special characters
+fn value_to_csv_field(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => {
+            // If the string contains comma, quote, or newline, wrap it in quotes
+            if s.contains(',') || s.contains('"') || s.contains('\n') {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            } else {
+                s.clone()
+            }
+        }
+        Value::Array(_) | Value::Object(_) => {
+            // For complex types, serialize as JSON string
+            let json_str = serde_json::to_string(value).unwrap_or_default();
+            format!("\"{}\"", json_str.replace('"', "\"\""))
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use serde_json::Value;
+
+use crate::analysis::summary::ExperimentSummary;
+use crate::export::{AnnotationQueue, ExportInfo, RowEncoder};
+
+/// Incrementally converts InfluxDB rows to newline delimited JSON, one object per row
+/// The first line holds the experiment summary as `{"summary": {...}}`. Keys follow the table
+/// schema, followed by an `annotation` key which is null for rows without annotations.
+/// Annotations after the last row are written to extra rows at the end
+pub struct JsonlEncoder {
+    columns: Vec<String>,
+    annotations: AnnotationQueue,
+    summary: Option<ExperimentSummary>,
+    rows: usize,
+}
+
+impl JsonlEncoder {
+    pub fn new(export: ExportInfo) -> Self {
+        Self {
+            columns: export.columns.into_iter().map(|c| c.name).collect(),
+            annotations: AnnotationQueue::new(export.annotations),
+            summary: export.summary,
+            rows: 0,
+        }
+    }
+
+    /// Write the summary line, once, before the first row
+    fn write_summary(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        if let Some(summary) = self.summary.take() {
+            serde_json::to_writer(&mut *out, &serde_json::json!({ "summary": summary }))
+                .map_err(|e| e.to_string())?;
+            out.push(b'\n');
+        }
+        Ok(())
+    }
+
+    /// Write a single object, keeping the keys in schema order
+    fn write_line(
+        &self,
+        value: impl Fn(&str) -> Value,
+        markers: String,
+        out: &mut Vec<u8>,
+    ) -> Result<(), String> {
+        let markers = if markers.is_empty() {
+            Value::Null
+        } else {
+            Value::String(markers)
+        };
+        let fields = self
+            .columns
+            .iter()
+            .map(|column| (column.as_str(), value(column)))
+            .chain(std::iter::once(("annotation", markers)));
+
+        out.push(b'{');
+        for (i, (key, value)) in fields.enumerate() {
+            if i > 0 {
+                out.push(b',');
+            }
+            serde_json::to_writer(&mut *out, key).map_err(|e| e.to_string())?;
+            out.push(b':');
+            serde_json::to_writer(&mut *out, &value).map_err(|e| e.to_string())?;
+        }
+        out.extend_from_slice(b"}\n");
+        Ok(())
+    }
+}
+
+impl RowEncoder for JsonlEncoder {
+    fn write_row(&mut self, record: &Value, out: &mut Vec<u8>) -> Result<(), String> {
+        self.write_summary(out)?;
+        let time = record.get("time").and_then(|t| t.as_str()).unwrap_or("");
+        let markers = self.annotations.take_until(time);
+
+        self.write_line(
+            |column| record.get(column).cloned().unwrap_or(Value::Null),
+            markers,
+            out,
+        )?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Write the annotations that came after the last row
+    fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        self.write_summary(out)?;
+        while let Some((time, markers)) = self.annotations.take_next() {
+            self.write_line(
+                |column| match column {
+                    "time" => Value::String(time.clone()),
+                    _ => Value::Null,
+                },
+                markers,
+                out,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn rows(&self) -> usize {
+        self.rows
+    }
+}
@@ -0,0 +1,470 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::database::experiment_data::{column_unit, parse_influx_time};
+use crate::experiment::listing::ExperimentFromDB;
+use crate::export::{ExportInfo, RowEncoder};
+use crate::http::messages::AnnotationFromDB;
+
+// MAT-file v5 data types and array classes, see the MATLAB "MAT-File Format" reference
+const MI_INT8: u32 = 1;
+const MI_UINT16: u32 = 4;
+const MI_INT32: u32 = 5;
+const MI_UINT32: u32 = 6;
+const MI_DOUBLE: u32 = 9;
+const MI_MATRIX: u32 = 14;
+const MX_CELL_CLASS: u32 = 1;
+const MX_STRUCT_CLASS: u32 = 2;
+const MX_CHAR_CLASS: u32 = 4;
+const MX_DOUBLE_CLASS: u32 = 6;
+
+/// Longest variable or field name accepted by MATLAB
+const MAX_NAME_LEN: usize = 63;
+/// Variables written next to the channels
+const METADATA_VARIABLES: [&str; 4] = ["summary", "experiment", "units", "annotations"];
+
+/// Converts InfluxDB rows to a MATLAB v5 .mat file
+/// The `summary` struct holding the experiment summary comes first. Every numeric column becomes a
+/// double column vector, with `time` in seconds since the start of the experiment. The
+/// `experiment`, `units` and `annotations` structs hold the metadata
+/// A .mat file starts with the size of every variable, so the channels are collected in memory
+/// and the file is only written once the last row came in
+pub struct MatEncoder {
+    channels: Vec<Channel>,
+    experiment: ExperimentFromDB,
+    annotations: Vec<AnnotationFromDB>,
+    summary: Option<Value>,
+    start_time: Option<DateTime<Utc>>,
+    rows: usize,
+}
+
+struct Channel {
+    column: String,
+    /// Sanitized MATLAB variable name
+    name: String,
+    is_time: bool,
+    values: Vec<f64>,
+}
+
+impl MatEncoder {
+    pub fn new(export: ExportInfo) -> Self {
+        // Tags and other strings are repeated in the experiment metadata instead
+        let columns: Vec<_> = export
+            .columns
+            .into_iter()
+            .filter(|c| {
+                c.data_type.starts_with("Timestamp")
+                    || c.data_type.starts_with("Float")
+                    || c.data_type.starts_with("Int")
+                    || c.data_type.starts_with("UInt")
+                    || c.data_type == "Boolean"
+            })
+            .collect();
+        let names =
+            unique_matlab_names(columns.iter().map(|c| c.name.as_str()), &METADATA_VARIABLES);
+        let channels = columns
+            .into_iter()
+            .zip(names)
+            .map(|(c, name)| Channel {
+                name,
+                is_time: c.data_type.starts_with("Timestamp"),
+                column: c.name,
+                values: Vec::new(),
+            })
+            .collect();
+
+        let start_time = export
+            .experiment
+            .start_time
+            .as_deref()
+            .and_then(parse_influx_time);
+
+        Self {
+            channels,
+            experiment: export.experiment,
+            annotations: export.annotations,
+            summary: export
+                .summary
+                .and_then(|summary| serde_json::to_value(summary).ok()),
+            start_time,
+            rows: 0,
+        }
+    }
+
+    /// Seconds between the start of the experiment and an InfluxDB timestamp
+    fn seconds_since_start(&self, time: &str) -> f64 {
+        match (self.start_time, parse_influx_time(time)) {
+            (Some(start), Some(time)) => {
+                (time - start).num_nanoseconds().unwrap_or_default() as f64 / 1e9
+            }
+            _ => f64::NAN,
+        }
+    }
+
+    fn experiment_struct(&self) -> MatValue {
+        let text = |s: &str| MatValue::Char(s.to_string());
+        MatValue::Struct(vec![
+            ("id".to_string(), text(&self.experiment.experiment_id)),
+            ("name".to_string(), text(&self.experiment.experiment_name)),
+            (
+                "description".to_string(),
+                text(&self.experiment.description),
+            ),
+            ("table_name".to_string(), text(&self.experiment.table_name)),
+            (
+                "start_time".to_string(),
+                text(self.experiment.start_time.as_deref().unwrap_or("")),
+            ),
+            (
+                "duration_seconds".to_string(),
+                MatValue::Double(vec![self.experiment.duration_seconds]),
+            ),
+        ])
+    }
+
+    fn units_struct(&self) -> MatValue {
+        MatValue::Struct(
+            self.channels
+                .iter()
+                .map(|channel| {
+                    let unit = if channel.is_time {
+                        Some("s")
+                    } else {
+                        column_unit(&channel.column)
+                    };
+                    (
+                        channel.name.clone(),
+                        MatValue::Char(unit.unwrap_or("").to_string()),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn annotations_struct(&self) -> MatValue {
+        let text = |f: fn(&AnnotationFromDB) -> &String| {
+            MatValue::Cell(
+                self.annotations
+                    .iter()
+                    .map(|a| MatValue::Char(f(a).clone()))
+                    .collect(),
+            )
+        };
+        MatValue::Struct(vec![
+            (
+                "time".to_string(),
+                MatValue::Double(
+                    self.annotations
+                        .iter()
+                        .map(|a| self.seconds_since_start(&a.time))
+                        .collect(),
+                ),
+            ),
+            ("category".to_string(), text(|a| &a.category)),
+            ("text".to_string(), text(|a| &a.text)),
+        ])
+    }
+}
+
+impl RowEncoder for MatEncoder {
+    fn write_row(&mut self, record: &Value, _out: &mut Vec<u8>) -> Result<(), String> {
+        let time = record.get("time").and_then(|t| t.as_str()).unwrap_or("");
+        let seconds = self.seconds_since_start(time);
+
+        for channel in self.channels.iter_mut() {
+            let value = match record.get(&channel.column) {
+                _ if channel.is_time => seconds,
+                Some(Value::Number(n)) => n.as_f64().unwrap_or(f64::NAN),
+                Some(Value::Bool(b)) => f64::from(u8::from(*b)),
+                _ => f64::NAN,
+            };
+            channel.values.push(value);
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        write_header(out);
+
+        if let Some(summary) = self.summary.take() {
+            write_matrix(out, "summary", &json_to_mat(&summary));
+        }
+        for channel in self.channels.iter_mut() {
+            let values = std::mem::take(&mut channel.values);
+            write_matrix(out, &channel.name, &MatValue::Double(values));
+        }
+        write_matrix(out, "experiment", &self.experiment_struct());
+        write_matrix(out, "units", &self.units_struct());
+        write_matrix(out, "annotations", &self.annotations_struct());
+        Ok(())
+    }
+
+    fn rows(&self) -> usize {
+        self.rows
+    }
+}
+
+/// Subset of MATLAB arrays needed for an experiment export
+enum MatValue {
+    /// Column vector of doubles
+    Double(Vec<f64>),
+    /// Character row vector
+    Char(String),
+    /// Column cell array
+    Cell(Vec<MatValue>),
+    /// Scalar struct with the given fields
+    Struct(Vec<(String, MatValue)>),
+}
+
+/// Convert a json value to the closest MATLAB array: objects become structs, arrays of numbers
+/// double vectors and other arrays cells. Booleans become 0 or 1 and null an empty double
+fn json_to_mat(value: &Value) -> MatValue {
+    match value {
+        Value::Null => MatValue::Double(Vec::new()),
+        Value::Bool(b) => MatValue::Double(vec![f64::from(u8::from(*b))]),
+        Value::Number(n) => MatValue::Double(vec![n.as_f64().unwrap_or(f64::NAN)]),
+        Value::String(text) => MatValue::Char(text.clone()),
+        Value::Array(items) if !items.is_empty() && items.iter().all(Value::is_number) => {
+            MatValue::Double(items.iter().filter_map(Value::as_f64).collect())
+        }
+        Value::Array(items) => MatValue::Cell(items.iter().map(json_to_mat).collect()),
+        Value::Object(fields) => MatValue::Struct(
+            fields
+                .iter()
+                .map(|(name, field)| (name.clone(), json_to_mat(field)))
+                .collect(),
+        ),
+    }
+}
+
+/// Write the 128 byte MAT-file header
+fn write_header(out: &mut Vec<u8>) {
+    let mut text = format!(
+        "MATLAB 5.0 MAT-file, Platform: loop_sense, Created on: {}",
+        Utc::now().format("%a %b %e %H:%M:%S %Y")
+    )
+    .into_bytes();
+    text.resize(116, b' ');
+
+    out.extend_from_slice(&text);
+    // No subsystem data
+    out.extend_from_slice(&[0; 8]);
+    // Version, followed by the endian indicator which reads "IM" in a little endian file
+    out.extend_from_slice(&0x0100u16.to_le_bytes());
+    out.extend_from_slice(b"IM");
+}
+
+/// Write a data element: its type and size, followed by the data padded to 8 bytes
+/// Data of 1 to 4 bytes is written in the small data element format, with the size and type
+/// packed in the first 4 bytes and the data in the next 4, as MATLAB does and requires for the
+/// field name length of a struct
+fn write_element(out: &mut Vec<u8>, data_type: u32, data: &[u8]) {
+    if (1..=4).contains(&data.len()) {
+        out.extend_from_slice(&((data.len() as u32) << 16 | data_type).to_le_bytes());
+        out.extend_from_slice(data);
+        out.resize(out.len() + 4 - data.len(), 0);
+        return;
+    }
+    out.extend_from_slice(&data_type.to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    out.resize(out.len() + (8 - data.len() % 8) % 8, 0);
+}
+
+/// Write a named array, nested arrays in cells and structs have an empty name
+fn write_matrix(out: &mut Vec<u8>, name: &str, value: &MatValue) {
+    let (class, rows, columns) = match value {
+        MatValue::Double(values) => (MX_DOUBLE_CLASS, values.len(), 1),
+        MatValue::Char(text) => (MX_CHAR_CLASS, 1, text.encode_utf16().count()),
+        MatValue::Cell(items) => (MX_CELL_CLASS, items.len(), 1),
+        MatValue::Struct(_) => (MX_STRUCT_CLASS, 1, 1),
+    };
+
+    let mut body = Vec::new();
+    write_element(&mut body, MI_UINT32, &le_bytes([class, 0]));
+    write_element(
+        &mut body,
+        MI_INT32,
+        &le_bytes([rows as u32, columns as u32]),
+    );
+    write_element(&mut body, MI_INT8, name.as_bytes());
+
+    match value {
+        MatValue::Double(values) => {
+            let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            write_element(&mut body, MI_DOUBLE, &data);
+        }
+        MatValue::Char(text) => {
+            let data: Vec<u8> = text.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+            write_element(&mut body, MI_UINT16, &data);
+        }
+        MatValue::Cell(items) => {
+            for item in items {
+                write_matrix(&mut body, "", item);
+            }
+        }
+        MatValue::Struct(fields) => {
+            // Field names are stored as fixed length, null terminated strings
+            let names = unique_matlab_names(fields.iter().map(|(name, _)| name.as_str()), &[]);
+            let name_len = names.iter().map(String::len).max().unwrap_or(0) + 1;
+            write_element(&mut body, MI_INT32, &(name_len as u32).to_le_bytes());
+
+            let mut data = Vec::with_capacity(name_len * names.len());
+            for name in &names {
+                data.extend_from_slice(name.as_bytes());
+                data.resize(data.len() + name_len - name.len(), 0);
+            }
+            write_element(&mut body, MI_INT8, &data);
+
+            for (_, field) in fields {
+                write_matrix(&mut body, "", field);
+            }
+        }
+    }
+
+    write_element(out, MI_MATRIX, &body);
+}
+
+fn le_bytes<const N: usize>(values: [u32; N]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// Turn a column name into a valid MATLAB identifier: ascii alphanumerics and underscores,
+/// starting with a letter and at most `MAX_NAME_LEN` characters long
+fn matlab_name(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name.insert(0, 'x');
+    }
+    name.truncate(MAX_NAME_LEN);
+    name
+}
+
+/// Turn names into distinct MATLAB identifiers. A name that would repeat an earlier one or one of
+/// the `reserved` names gets a `_2`, `_3`, ... suffix
+fn unique_matlab_names<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    reserved: &[&str],
+) -> Vec<String> {
+    let mut taken: HashSet<String> = reserved.iter().map(|name| name.to_string()).collect();
+    names
+        .into_iter()
+        .map(|name| {
+            let base = matlab_name(name);
+            let mut unique = base.clone();
+            let mut count = 2;
+            while taken.contains(&unique) {
+                let suffix = format!("_{count}");
+                // Identifiers are ascii, so any length is a char boundary
+                unique = format!(
+                    "{}{suffix}",
+                    &base[..base.len().min(MAX_NAME_LEN - suffix.len())]
+                );
+                count += 1;
+            }
+            taken.insert(unique.clone());
+            unique
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `s = struct('a', 1, 'c', {{'hi'}})` as laid out by the MAT-file v5 format reference,
+    /// following the 116 byte descriptive text of the header
+    #[rustfmt::skip]
+    const STRUCT_FILE: &[u8] = &[
+        // Header: no subsystem data, version 0x0100, endian indicator "IM"
+        0, 0, 0, 0, 0, 0, 0, 0,
+        0x00, 0x01, b'I', b'M',
+        // miMATRIX of 224 bytes
+        14, 0, 0, 0, 224, 0, 0, 0,
+        // Array flags: mxSTRUCT_CLASS
+        6, 0, 0, 0, 8, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0,
+        // Dimensions 1x1
+        5, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0,
+        // Array name "s", small data element
+        1, 0, 1, 0, b's', 0, 0, 0,
+        // Field name length 2, always a small data element
+        5, 0, 4, 0, 2, 0, 0, 0,
+        // Field names "a\0c\0", small data element
+        1, 0, 4, 0, b'a', 0, b'c', 0,
+        // Field a: miMATRIX of 56 bytes
+        14, 0, 0, 0, 56, 0, 0, 0,
+        6, 0, 0, 0, 8, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0,
+        5, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0,
+        // Empty array name
+        1, 0, 0, 0, 0, 0, 0, 0,
+        // miDOUBLE 1.0
+        9, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f,
+        // Field c: miMATRIX of 96 bytes, a 1x1 mxCELL_CLASS
+        14, 0, 0, 0, 96, 0, 0, 0,
+        6, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        5, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0,
+        1, 0, 0, 0, 0, 0, 0, 0,
+        // Cell item: miMATRIX of 48 bytes, a 1x2 mxCHAR_CLASS
+        14, 0, 0, 0, 48, 0, 0, 0,
+        6, 0, 0, 0, 8, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0,
+        5, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0,
+        1, 0, 0, 0, 0, 0, 0, 0,
+        // miUINT16 "hi", small data element
+        4, 0, 4, 0, b'h', 0, b'i', 0,
+    ];
+
+    #[test]
+    fn writes_struct_cell_and_double() {
+        let mut out = Vec::new();
+        write_header(&mut out);
+        let value = MatValue::Struct(vec![
+            ("a".to_string(), MatValue::Double(vec![1.0])),
+            (
+                "c".to_string(),
+                MatValue::Cell(vec![MatValue::Char("hi".to_string())]),
+            ),
+        ]);
+        write_matrix(&mut out, "s", &value);
+
+        assert!(out.starts_with(b"MATLAB 5.0 MAT-file"));
+        assert_eq!(&out[116..], STRUCT_FILE);
+    }
+
+    #[test]
+    fn pads_elements_to_8_bytes() {
+        let mut out = Vec::new();
+        write_element(&mut out, MI_INT8, b"hello");
+        assert_eq!(
+            out,
+            [
+                1, 0, 0, 0, 5, 0, 0, 0, b'h', b'e', b'l', b'l', b'o', 0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn makes_names_valid_identifiers() {
+        assert_eq!(matlab_name("heart_rate"), "heart_rate");
+        assert_eq!(matlab_name("flow-rate (l/min)"), "flow_rate__l_min_");
+        assert_eq!(matlab_name("2nd"), "x2nd");
+        assert_eq!(matlab_name(&"a".repeat(100)).len(), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn deduplicates_names() {
+        let long = "b".repeat(100);
+        let names = unique_matlab_names(
+            ["a-b", "a_b", "a_b", "units", long.as_str(), long.as_str()],
+            &METADATA_VARIABLES,
+        );
+        assert_eq!(names[..4], ["a_b", "a_b_2", "a_b_3", "units_2"]);
+        assert_eq!(names[4], "b".repeat(MAX_NAME_LEN));
+        assert_eq!(names[5], format!("{}_2", "b".repeat(MAX_NAME_LEN - 2)));
+    }
+}
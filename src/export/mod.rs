@@ -0,0 +1,147 @@
+pub mod archive;
+pub mod csv;
+pub mod jsonl;
+pub mod mat;
+pub mod parquet;
+
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::analysis::summary::ExperimentSummary;
+use crate::database::query::TableColumn;
+use crate::experiment::listing::ExperimentFromDB;
+use crate::http::messages::AnnotationFromDB;
+
+/// Reasons an experiment can not be exported or archived
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("experiment {0} not found")]
+    NotFound(String),
+    #[error("experiment {0} holds no data")]
+    NoData(String),
+    #[error("unable to encode the export: {0}")]
+    Encoding(String),
+    #[error("{0}")]
+    Database(String),
+}
+
+impl From<String> for ExportError {
+    fn from(err: String) -> Self {
+        ExportError::Database(err)
+    }
+}
+
+/// File formats an experiment table can be downloaded in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Jsonl,
+    Parquet,
+    Mat,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Mat => "mat",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+            ExportFormat::Mat => "application/x-matlab-data",
+        }
+    }
+
+    /// Create an encoder converting the rows of an experiment table to this format
+    pub fn encoder(&self, export: ExportInfo) -> Result<Box<dyn RowEncoder>, String> {
+        Ok(match self {
+            ExportFormat::Csv => Box::new(csv::CsvEncoder::new(export)),
+            ExportFormat::Jsonl => Box::new(jsonl::JsonlEncoder::new(export)),
+            ExportFormat::Parquet => Box::new(parquet::ParquetEncoder::new(export)?),
+            ExportFormat::Mat => Box::new(mat::MatEncoder::new(export)),
+        })
+    }
+}
+
+/// Everything known about an exported experiment table, apart from its rows
+pub struct ExportInfo {
+    pub columns: Vec<TableColumn>,
+    pub experiment: ExperimentFromDB,
+    pub annotations: Vec<AnnotationFromDB>,
+    /// Written before anything else, so it is the first thing a reviewer reads
+    pub summary: Option<ExperimentSummary>,
+}
+
+/// Converts the rows of an experiment table into an export file, chunk by chunk
+pub trait RowEncoder: Send {
+    /// Encode a row, appending any output to `chunk`
+    fn write_row(&mut self, row: &Value, chunk: &mut Vec<u8>) -> Result<(), String>;
+
+    /// Complete the file after the last row, appending the remaining output to `chunk`
+    fn finish(&mut self, chunk: &mut Vec<u8>) -> Result<(), String>;
+
+    /// Number of rows encoded so far
+    fn rows(&self) -> usize;
+}
+
+/// Annotations of an exported experiment, handed out in time order as the rows go by
+/// Every annotation is attached to the first row at or after its time. Annotations after the
+/// last row are handed out by `take_next`
+pub struct AnnotationQueue {
+    annotations: VecDeque<AnnotationFromDB>,
+}
+
+impl AnnotationQueue {
+    pub fn new(annotations: Vec<AnnotationFromDB>) -> Self {
+        Self {
+            annotations: annotations.into(),
+        }
+    }
+
+    /// Remove the annotations at or before `time` and format them as a single field
+    pub fn take_until(&mut self, time: &str) -> String {
+        self.take_while(|a| a.time.as_str() <= time)
+    }
+
+    /// Remove the annotations sharing the time of the first remaining annotation
+    /// Returns that time and the annotations formatted as a single field
+    pub fn take_next(&mut self) -> Option<(String, String)> {
+        let time = self.annotations.front()?.time.clone();
+        let markers = self.take_while(|a| a.time == time);
+        Some((time, markers))
+    }
+
+    fn take_while(&mut self, attach: impl Fn(&AnnotationFromDB) -> bool) -> String {
+        let mut markers = Vec::new();
+        while let Some(annotation) = self.annotations.pop_front() {
+            if !attach(&annotation) {
+                self.annotations.push_front(annotation);
+                break;
+            }
+            markers.push(format_annotation(&annotation));
+        }
+        markers.join("; ")
+    }
+}
+
+/// Format an annotation as "category: text", or just the text if it has no category
+pub fn format_annotation(annotation: &AnnotationFromDB) -> String {
+    if annotation.category.is_empty() {
+        annotation.text.clone()
+    } else {
+        format!("{}: {}", annotation.category, annotation.text)
+    }
+}
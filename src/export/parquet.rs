@@ -0,0 +1,302 @@
+use std::sync::Arc;
+
+use parquet::basic::{Compression, LogicalType, Repetition, TimeUnit, Type as PhysicalType};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::format::{KeyValue, NanoSeconds};
+use parquet::schema::types::Type;
+use serde_json::Value;
+
+use crate::database::experiment_data::{column_unit, parse_influx_time};
+use crate::export::{AnnotationQueue, ExportInfo, RowEncoder};
+
+/// Number of rows buffered before they are written out as a parquet row group
+const ROW_GROUP_ROWS: usize = 10_000;
+
+/// Incrementally converts InfluxDB rows to a Parquet file with typed columns
+/// Rows are buffered per column and written out one row group at a time, so only a single row
+/// group is held in memory. Units, experiment metadata, annotations and the experiment summary are
+/// stored in the file metadata, annotations are also written to an extra `annotation` column
+pub struct ParquetEncoder {
+    writer: Option<SerializedFileWriter<Vec<u8>>>,
+    columns: Vec<ColumnBuffer>,
+    annotations: AnnotationQueue,
+    buffered: usize,
+    rows: usize,
+}
+
+impl ParquetEncoder {
+    pub fn new(export: ExportInfo) -> Result<Self, String> {
+        let mut columns: Vec<ColumnBuffer> = export
+            .columns
+            .iter()
+            .map(|c| ColumnBuffer::new(&c.name, ColumnValues::for_data_type(&c.data_type)))
+            .collect();
+        columns.push(ColumnBuffer::new(
+            "annotation",
+            ColumnValues::Utf8(Vec::new()),
+        ));
+
+        let fields = columns
+            .iter()
+            .map(|c| c.parquet_type().map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        let schema = Type::group_type_builder("schema")
+            .with_fields(fields)
+            .build()
+            .map_err(|e| format!("Failed to build parquet schema: {}", e))?;
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut writer =
+            SerializedFileWriter::new(Vec::new(), Arc::new(schema), Arc::new(properties))
+                .map_err(|e| format!("Failed to create parquet writer: {}", e))?;
+
+        // Units are only known for the columns written by this service
+        let units: serde_json::Map<String, Value> = export
+            .columns
+            .iter()
+            .filter_map(|c| Some((c.name.clone(), column_unit(&c.name)?.into())))
+            .collect();
+        let metadata = [
+            ("units", serde_json::to_string(&units)),
+            ("experiment", serde_json::to_string(&export.experiment)),
+            ("annotations", serde_json::to_string(&export.annotations)),
+            ("summary", serde_json::to_string(&export.summary)),
+        ];
+        for (key, value) in metadata {
+            let value = value.map_err(|e| format!("Failed to serialize {}: {}", key, e))?;
+            writer.append_key_value_metadata(KeyValue::new(key.to_string(), value));
+        }
+
+        Ok(Self {
+            writer: Some(writer),
+            columns,
+            annotations: AnnotationQueue::new(export.annotations),
+            buffered: 0,
+            rows: 0,
+        })
+    }
+
+    /// Write the buffered rows as a row group and hand out the bytes written so far
+    fn flush_row_group(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| "Parquet file already finished".to_string())?;
+
+        if self.buffered > 0 {
+            let mut row_group = writer
+                .next_row_group()
+                .map_err(|e| format!("Failed to start parquet row group: {}", e))?;
+            for column in self.columns.iter_mut() {
+                let mut column_writer = row_group
+                    .next_column()
+                    .map_err(|e| format!("Failed to start parquet column: {}", e))?
+                    .ok_or_else(|| format!("Parquet column '{}' missing", column.name))?;
+                column.write_to(&mut column_writer)?;
+                column_writer
+                    .close()
+                    .map_err(|e| format!("Failed to close parquet column: {}", e))?;
+            }
+            row_group
+                .close()
+                .map_err(|e| format!("Failed to close parquet row group: {}", e))?;
+            self.buffered = 0;
+        }
+
+        out.append(writer.inner_mut());
+        Ok(())
+    }
+}
+
+impl RowEncoder for ParquetEncoder {
+    fn write_row(&mut self, record: &Value, out: &mut Vec<u8>) -> Result<(), String> {
+        let time = record.get("time").and_then(|t| t.as_str()).unwrap_or("");
+        let markers = self.annotations.take_until(time);
+
+        for column in self.columns.iter_mut() {
+            if column.name == "annotation" {
+                column.push_str((!markers.is_empty()).then_some(markers.as_str()));
+            } else {
+                column.push(record.get(&column.name));
+            }
+        }
+        self.buffered += 1;
+        self.rows += 1;
+
+        if self.buffered >= ROW_GROUP_ROWS {
+            self.flush_row_group(out)?;
+        }
+        Ok(())
+    }
+
+    /// Write the annotations that came after the last row, then the file footer
+    fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), String> {
+        while let Some((time, markers)) = self.annotations.take_next() {
+            for column in self.columns.iter_mut() {
+                match column.name.as_str() {
+                    "time" => column.push(Some(&Value::String(time.clone()))),
+                    "annotation" => column.push_str(Some(&markers)),
+                    _ => column.push(None),
+                }
+            }
+            self.buffered += 1;
+        }
+
+        self.flush_row_group(out)?;
+        let writer = self
+            .writer
+            .take()
+            .ok_or_else(|| "Parquet file already finished".to_string())?;
+        let mut rest = writer
+            .into_inner()
+            .map_err(|e| format!("Failed to write parquet footer: {}", e))?;
+        out.append(&mut rest);
+        Ok(())
+    }
+
+    fn rows(&self) -> usize {
+        self.rows
+    }
+}
+
+/// Values of a single column buffered for the next row group
+/// Nulls are left out of the values and marked by a definition level of 0
+struct ColumnBuffer {
+    name: String,
+    values: ColumnValues,
+    definition_levels: Vec<i16>,
+}
+
+enum ColumnValues {
+    Timestamp(Vec<i64>),
+    Double(Vec<f64>),
+    Int64(Vec<i64>),
+    Boolean(Vec<bool>),
+    Utf8(Vec<ByteArray>),
+}
+
+impl ColumnValues {
+    /// Pick the parquet column type for an Arrow data type reported by InfluxDB
+    fn for_data_type(data_type: &str) -> Self {
+        match data_type {
+            t if t.starts_with("Timestamp") => ColumnValues::Timestamp(Vec::new()),
+            "Float64" | "Float32" => ColumnValues::Double(Vec::new()),
+            "Int64" | "Int32" | "UInt64" | "UInt32" => ColumnValues::Int64(Vec::new()),
+            "Boolean" => ColumnValues::Boolean(Vec::new()),
+            // Strings and tags, which are dictionary encoded strings
+            _ => ColumnValues::Utf8(Vec::new()),
+        }
+    }
+}
+
+impl ColumnBuffer {
+    fn new(name: &str, values: ColumnValues) -> Self {
+        Self {
+            name: name.to_string(),
+            values,
+            definition_levels: Vec::new(),
+        }
+    }
+
+    fn parquet_type(&self) -> Result<Type, String> {
+        let (physical, logical) = match self.values {
+            ColumnValues::Timestamp(_) => (
+                PhysicalType::INT64,
+                Some(LogicalType::Timestamp {
+                    is_adjusted_to_u_t_c: true,
+                    unit: TimeUnit::NANOS(NanoSeconds::new()),
+                }),
+            ),
+            ColumnValues::Double(_) => (PhysicalType::DOUBLE, None),
+            ColumnValues::Int64(_) => (PhysicalType::INT64, None),
+            ColumnValues::Boolean(_) => (PhysicalType::BOOLEAN, None),
+            ColumnValues::Utf8(_) => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+        };
+
+        Type::primitive_type_builder(&self.name, physical)
+            .with_repetition(Repetition::OPTIONAL)
+            .with_logical_type(logical)
+            .build()
+            .map_err(|e| format!("Invalid parquet column '{}': {}", self.name, e))
+    }
+
+    /// Buffer a JSON value, values not matching the column type are stored as null
+    fn push(&mut self, value: Option<&Value>) {
+        let present = match (&mut self.values, value) {
+            (ColumnValues::Timestamp(values), Some(Value::String(time))) => {
+                match parse_influx_time(time).and_then(|t| t.timestamp_nanos_opt()) {
+                    Some(nanos) => {
+                        values.push(nanos);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            (ColumnValues::Double(values), Some(Value::Number(n))) => match n.as_f64() {
+                Some(v) => {
+                    values.push(v);
+                    true
+                }
+                None => false,
+            },
+            (ColumnValues::Int64(values), Some(Value::Number(n))) => match n.as_i64() {
+                Some(v) => {
+                    values.push(v);
+                    true
+                }
+                None => false,
+            },
+            (ColumnValues::Boolean(values), Some(Value::Bool(b))) => {
+                values.push(*b);
+                true
+            }
+            (ColumnValues::Utf8(values), Some(Value::String(s))) => {
+                values.push(ByteArray::from(s.as_bytes().to_vec()));
+                true
+            }
+            (ColumnValues::Utf8(values), Some(v)) if !v.is_null() => {
+                values.push(ByteArray::from(v.to_string().into_bytes()));
+                true
+            }
+            _ => false,
+        };
+        self.definition_levels.push(i16::from(present));
+    }
+
+    fn push_str(&mut self, value: Option<&str>) {
+        self.push(value.map(|s| Value::String(s.to_string())).as_ref());
+    }
+
+    /// Write the buffered values to a row group column and clear the buffer
+    fn write_to(
+        &mut self,
+        writer: &mut parquet::file::writer::SerializedColumnWriter<'_>,
+    ) -> Result<(), String> {
+        let levels = Some(self.definition_levels.as_slice());
+        let result = match &mut self.values {
+            ColumnValues::Timestamp(values) | ColumnValues::Int64(values) => writer
+                .typed::<Int64Type>()
+                .write_batch(values, levels, None)
+                .map(|_| values.clear()),
+            ColumnValues::Double(values) => writer
+                .typed::<DoubleType>()
+                .write_batch(values, levels, None)
+                .map(|_| values.clear()),
+            ColumnValues::Boolean(values) => writer
+                .typed::<BoolType>()
+                .write_batch(values, levels, None)
+                .map(|_| values.clear()),
+            ColumnValues::Utf8(values) => writer
+                .typed::<ByteArrayType>()
+                .write_batch(values, levels, None)
+                .map(|_| values.clear()),
+        };
+        self.definition_levels.clear();
+
+        result.map_err(|e| format!("Failed to write parquet column '{}': {}", self.name, e))
+    }
+}
@@ -0,0 +1,302 @@
+use axum::Json;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode, header};
+use serde::{Deserialize, Serialize};
+use tracing::*;
+use utoipa::ToSchema;
+
+use crate::auth::{Role, User, UserInfo, UserStore};
+use crate::axumstate::AxumState;
+use crate::http::error::{ApiError, ApiErrorBody, ApiJson, ApiPath};
+
+/// Any authenticated user
+pub struct Viewer(pub User);
+
+/// Any authenticated user opening a websocket, with the token it authenticated with
+/// Browsers cannot set headers on websockets, so the token may also be sent as query parameter.
+/// The connection outlives the request, so the token is checked again as it may be revoked
+pub struct ViewerSession {
+    pub user: User,
+    pub token: String,
+}
+
+/// Authenticated user allowed to drive the rig and record experiments
+pub struct Operator(pub User);
+
+/// Authenticated user allowed to manage users
+pub struct Admin(pub User);
+
+/// Operator holding the control lease, the only client allowed to change the setpoints and
+/// run experiments
+pub struct Controller(pub User);
+
+/// Header carrying the token of the control lease
+pub const LEASE_HEADER: &str = "x-control-lease";
+
+/// Control lease token sent with the request, if any
+pub fn lease_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(LEASE_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Check that the request carries the token of the current control lease, extending the lease
+pub fn check_control_lease(state: &AxumState, headers: &HeaderMap) -> Result<(), ApiError> {
+    match state.control_lease.lock() {
+        Ok(mut lease) => Ok(lease.check(lease_token(headers))?),
+        Err(_) => Err(ApiError::StateUnavailable("control lease")),
+    }
+}
+
+/// Token sent as query parameter, only accepted on the websocket upgrade as a token in a URL
+/// ends up in access logs and browser history
+#[derive(Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// Token sent as `Authorization: Bearer` header
+fn request_token(parts: &Parts) -> Option<String> {
+    let value = parts.headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+/// Token sent as `Authorization: Bearer` header or `access_token` query parameter
+fn websocket_token(parts: &Parts) -> Option<String> {
+    request_token(parts).or_else(|| {
+        Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|Query(query)| query.access_token)
+    })
+}
+
+/// Look up the user sending the request, and check that it has at least `role`
+fn authenticate(parts: &Parts, state: &AxumState, role: Role) -> Result<User, ApiError> {
+    authenticate_with(parts, state, request_token(parts), role)
+}
+
+/// Look up the user `token` of a request belongs to, and check that it has at least `role`
+fn authenticate_with(
+    parts: &Parts,
+    state: &AxumState,
+    token: Option<String>,
+    role: Role,
+) -> Result<User, ApiError> {
+    let Some(token) = token else {
+        return Err(ApiError::Unauthorized(String::from("a token is required")));
+    };
+    authenticate_token(state, &token, role).inspect_err(|err| {
+        if let ApiError::Unauthorized(_) = err {
+            warn!(
+                "Rejecting request to {} with an unknown token",
+                parts.uri.path()
+            );
+        }
+    })
+}
+
+/// Look up the user `token` belongs to, and check that it has at least `role`
+pub fn authenticate_token(state: &AxumState, token: &str, role: Role) -> Result<User, ApiError> {
+    let user = match state.users.lock() {
+        Ok(users) => users.authenticate(token),
+        Err(_) => return Err(ApiError::StateUnavailable("user store")),
+    };
+    let Some(user) = user else {
+        return Err(ApiError::Unauthorized(String::from("unknown token")));
+    };
+    user.require(role)?;
+    Ok(user)
+}
+
+impl FromRequestParts<AxumState> for Viewer {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AxumState) -> Result<Self, ApiError> {
+        authenticate(parts, state, Role::Viewer).map(Viewer)
+    }
+}
+
+impl FromRequestParts<AxumState> for ViewerSession {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AxumState) -> Result<Self, ApiError> {
+        let token = websocket_token(parts);
+        let user = authenticate_with(parts, state, token.clone(), Role::Viewer)?;
+        Ok(ViewerSession {
+            user,
+            token: token.unwrap_or_default(),
+        })
+    }
+}
+
+impl FromRequestParts<AxumState> for Operator {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AxumState) -> Result<Self, ApiError> {
+        authenticate(parts, state, Role::Operator).map(Operator)
+    }
+}
+
+impl FromRequestParts<AxumState> for Admin {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AxumState) -> Result<Self, ApiError> {
+        authenticate(parts, state, Role::Admin).map(Admin)
+    }
+}
+
+impl FromRequestParts<AxumState> for Controller {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AxumState) -> Result<Self, ApiError> {
+        let user = authenticate(parts, state, Role::Operator)?;
+        check_control_lease(state, &parts.headers)?;
+        Ok(Controller(user))
+    }
+}
+
+/// User to create
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct NewUser {
+    /// 1-32 letters, digits, '-', '_' or '.'
+    pub name: String,
+    pub role: Role,
+}
+
+/// Created user, with the token it authenticates with
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CreatedUser {
+    pub name: String,
+    pub role: Role,
+    /// Only returned once, it cannot be looked up later
+    pub token: String,
+}
+
+/// Return the user the token belongs to
+#[utoipa::path(
+    get,
+    path = "/auth/me",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Authenticated user", body = User),
+        (status = 401, description = "Missing or unknown token", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler(state = AxumState)]
+pub async fn get_current_user(Viewer(user): Viewer) -> Json<User> {
+    Json(user)
+}
+
+/// Return all users, without their tokens
+#[utoipa::path(
+    get,
+    path = "/auth/users",
+    tag = "auth",
+    responses(
+        (status = 200, description = "All users", body = Vec<UserInfo>),
+        (status = 403, description = "Not an admin", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_users(
+    state: axum::extract::State<AxumState>,
+    _admin: Admin,
+) -> Result<Json<Vec<UserInfo>>, ApiError> {
+    match state.users.lock() {
+        Ok(users) => Ok(Json(users.list())),
+        Err(_) => Err(ApiError::StateUnavailable("user store")),
+    }
+}
+
+/// POST request handler to create a user, returning its token
+#[utoipa::path(
+    post,
+    path = "/auth/users",
+    tag = "auth",
+    request_body = NewUser,
+    responses(
+        (status = 200, description = "Created user and its token", body = CreatedUser),
+        (status = 400, description = "Invalid name", body = ApiErrorBody),
+        (status = 403, description = "Not an admin", body = ApiErrorBody),
+        (status = 409, description = "Name is taken", body = ApiErrorBody),
+        (status = 500, description = "Users could not be stored", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn post_user(
+    state: axum::extract::State<AxumState>,
+    Admin(admin): Admin,
+    ApiJson(new_user): ApiJson<NewUser>,
+) -> Result<Json<CreatedUser>, ApiError> {
+    let token = update_users(&state, |users| users.add(&new_user.name, new_user.role)).await?;
+    info!(
+        "{} created {} user {}",
+        admin.name,
+        new_user.role.as_str(),
+        new_user.name
+    );
+    Ok(Json(CreatedUser {
+        name: new_user.name,
+        role: new_user.role,
+        token,
+    }))
+}
+
+/// DELETE request handler to remove a user, revoking its token
+#[utoipa::path(
+    delete,
+    path = "/auth/users/{name}",
+    tag = "auth",
+    params(("name" = String, Path, description = "User name")),
+    responses(
+        (status = 204, description = "User removed"),
+        (status = 403, description = "Not an admin", body = ApiErrorBody),
+        (status = 404, description = "User not found", body = ApiErrorBody),
+        (status = 409, description = "User is the last admin", body = ApiErrorBody),
+        (status = 500, description = "Users could not be stored", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn delete_user(
+    state: axum::extract::State<AxumState>,
+    Admin(admin): Admin,
+    ApiPath(name): ApiPath<String>,
+) -> Result<StatusCode, ApiError> {
+    update_users(&state, |users| users.remove(&name)).await?;
+    info!("{} removed user {}", admin.name, name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Apply a change to a copy of the users, and keep it once it is stored on disk
+/// Changes are applied one at a time, each to the users stored by the previous one
+async fn update_users<T, E>(
+    state: &AxumState,
+    change: impl FnOnce(&mut UserStore) -> Result<T, E>,
+) -> Result<T, ApiError>
+where
+    ApiError: From<E>,
+{
+    let _update = state.users_update.lock().await;
+
+    let mut users = match state.users.lock() {
+        Ok(users) => users.clone(),
+        Err(_) => return Err(ApiError::StateUnavailable("user store")),
+    };
+    let result = change(&mut users)?;
+
+    if let Err(err) = users.save().await {
+        error!("Unable to store the users: {err}");
+        return Err(ApiError::Internal(String::from(
+            "unable to store the users",
+        )));
+    }
+    match state.users.lock() {
+        Ok(mut stored) => *stored = users,
+        Err(_) => return Err(ApiError::StateUnavailable("user store")),
+    }
+    Ok(result)
+}
@@ -0,0 +1,167 @@
+use axum::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::messages::frontend_messages::{NUMERIC_REPORT_CHANNELS, Report};
+
+/// Version of the binary frame layout, the first byte of every frame
+pub const BINARY_FRAME_VERSION: u8 = 2;
+
+/// Kind of a binary frame, the second byte of every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// Reports received since the previous frame
+    Live = 0,
+    /// Reports received before the client connected
+    History = 1,
+}
+
+impl FrameKind {
+    const ALL: [FrameKind; 2] = [FrameKind::Live, FrameKind::History];
+
+    fn name(self) -> &'static str {
+        match self {
+            FrameKind::Live => "live",
+            FrameKind::History => "history",
+        }
+    }
+}
+
+/// Field of a binary frame
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct BinaryField {
+    pub name: &'static str,
+    /// Little endian type, e.g. `u16` or `f32`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub description: &'static str,
+}
+
+/// Channel which can be present in the samples of a binary frame
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct BinaryChannel {
+    /// Bit of the channel in the channel mask
+    pub bit: usize,
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+}
+
+/// Value of the `kind` header field
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct BinaryFrameKind {
+    pub value: u8,
+    pub name: &'static str,
+}
+
+/// Layout of the binary websocket frames
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct BinarySchema {
+    pub version: u8,
+    pub byte_order: &'static str,
+    /// Fields at the start of every frame
+    pub header: Vec<BinaryField>,
+    pub kinds: Vec<BinaryFrameKind>,
+    /// Fields every sample starts with, followed by one value per channel in the channel mask
+    pub sample: Vec<BinaryField>,
+    pub channels: Vec<BinaryChannel>,
+}
+
+/// Layout of the binary frames produced by `encode_reports`
+pub fn binary_schema() -> BinarySchema {
+    BinarySchema {
+        version: BINARY_FRAME_VERSION,
+        byte_order: "little_endian",
+        header: vec![
+            BinaryField {
+                name: "version",
+                kind: "u8",
+                description: "version of the frame layout",
+            },
+            BinaryField {
+                name: "kind",
+                kind: "u8",
+                description: "whether the frame holds live or replayed history reports",
+            },
+            BinaryField {
+                name: "samples",
+                kind: "u16",
+                description: "number of samples in the frame",
+            },
+            BinaryField {
+                name: "channels",
+                kind: "u32",
+                description: "channel mask, bit n is set if channel n is present in every sample",
+            },
+        ],
+        kinds: FrameKind::ALL
+            .iter()
+            .map(|&kind| BinaryFrameKind {
+                value: kind as u8,
+                name: kind.name(),
+            })
+            .collect(),
+        sample: vec![BinaryField {
+            name: "time",
+            kind: "i64",
+            description: "time of the sample, in ns since the unix epoch",
+        }],
+        channels: NUMERIC_REPORT_CHANNELS
+            .iter()
+            .enumerate()
+            .map(|(bit, name)| BinaryChannel {
+                bit,
+                name,
+                kind: "f32",
+            })
+            .collect(),
+    }
+}
+
+/// Return the layout of the binary websocket frames
+#[utoipa::path(
+    get,
+    path = "/ws/schema",
+    tag = "measurements",
+    security(()),
+    responses(
+        (status = 200, description = "Layout of the binary frames", body = BinarySchema),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_binary_schema() -> Json<BinarySchema> {
+    Json(binary_schema())
+}
+
+/// Channel mask selecting the given channels, all channels if None
+/// Channels which are not numeric are not sent in binary frames and are ignored
+pub fn channel_mask(channels: Option<&[String]>) -> u32 {
+    NUMERIC_REPORT_CHANNELS
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| channels.is_none_or(|c| c.iter().any(|c| c == *name)))
+        .fold(0, |mask, (bit, _)| mask | (1 << bit))
+}
+
+/// Encode reports into a binary frame holding the channels in `mask`
+/// Missing values, like the setpoints of a disabled controller, are encoded as NaN
+pub fn encode_reports(reports: &[Report], mask: u32, kind: FrameKind) -> Vec<u8> {
+    let channels = mask.count_ones() as usize;
+    let mut frame = Vec::with_capacity(8 + reports.len() * (8 + 4 * channels));
+
+    frame.push(BINARY_FRAME_VERSION);
+    frame.push(kind as u8);
+    frame.extend_from_slice(&(reports.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    frame.extend_from_slice(&mask.to_le_bytes());
+
+    for report in reports.iter().take(u16::MAX as usize) {
+        frame.extend_from_slice(&report.time().to_le_bytes());
+        for (bit, value) in report.numeric_values().into_iter().enumerate() {
+            if mask & (1 << bit) != 0 {
+                frame.extend_from_slice(&value.unwrap_or(f32::NAN).to_le_bytes());
+            }
+        }
+    }
+    frame
+}
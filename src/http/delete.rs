@@ -0,0 +1,107 @@
+use crate::auth::{Role, User};
+use crate::axumstate::AxumState;
+use crate::control::lease::LeaseChange;
+use crate::experiment::catalogue::{load_catalogue_entry, store_catalogue_entry};
+use crate::http::auth::{Operator, lease_token};
+use crate::http::error::{ApiError, ApiErrorBody, ApiPath};
+use crate::messages::system_events::{SystemEvent, publish_event};
+use axum::http::{HeaderMap, StatusCode};
+use chrono::Utc;
+use tracing::*;
+use uuid::Uuid;
+
+/// DELETE request handler to move an experiment to the trash
+/// The experiment is hidden from the experiment list, and removed for good once it was in the
+/// trash for longer than the trash retention period
+#[utoipa::path(
+    delete,
+    path = "/experiment/{id}",
+    tag = "experiments",
+    params(("id" = Uuid, Path, description = "Experiment id")),
+    responses(
+        (status = 204, description = "Experiment is in the trash"),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 404, description = "Experiment not found", body = ApiErrorBody),
+        (status = 409, description = "Experiment is running", body = ApiErrorBody),
+        (status = 500, description = "Internal error", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn delete_experiment(
+    state: axum::extract::State<AxumState>,
+    _operator: Operator,
+    ApiPath(experiment_id): ApiPath<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    let (mut entry, is_running) = load_catalogue_entry(&state, experiment_id).await?;
+    if is_running {
+        warn!("Rejecting deletion of running experiment {experiment_id}");
+        return Err(ApiError::Conflict(String::from(
+            "stop the experiment before deleting it",
+        )));
+    }
+    if entry.deleted_at.is_some() {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let now = Utc::now();
+    entry.deleted_at = Some(now);
+    entry.time = now;
+    store_catalogue_entry(&state, entry).await?;
+
+    info!("Moved experiment {experiment_id} to the trash");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE request handler to give up the control lease
+/// Admins may release the lease of any client
+#[utoipa::path(
+    delete,
+    path = "/control/lease",
+    tag = "control",
+    params(("X-Control-Lease" = Option<String>, Header, description = "Token of the control lease")),
+    responses(
+        (status = 204, description = "Nobody is in control"),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 423, description = "The lease is held by another client", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn delete_control_lease(
+    state: axum::extract::State<AxumState>,
+    Operator(user): Operator,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    release_control(&state, &user, lease_token(&headers))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Release the control lease held with `token`, or any lease if the user is an admin
+/// Shared by the DELETE /control/lease handler and the websocket
+pub(crate) fn release_control(
+    state: &AxumState,
+    user: &User,
+    token: Option<&str>,
+) -> Result<(), ApiError> {
+    let released = match state.control_lease.lock() {
+        Ok(mut lease) => lease.release(token, user.role >= Role::Admin)?,
+        Err(_) => {
+            error!("Unable to release the control lease");
+            return Err(ApiError::StateUnavailable("control lease"));
+        }
+    };
+
+    if let Some(owner) = released {
+        info!("{} released the control lease of {owner}", user.name);
+        publish_event(
+            &state.event_broadcast,
+            SystemEvent::ControlLeaseChanged {
+                change: LeaseChange::Released,
+                owner: None,
+                previous_owner: Some(owner),
+            },
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,275 @@
+use axum::Json;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use serde_json::{Value, json};
+use thiserror::Error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::analysis::compare::CompareError;
+use crate::auth::UserError;
+use crate::control::lease::LeaseError;
+use crate::experiment::annotations::AnnotationError;
+use crate::experiment::catalogue::{CatalogueError, ExperimentEditError};
+use crate::experiment::search::ExperimentSearchError;
+use crate::export::ExportError;
+use crate::export::archive::ImportError;
+use crate::messages::validation::FieldError;
+
+/// Error returned by the http handlers and websocket commands
+/// Sent to the client as an `ApiErrorBody`, with the status code of the error
+#[derive(Debug, Error)]
+pub enum ApiError {
+    /// The request could not be parsed, or asks for something that makes no sense
+    #[error("{0}")]
+    InvalidRequest(String),
+    /// One or more fields of the request hold invalid values
+    #[error("{} invalid field(s)", .0.len())]
+    InvalidFields(Vec<FieldError>),
+    /// No valid token was sent
+    #[error("{0}")]
+    Unauthorized(String),
+    /// The role of the user does not allow the request
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    /// The request does not apply to the current state, e.g. pausing a paused experiment
+    #[error("{0}")]
+    Conflict(String),
+    /// The request needs the control lease, which the client does not hold
+    #[error("{0}")]
+    Locked(String),
+    /// A mutex guarding shared state was poisoned by a task that panicked
+    #[error("the {0} is unavailable")]
+    StateUnavailable(&'static str),
+    /// A background task no longer accepts messages
+    #[error("the {0} task is not running")]
+    TaskUnavailable(&'static str),
+    /// The database could not be queried or returned something unexpected
+    #[error("database error: {0}")]
+    Database(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// Body of every error response
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct ApiErrorBody {
+    /// Kind of error, stable across releases, e.g. `not_found`
+    pub code: &'static str,
+    /// Human readable description of the problem
+    pub message: String,
+    /// Further information depending on the code, e.g. the invalid fields, or null
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidRequest(_) | ApiError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Locked(_) => StatusCode::LOCKED,
+            ApiError::TaskUnavailable(_) | ApiError::Database(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::StateUnavailable(_) | ApiError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::InvalidFields(_) => "invalid_fields",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Locked(_) => "locked",
+            ApiError::StateUnavailable(_) => "state_unavailable",
+            ApiError::TaskUnavailable(_) => "task_unavailable",
+            ApiError::Database(_) => "database_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn body(&self) -> ApiErrorBody {
+        let details = match self {
+            ApiError::InvalidFields(fields) => Some(json!({ "fields": fields })),
+            _ => None,
+        };
+        ApiErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            details,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        if let ApiError::Unauthorized(_) = self {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+/// Errors of the DB helpers are plain strings
+impl From<String> for ApiError {
+    fn from(err: String) -> Self {
+        ApiError::Database(err)
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<Vec<FieldError>> for ApiError {
+    fn from(errors: Vec<FieldError>) -> Self {
+        ApiError::InvalidFields(errors)
+    }
+}
+
+impl From<ExperimentEditError> for ApiError {
+    fn from(err: ExperimentEditError) -> Self {
+        ApiError::InvalidFields(vec![FieldError {
+            field: err.field(),
+            message: err.to_string(),
+        }])
+    }
+}
+
+impl From<AnnotationError> for ApiError {
+    fn from(err: AnnotationError) -> Self {
+        match err {
+            AnnotationError::EmptyText => ApiError::InvalidFields(vec![FieldError {
+                field: "text",
+                message: err.to_string(),
+            }]),
+            AnnotationError::TimeOutOfRange(_) => ApiError::InvalidFields(vec![FieldError {
+                field: "time",
+                message: err.to_string(),
+            }]),
+            AnnotationError::NotRunning => ApiError::Conflict(err.to_string()),
+            AnnotationError::StateUnavailable(state) => ApiError::StateUnavailable(state),
+            AnnotationError::TaskUnavailable(task) => ApiError::TaskUnavailable(task),
+        }
+    }
+}
+
+impl From<CatalogueError> for ApiError {
+    fn from(err: CatalogueError) -> Self {
+        match err {
+            CatalogueError::NotFound(experiment_id) => experiment_not_found(experiment_id),
+            CatalogueError::InvalidId(_) => ApiError::Internal(err.to_string()),
+            CatalogueError::StateUnavailable(state) => ApiError::StateUnavailable(state),
+            CatalogueError::TaskUnavailable(task) => ApiError::TaskUnavailable(task),
+            CatalogueError::Database(err) => ApiError::Database(err),
+        }
+    }
+}
+
+impl From<ExperimentSearchError> for ApiError {
+    fn from(err: ExperimentSearchError) -> Self {
+        ApiError::InvalidFields(vec![FieldError {
+            field: err.field(),
+            message: err.to_string(),
+        }])
+    }
+}
+
+impl From<CompareError> for ApiError {
+    fn from(err: CompareError) -> Self {
+        match err {
+            CompareError::NotFound(_) | CompareError::NoData(_) => {
+                ApiError::NotFound(err.to_string())
+            }
+            CompareError::Database(err) => ApiError::Database(err),
+        }
+    }
+}
+
+impl From<ExportError> for ApiError {
+    fn from(err: ExportError) -> Self {
+        match err {
+            ExportError::NotFound(_) | ExportError::NoData(_) => {
+                ApiError::NotFound(err.to_string())
+            }
+            ExportError::Encoding(err) => ApiError::Internal(err),
+            ExportError::Database(err) => ApiError::Database(err),
+        }
+    }
+}
+
+impl From<ImportError> for ApiError {
+    fn from(err: ImportError) -> Self {
+        match err {
+            ImportError::InvalidArchive(_) => ApiError::InvalidRequest(err.to_string()),
+            ImportError::Database(err) => ApiError::Database(err),
+        }
+    }
+}
+
+impl From<UserError> for ApiError {
+    fn from(err: UserError) -> Self {
+        match err {
+            UserError::InvalidName => ApiError::InvalidFields(vec![FieldError {
+                field: "name",
+                message: err.to_string(),
+            }]),
+            UserError::Exists(_) | UserError::LastAdmin => ApiError::Conflict(err.to_string()),
+            UserError::NotFound(_) => ApiError::NotFound(err.to_string()),
+        }
+    }
+}
+
+impl From<LeaseError> for ApiError {
+    fn from(err: LeaseError) -> Self {
+        ApiError::Locked(err.to_string())
+    }
+}
+
+/// `NotFound` error for an experiment that does not exist
+pub fn experiment_not_found(experiment_id: Uuid) -> ApiError {
+    ApiError::NotFound(format!("experiment {experiment_id} not found"))
+}
+
+/// JSON request body, rejected with an `ApiError`
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+/// Query parameters, rejected with an `ApiError`
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct ApiQuery<T>(pub T);
+
+/// Path parameters, rejected with an `ApiError`
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct ApiPath<T>(pub T);
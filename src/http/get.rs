@@ -1,28 +1,65 @@
-use crate::database::secrets::*;
+use crate::analysis::compare::{CompareError, MAX_COMPARED_EXPERIMENTS, compare_experiments};
+use crate::analysis::summary::{ExperimentSummary, load_summary};
+use crate::config::{Config, config};
+use crate::control::history::{DEFAULT_HISTORY_SECONDS, HISTORY_SECONDS};
+use crate::control::lease::LeaseStatus;
+use crate::database::experiment_data::{
+    NUMERIC_COLUMNS, SENSOR_COLUMNS, query_downsampled, query_time_range,
+};
+use crate::database::query::{query_table_columns, quote_identifier, stream_sql};
 use crate::experiment::ExperimentStatus;
-use crate::http::messages::{ExperimentFromDB, ExperimentList, ExperimentListFromDB};
+use crate::experiment::catalogue::{apply_catalogue, apply_catalogue_to_row};
+use crate::experiment::listing::{
+    find_experiment, get_experiment_metadata, query_annotations, query_experiments_from_influxdb,
+    query_setpoint_events, resolve_experiment_table,
+};
+use crate::experiment::naming::EXPERIMENT_TABLE_PREFIX;
+use crate::export::archive::stream_archive;
+use crate::export::{ExportError, ExportFormat, ExportInfo};
+use crate::http::auth::{Admin, Viewer};
+use crate::http::error::{ApiError, ApiErrorBody, ApiPath, ApiQuery, experiment_not_found};
+use crate::http::messages::{
+    ArchiveQuery, ChannelBuckets, ExperimentCompareQuery, ExperimentComparison, ExperimentData,
+    ExperimentDataQuery, ExperimentDetail, ExperimentList, ExperimentListFromDB,
+    ExperimentListQuery, ExportQuery, HistoryQuery, SetpointEventList,
+};
+use crate::messages::db_messages::DatabaseMessage;
+use crate::messages::validation::FieldError;
 use crate::{AxumState, http::messages::HeartbeatMessage, messages::frontend_messages::Report};
 use axum::Json;
-use axum::extract::Path;
+use axum::body::Body;
 use axum::http::{StatusCode, header};
-use axum::response::Response;
-use serde_json::Value;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use futures_util::{Stream, StreamExt, stream};
 use tracing::*;
+use uuid::Uuid;
 
 /// Returns the latest measurement report from the mcu
+#[utoipa::path(
+    get,
+    path = "/measurements",
+    tag = "measurements",
+    responses(
+        (status = 200, description = "Latest report", body = Report),
+        (status = 204, description = "No report received yet"),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+    )
+)]
 #[axum::debug_handler]
 pub async fn get_measurements(
     state: axum::extract::State<AxumState>,
-) -> Result<Json<Report>, StatusCode> {
+    _viewer: Viewer,
+) -> Result<Response, ApiError> {
     if let Ok(guard) = state.report.lock() {
         if let Some(ref report) = *guard {
             // Return json-serialised report
             info!("GET measurements returning report: {:?}", report);
-            return Ok(Json(report.clone()));
+            return Ok(Json(report.clone()).into_response());
         } else {
             // No report was generated yet
             warn!("GET measurements attempted but no report in axum state");
-            return Err(StatusCode::NO_CONTENT);
+            return Ok(StatusCode::NO_CONTENT.into_response());
         }
     }
 
@@ -30,32 +67,161 @@ pub async fn get_measurements(
     error!(
         "Unable to lock the report mutex guard during GET measurements, returning INTERNAL_SERVER_ERROR and moving on with life..."
     );
-    Err(StatusCode::INTERNAL_SERVER_ERROR)
+    Err(ApiError::StateUnavailable("latest report"))
+}
+
+/// Returns the reports of the last `seconds`, oldest first, whether or not an experiment ran
+#[utoipa::path(
+    get,
+    path = "/measurements/history",
+    tag = "measurements",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "Reports, oldest first", body = Vec<Report>),
+        (status = 400, description = "Invalid query parameters", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_measurement_history(
+    state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+    ApiQuery(query): ApiQuery<HistoryQuery>,
+) -> Result<Json<Vec<Report>>, ApiError> {
+    let seconds = query.seconds.unwrap_or(DEFAULT_HISTORY_SECONDS);
+    validate_history_seconds("seconds", seconds)?;
+
+    match recent_reports(&state, seconds) {
+        Some(reports) => Ok(Json(reports)),
+        None => {
+            error!("Unable to lock the report history during GET measurement history");
+            Err(ApiError::StateUnavailable("report history"))
+        }
+    }
+}
+
+/// Check that a requested length of report history, in seconds, is kept in memory
+pub(crate) fn validate_history_seconds(field: &'static str, seconds: u64) -> Result<(), ApiError> {
+    if seconds > HISTORY_SECONDS {
+        warn!("Requested {seconds} s of report history");
+        return Err(ApiError::InvalidFields(vec![FieldError {
+            field,
+            message: format!("at most {HISTORY_SECONDS} s of reports are kept"),
+        }]));
+    }
+    Ok(())
+}
+
+/// Reports of the last `seconds` as sent to the frontend, None if the history is unavailable
+/// Reports received since the current experiment started are sent as part of it
+pub(crate) fn recent_reports(state: &AxumState, seconds: u64) -> Option<Vec<Report>> {
+    let from = Utc::now() - chrono::Duration::seconds(seconds as i64);
+    let experiment = state.current_experiment.lock().ok()?.clone();
+    let reports = state.history.lock().ok()?.since(from);
+    Some(
+        reports
+            .into_iter()
+            .map(|report| {
+                let during = experiment
+                    .as_ref()
+                    .filter(|experiment| report.time >= experiment.start_time);
+                Report::from(report.with_experiment(during.cloned().unwrap_or_default()))
+            })
+            .collect(),
+    )
 }
 
 /// Return a heartbeat message
+#[utoipa::path(
+    get,
+    path = "/heartbeat",
+    tag = "system",
+    security(()),
+    responses(
+        (status = 200, description = "Service is alive", body = HeartbeatMessage),
+    )
+)]
 #[axum::debug_handler]
-pub async fn get_heartbeat(_state: axum::extract::State<AxumState>) -> Json<HeartbeatMessage> {
-    Json(HeartbeatMessage::new())
+pub async fn get_heartbeat(state: axum::extract::State<AxumState>) -> Json<HeartbeatMessage> {
+    let mcu_clock = state
+        .mcu_clock
+        .lock()
+        .map(|clock| clock.clone())
+        .unwrap_or_default();
+    Json(HeartbeatMessage::new(*state.start_time, mcu_clock))
+}
+
+/// Return the configuration the service runs with, without its secrets
+#[utoipa::path(
+    get,
+    path = "/system/config",
+    tag = "system",
+    responses(
+        (status = 200, description = "Configuration in effect", body = Config),
+        (status = 403, description = "Not an admin", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler(state = AxumState)]
+pub async fn get_config(_admin: Admin) -> Json<Config> {
+    Json(config().redacted())
 }
 
 /// Return status of the currently running experiment
+#[utoipa::path(
+    get,
+    path = "/experiment/status",
+    tag = "experiments",
+    responses(
+        (status = 200, description = "Running experiment, `is_running` is false if none is", body = ExperimentStatus),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+    )
+)]
 #[axum::debug_handler]
 pub async fn get_experiment_status(
     state: axum::extract::State<AxumState>,
-) -> Result<Json<ExperimentStatus>, StatusCode> {
+    _viewer: Viewer,
+) -> Result<Json<ExperimentStatus>, ApiError> {
     if let Ok(experiment) = state.current_experiment.lock() {
         if let Some(ref exp) = *experiment {
             // Convert to ExperimentStatus on-the-fly to get fresh duration calculation
             let status: ExperimentStatus = exp.into();
-            Ok(Json(status))
+            let data_quality = state
+                .data_quality
+                .lock()
+                .map(|quality| quality.clone())
+                .unwrap_or_default();
+            Ok(Json(status.with_quality(data_quality)))
         } else {
             let status = ExperimentStatus::default();
             Ok(Json(status))
         }
     } else {
         error!("Unable to fetch the current experiment");
-        Err(StatusCode::INTERNAL_SERVER_ERROR)
+        Err(ApiError::StateUnavailable("current experiment"))
+    }
+}
+
+/// Return the client in control of the rig, if any
+#[utoipa::path(
+    get,
+    path = "/control/lease",
+    tag = "control",
+    responses(
+        (status = 200, description = "Holder of the control lease", body = LeaseStatus),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_control_lease(
+    state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+) -> Result<Json<LeaseStatus>, ApiError> {
+    match state.control_lease.lock() {
+        Ok(lease) => Ok(Json(lease.status())),
+        Err(_) => {
+            error!("Unable to fetch the control lease");
+            Err(ApiError::StateUnavailable("control lease"))
+        }
     }
 }
 
@@ -63,7 +229,8 @@ pub async fn get_experiment_status(
 #[axum::debug_handler]
 pub async fn get_list_experiment(
     state: axum::extract::State<AxumState>,
-) -> Result<Json<ExperimentList>, StatusCode> {
+    _viewer: Viewer,
+) -> Result<Json<ExperimentList>, ApiError> {
     let experiments = state.experiments.lock();
 
     if let Ok(experiments) = experiments {
@@ -72,485 +239,680 @@ pub async fn get_list_experiment(
         return Ok(Json(experiments));
     }
 
-    warn!("GET experiment list returned nothing");
-    Err(StatusCode::NO_CONTENT)
+    error!("Unable to fetch the experiment list");
+    Err(ApiError::StateUnavailable("experiment list"))
 }
 
-/// Return all experiments by querying InfluxDB directly
+/// Return the experiments matching the query by querying InfluxDB directly, except those in the
+/// trash
+#[utoipa::path(
+    get,
+    path = "/experiment/list",
+    tag = "experiments",
+    params(ExperimentListQuery),
+    responses(
+        (status = 200, description = "Matching experiments", body = ExperimentListFromDB),
+        (status = 400, description = "Invalid query parameters", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
 #[axum::debug_handler]
 pub async fn get_list_experiments_from_db(
     _state: axum::extract::State<AxumState>,
-) -> Result<Json<ExperimentListFromDB>, StatusCode> {
+    _viewer: Viewer,
+    ApiQuery(params): ApiQuery<ExperimentListQuery>,
+) -> Result<Json<ExperimentListFromDB>, ApiError> {
+    if let Err(err) = params.validate() {
+        warn!("Invalid experiment list query {:?}: {err}", params);
+        return Err(err.into());
+    }
+
     match query_experiments_from_influxdb().await {
-        Ok(experiments) => {
-            info!("Returning {} experiments from database", experiments.len());
-            Ok(Json(ExperimentListFromDB { experiments }))
+        Ok(mut experiments) => {
+            experiments.retain(|e| e.deleted_at.is_none());
+            let (experiments, total) = params.apply(experiments);
+            info!(
+                "Returning {} of {} matching experiments from database",
+                experiments.len(),
+                total
+            );
+            Ok(Json(ExperimentListFromDB { experiments, total }))
         }
         Err(e) => {
             error!("Failed to retrieve experiments from InfluxDB: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            Err(ApiError::Database(e))
         }
     }
 }
 
-/// Download experiment data as CSV
+/// Return all experiments in the trash, which are removed for good once they were deleted for
+/// longer than the trash retention period
+#[utoipa::path(
+    get,
+    path = "/experiment/trash",
+    tag = "experiments",
+    responses(
+        (status = 200, description = "Experiments in the trash", body = ExperimentListFromDB),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
 #[axum::debug_handler]
-pub async fn download_experiment_csv(
+pub async fn get_experiment_trash(
     _state: axum::extract::State<AxumState>,
-    Path(table_name): Path<String>,
-) -> Result<Response, StatusCode> {
-    info!("Download request for table: {}", table_name);
+    _viewer: Viewer,
+) -> Result<Json<ExperimentListFromDB>, ApiError> {
+    match query_experiments_from_influxdb().await {
+        Ok(mut experiments) => {
+            experiments.retain(|e| e.deleted_at.is_some());
+            info!("Returning {} experiments from the trash", experiments.len());
+            let total = experiments.len();
+            Ok(Json(ExperimentListFromDB { experiments, total }))
+        }
+        Err(e) => {
+            error!("Failed to retrieve experiments from InfluxDB: {}", e);
+            Err(ApiError::Database(e))
+        }
+    }
+}
+
+/// Download experiment data as CSV, JSON Lines, Parquet or a MATLAB .mat file
+#[utoipa::path(
+    get,
+    path = "/experiment/download/{table_name}",
+    tag = "experiments",
+    params(
+        ("table_name" = String, Path, description = "Table of the experiment"),
+        ExportQuery,
+    ),
+    responses(
+        (status = 200, description = "Experiment data in the requested format", content(
+            ("text/csv"),
+            ("application/x-ndjson"),
+            ("application/vnd.apache.parquet"),
+            ("application/x-matlab-data"),
+        )),
+        (status = 400, description = "Invalid table name", body = ApiErrorBody),
+        (status = 404, description = "Table not found or empty", body = ApiErrorBody),
+        (status = 500, description = "Export failed", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn download_experiment(
+    _state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+    ApiPath(table_name): ApiPath<String>,
+    ApiQuery(params): ApiQuery<ExportQuery>,
+) -> Result<Response, ApiError> {
+    let format = params.format.unwrap_or_default();
+    info!("Download request for table: {} as {:?}", table_name, format);
 
     // Validate table name
-    if !table_name.starts_with("experiment_") {
+    if !table_name.starts_with(EXPERIMENT_TABLE_PREFIX) {
         warn!("Invalid table name requested: {}", table_name);
-        return Err(StatusCode::BAD_REQUEST);
+        return Err(ApiError::InvalidFields(vec![FieldError {
+            field: "table_name",
+            message: format!("table_name must start with {EXPERIMENT_TABLE_PREFIX}"),
+        }]));
     }
 
-    // Query data from InfluxDB and convert to CSV
-    match query_table_as_csv(&table_name).await {
-        Ok(csv_content) => {
-            info!(
-                "Successfully generated CSV for table: {} ({} bytes)",
-                table_name,
-                csv_content.len()
-            );
-
-            let filename = format!("{}.csv", table_name);
+    // Query data from InfluxDB and stream it in the requested format
+    match stream_table_export(&table_name, format).await {
+        Ok(export_stream) => {
+            info!("Streaming {:?} export for table: {}", format, table_name);
+
+            // Legacy table names may contain user input, keep the header value well-formed
+            let filename: String = table_name
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            let filename = format!("{}.{}", filename, format.extension());
 
             // Build response with appropriate headers
             Ok(Response::builder()
                 .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, "text/csv")
+                .header(header::CONTENT_TYPE, format.content_type())
                 .header(
                     header::CONTENT_DISPOSITION,
                     format!("attachment; filename=\"{}\"", filename),
                 )
-                .body(csv_content.into())
+                .body(Body::from_stream(export_stream))
                 .unwrap())
         }
         Err(e) => {
             error!("Failed to download experiment {}: {}", table_name, e);
-            if e.contains("No data found") || e.contains("not found") {
-                Err(StatusCode::NOT_FOUND)
-            } else {
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
-            }
+            Err(e.into())
         }
     }
 }
 
-/// Query InfluxDB for all experiment tables and their metadata
-async fn query_experiments_from_influxdb() -> Result<Vec<ExperimentFromDB>, String> {
-    // Create HTTP client
-    let client = reqwest::Client::new();
-
-    // Query to list all tables starting with 'experiment_' in the iox schema
-    let list_tables_query = r#"
-        SELECT DISTINCT table_name 
-        FROM information_schema.tables 
-        WHERE table_name LIKE 'experiment_%' AND table_schema = 'iox'
-        ORDER BY table_name DESC
-    "#;
-
-    // Execute query to get table names
-    let url = format!("{}/api/v3/query_sql", DB_URI);
-    info!("Querying InfluxDB at: {}", url);
-    info!("Query: {}", list_tables_query);
-
-    let response = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", DB_ACCESS_TOKEN))
-        .header("Content-Type", "application/json")
-        .json(&serde_json::json!({
-            "db": DB_NAME,
-            "q": list_tables_query,
-            "format": "json"
-        }))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to query InfluxDB: {}", e))?;
-
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        error!(
-            "InfluxDB query failed with status {}: {}",
-            status, error_text
-        );
-        return Err(format!("InfluxDB query failed with status: {}", status));
-    }
-
-    let tables_result: Value = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse InfluxDB response: {}", e))?;
-
-    info!(
-        "InfluxDB response: {}",
-        serde_json::to_string_pretty(&tables_result)
-            .unwrap_or_else(|_| "Failed to serialize".to_string())
-    );
-
-    // Extract table names from response
-    let table_names = extract_table_names(&tables_result).ok_or_else(|| {
-        format!(
-            "Failed to extract table names from response: {:?}",
-            tables_result
-        )
-    })?;
-
-    info!(
-        "Found {} experiment tables: {:?}",
-        table_names.len(),
-        table_names
-    );
-
-    if table_names.is_empty() {
-        return Ok(Vec::new());
-    }
-
-    // For each table, query first and last record to get metadata
-    let mut experiments = Vec::new();
-    for table_name in table_names {
-        match get_experiment_metadata(&client, &table_name).await {
-            Ok(Some(experiment)) => experiments.push(experiment),
-            Ok(None) => {
-                warn!("No metadata found for table: {}", table_name);
+/// Download one or more experiments as a single archive, including their metadata, setpoint
+/// events, annotations, data gaps and data. Without ids, all experiments are archived
+#[utoipa::path(
+    get,
+    path = "/experiment/archive",
+    tag = "experiments",
+    params(ArchiveQuery),
+    responses(
+        (status = 200, description = "Gzip compressed JSON Lines archive", content_type = "application/gzip"),
+        (status = 400, description = "Invalid query parameters", body = ApiErrorBody),
+        (status = 404, description = "Experiment not found", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn download_experiment_archive(
+    _state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+    ApiQuery(params): ApiQuery<ArchiveQuery>,
+) -> Result<Response, ApiError> {
+    let experiment_ids = match params.ids {
+        Some(ref ids) => match parse_experiment_ids(ids) {
+            Some(ids) => ids,
+            None => {
+                warn!("Invalid experiment ids requested for archive: {}", ids);
+                return Err(ApiError::InvalidFields(vec![ids_error(String::from(
+                    "ids must be a comma separated list of experiment ids",
+                ))]));
             }
+        },
+        None => match query_experiments_from_influxdb().await {
+            Ok(experiments) => experiments
+                .iter()
+                .filter(|e| e.deleted_at.is_none())
+                .filter_map(|e| Uuid::parse_str(&e.experiment_id).ok())
+                .collect(),
             Err(e) => {
-                error!("Failed to get metadata for table {}: {}", table_name, e);
-                // Continue with other tables instead of failing completely
+                error!("Failed to retrieve experiments from InfluxDB: {}", e);
+                return Err(ApiError::Database(e));
             }
+        },
+    };
+    info!("Archive request for {} experiments", experiment_ids.len());
+
+    match stream_archive(experiment_ids).await {
+        Ok(archive_stream) => {
+            let filename = format!(
+                "loop-sense-archive-{}.jsonl.gz",
+                Utc::now().format("%Y%m%dT%H%M%SZ")
+            );
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/gzip")
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", filename),
+                )
+                .body(Body::from_stream(archive_stream))
+                .unwrap())
+        }
+        Err(e) => {
+            error!("Failed to archive experiments: {}", e);
+            Err(e.into())
         }
     }
-
-    Ok(experiments)
 }
 
-/// Extract table names from InfluxDB query response
-fn extract_table_names(response: &Value) -> Option<Vec<String>> {
-    // InfluxDB 3.0 returns results as an array of objects: [{"table_name": "..."}]
-    if let Some(results) = response.as_array() {
-        let tables: Vec<String> = results
-            .iter()
-            .filter_map(|result| {
-                result
-                    .get("table_name")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string())
-            })
-            .collect();
-
-        info!("Extracted {} table names from response", tables.len());
-        if !tables.is_empty() {
-            return Some(tables);
+/// Return the summary of a recorded experiment, computing and storing it if the experiment was
+/// never summarised
+#[utoipa::path(
+    get,
+    path = "/experiment/{id}/summary",
+    tag = "experiments",
+    params(("id" = Uuid, Path, description = "Experiment id")),
+    responses(
+        (status = 200, description = "Summary of the experiment", body = ExperimentSummary),
+        (status = 404, description = "Experiment not found", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_experiment_summary(
+    state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+    ApiPath(experiment_id): ApiPath<Uuid>,
+) -> Result<Json<ExperimentSummary>, ApiError> {
+    let experiment = match find_experiment(experiment_id).await {
+        Ok(Some(experiment)) => experiment,
+        Ok(None) => {
+            warn!("Experiment {} not found", experiment_id);
+            return Err(experiment_not_found(experiment_id));
+        }
+        Err(e) => {
+            error!("Failed to retrieve experiments from InfluxDB: {}", e);
+            return Err(ApiError::Database(e));
+        }
+    };
+
+    match load_summary(&reqwest::Client::new(), &experiment).await {
+        Ok(Some((summary, computed))) => {
+            if computed
+                && let Err(err) = state
+                    .db_sender
+                    .send(DatabaseMessage::Summary(Box::new(summary.clone())))
+                    .await
+            {
+                error!("Unable to send experiment summary to database task: {err}");
+            }
+            info!("Returning summary of experiment {}", experiment_id);
+            Ok(Json(summary))
+        }
+        Ok(None) => {
+            warn!("No data found for experiment {}", experiment_id);
+            Err(ExportError::NoData(experiment_id.to_string()).into())
+        }
+        Err(e) => {
+            error!("Failed to summarise experiment {}: {}", experiment_id, e);
+            Err(ApiError::Database(e))
         }
-    } else {
-        warn!("Response is not an array: {:?}", response);
     }
-
-    None
 }
 
-/// Get metadata for a specific experiment table
-async fn get_experiment_metadata(
-    client: &reqwest::Client,
-    table_name: &str,
-) -> Result<Option<ExperimentFromDB>, String> {
-    // Query first record
-    let first_query = format!(
-        r#"SELECT experiment_id, experiment_name, experiment_description, time
-           FROM "{}"
-           ORDER BY time ASC
-           LIMIT 1"#,
-        table_name
-    );
-
-    // Query last record
-    let last_query = format!(
-        r#"SELECT time
-           FROM "{}"
-           ORDER BY time DESC
-           LIMIT 1"#,
-        table_name
-    );
-
-    let url = format!("{}/api/v3/query_sql", DB_URI);
-
-    // Get first record
-    let first_response = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", DB_ACCESS_TOKEN))
-        .header("Content-Type", "application/json")
-        .json(&serde_json::json!({
-            "db": DB_NAME,
-            "q": first_query,
-            "format": "json"
-        }))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to query first record: {}", e))?;
-
-    if !first_response.status().is_success() {
-        return Err(format!(
-            "First record query failed: {}",
-            first_response.status()
-        ));
+/// Compare the summary statistics, hemodynamics and, if they ran the same protocol, the protocol
+/// steps of two or more experiments
+#[utoipa::path(
+    get,
+    path = "/experiment/compare",
+    tag = "experiments",
+    params(ExperimentCompareQuery),
+    responses(
+        (status = 200, description = "Aligned statistics of the experiments", body = ExperimentComparison),
+        (status = 400, description = "Invalid query parameters", body = ApiErrorBody),
+        (status = 404, description = "Experiment not found or without data", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_experiment_comparison(
+    _state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+    ApiQuery(params): ApiQuery<ExperimentCompareQuery>,
+) -> Result<Json<ExperimentComparison>, ApiError> {
+    let experiment_ids = match parse_experiment_ids(&params.ids) {
+        Some(ids) if (2..=MAX_COMPARED_EXPERIMENTS).contains(&ids.len()) => ids,
+        _ => {
+            warn!(
+                "Invalid experiment ids requested for comparison: {}",
+                params.ids
+            );
+            return Err(ApiError::InvalidFields(vec![ids_error(format!(
+                "ids must list 2 to {MAX_COMPARED_EXPERIMENTS} experiment ids"
+            ))]));
+        }
+    };
+    let Some(columns) = parse_columns(params.columns.as_deref()) else {
+        warn!("Invalid columns requested: {:?}", params.columns);
+        return Err(ApiError::InvalidFields(vec![columns_error()]));
+    };
+
+    match compare_experiments(&experiment_ids, &columns).await {
+        Ok(comparison) => {
+            info!(
+                "Returning comparison of {} experiments",
+                experiment_ids.len()
+            );
+            Ok(Json(comparison))
+        }
+        Err(err @ (CompareError::NotFound(_) | CompareError::NoData(_))) => {
+            warn!("Unable to compare experiments: {err}");
+            Err(err.into())
+        }
+        Err(err) => {
+            error!("Failed to compare experiments: {err}");
+            Err(err.into())
+        }
     }
+}
 
-    let first_data: Value = first_response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse first record: {}", e))?;
-
-    // Get last record
-    let last_response = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", DB_ACCESS_TOKEN))
-        .header("Content-Type", "application/json")
-        .json(&serde_json::json!({
-            "db": DB_NAME,
-            "q": last_query,
-            "format": "json"
-        }))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to query last record: {}", e))?;
-
-    if !last_response.status().is_success() {
-        return Err(format!(
-            "Last record query failed: {}",
-            last_response.status()
-        ));
+/// Error for an invalid `ids` query parameter
+fn ids_error(message: String) -> FieldError {
+    FieldError {
+        field: "ids",
+        message,
     }
+}
 
-    let last_data: Value = last_response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse last record: {}", e))?;
-
-    // Extract metadata from responses
-    let first_record = extract_first_record(&first_data);
-    let last_time = extract_last_time(&last_data);
-
-    if let (Some(first), Some(last_time)) = (first_record, last_time) {
-        // InfluxDB timestamps may not have timezone suffix, add 'Z' if missing
-        let start_time_str = if first.start_time.ends_with('Z') {
-            first.start_time.clone()
-        } else {
-            format!("{}Z", first.start_time)
-        };
-        let end_time_str = if last_time.ends_with('Z') {
-            last_time.clone()
-        } else {
-            format!("{}Z", last_time)
-        };
-
-        // Calculate duration
-        let start_time = chrono::DateTime::parse_from_rfc3339(&start_time_str)
-            .map_err(|e| format!("Failed to parse start time '{}': {}", start_time_str, e))?;
-        let end_time = chrono::DateTime::parse_from_rfc3339(&end_time_str)
-            .map_err(|e| format!("Failed to parse end time '{}': {}", end_time_str, e))?;
-
-        let duration_seconds = (end_time - start_time).num_milliseconds() as f64 / 1000.0;
-
-        Ok(Some(ExperimentFromDB {
-            table_name: table_name.to_string(),
-            experiment_id: first.experiment_id,
-            experiment_name: first.experiment_name,
-            description: first.description,
-            start_time: Some(first.start_time),
-            duration_seconds,
-        }))
-    } else {
-        Ok(None)
+/// Error for an invalid `columns` query parameter
+fn columns_error() -> FieldError {
+    FieldError {
+        field: "columns",
+        message: String::from("columns must be a comma separated list of numeric columns"),
     }
 }
 
-#[derive(Debug)]
-struct FirstRecordData {
-    experiment_id: String,
-    experiment_name: String,
-    description: String,
-    start_time: String,
+/// Parse a comma separated list of experiment ids, None if it is empty or holds an invalid id
+fn parse_experiment_ids(ids: &str) -> Option<Vec<Uuid>> {
+    let ids = ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(Uuid::parse_str)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    (!ids.is_empty()).then_some(ids)
 }
 
-/// Extract first record data from query response
-fn extract_first_record(response: &Value) -> Option<FirstRecordData> {
-    // InfluxDB returns array format: [{"experiment_id": "...", "experiment_name": "...", ...}]
-    if let Some(results) = response.as_array() {
-        if let Some(first) = results.first() {
-            let experiment_id = first.get("experiment_id")?.as_str()?.to_string();
-            let experiment_name = first.get("experiment_name")?.as_str()?.to_string();
-            let description = first
-                .get("experiment_description")
-                .and_then(|d| d.as_str())
-                .unwrap_or("")
-                .to_string();
-            let start_time = first.get("time")?.as_str()?.to_string();
+/// Parse a comma separated list of columns, defaulting to the sensor channels
+/// None if the list is empty or holds a column that is not in `NUMERIC_COLUMNS`
+fn parse_columns(columns: Option<&str>) -> Option<Vec<&str>> {
+    let columns: Vec<&str> = match columns {
+        Some(columns) => columns
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .collect(),
+        None => SENSOR_COLUMNS.to_vec(),
+    };
+    if columns.is_empty() || columns.iter().any(|c| !NUMERIC_COLUMNS.contains(c)) {
+        return None;
+    }
+    Some(columns)
+}
 
+/// Return all setpoint changes recorded during an experiment
+#[utoipa::path(
+    get,
+    path = "/experiment/{id}/events",
+    tag = "experiments",
+    params(("id" = Uuid, Path, description = "Experiment id")),
+    responses(
+        (status = 200, description = "Setpoint changes, oldest first", body = SetpointEventList),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_setpoint_events(
+    _state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+    ApiPath(experiment_id): ApiPath<Uuid>,
+) -> Result<Json<SetpointEventList>, ApiError> {
+    match query_setpoint_events(&reqwest::Client::new(), experiment_id).await {
+        Ok(events) => {
             info!(
-                "Extracted first record: id={}, name={}, time={}",
-                experiment_id, experiment_name, start_time
+                "Returning {} setpoint events for experiment {}",
+                events.len(),
+                experiment_id
             );
-            return Some(FirstRecordData {
-                experiment_id,
-                experiment_name,
-                description,
-                start_time,
-            });
-        } else {
-            warn!("First record array is empty");
+            Ok(Json(SetpointEventList { events }))
+        }
+        Err(e) => {
+            error!(
+                "Failed to retrieve setpoint events for experiment {}: {}",
+                experiment_id, e
+            );
+            Err(ApiError::Database(e))
         }
-    } else {
-        warn!("First record response is not an array: {:?}", response);
     }
-
-    None
 }
 
-/// Extract last time from query response
-fn extract_last_time(response: &Value) -> Option<String> {
-    // InfluxDB returns array format: [{"time": "..."}]
-    if let Some(results) = response.as_array() {
-        if let Some(last) = results.first() {
-            let time = last.get("time")?.as_str()?.to_string();
-            info!("Extracted last time: {}", time);
-            return Some(time);
-        } else {
-            warn!("Last time array is empty");
+/// Return the metadata and annotations of a single experiment
+#[utoipa::path(
+    get,
+    path = "/experiment/{id}",
+    tag = "experiments",
+    params(("id" = Uuid, Path, description = "Experiment id")),
+    responses(
+        (status = 200, description = "Metadata and annotations", body = ExperimentDetail),
+        (status = 404, description = "Experiment not found", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_experiment_detail(
+    _state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+    ApiPath(experiment_id): ApiPath<Uuid>,
+) -> Result<Json<ExperimentDetail>, ApiError> {
+    let experiment = match find_experiment(experiment_id).await {
+        Ok(experiment) => experiment,
+        Err(e) => {
+            error!("Failed to retrieve experiments from InfluxDB: {}", e);
+            return Err(ApiError::Database(e));
+        }
+    };
+
+    let Some(experiment) = experiment else {
+        warn!("Experiment {} not found", experiment_id);
+        return Err(experiment_not_found(experiment_id));
+    };
+
+    match query_annotations(&reqwest::Client::new(), experiment_id).await {
+        Ok(annotations) => Ok(Json(ExperimentDetail {
+            experiment,
+            annotations,
+        })),
+        Err(e) => {
+            error!(
+                "Failed to retrieve annotations for experiment {}: {}",
+                experiment_id, e
+            );
+            Err(ApiError::Database(e))
         }
-    } else {
-        warn!("Last time response is not an array: {:?}", response);
     }
-
-    None
 }
 
-/// Query a table from InfluxDB and convert to CSV format
-async fn query_table_as_csv(table_name: &str) -> Result<String, String> {
-    let client = reqwest::Client::new();
-
-    // Query all data from the table ordered by time
-    let query = format!(r#"SELECT * FROM "{}" ORDER BY time ASC"#, table_name);
-
-    let url = format!("{}/api/v3/query_sql", DB_URI);
-    info!("Querying table {} for CSV export", table_name);
-
-    let response = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", DB_ACCESS_TOKEN))
-        .header("Content-Type", "application/json")
-        .json(&serde_json::json!({
-            "db": DB_NAME,
-            "q": query,
-            "format": "json"
-        }))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to query InfluxDB: {}", e))?;
-
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        error!(
-            "InfluxDB query failed with status {}: {}",
-            status, error_text
-        );
-        return Err(format!("InfluxDB query failed: {}", status));
+/// Default number of points returned by the experiment data endpoint
+const DEFAULT_DATA_POINTS: u32 = 2000;
+/// Maximum number of points returned by the experiment data endpoint
+const MAX_DATA_POINTS: u32 = 100_000;
+
+/// Return a time range of experiment data, downsampled into min/max/mean buckets
+#[utoipa::path(
+    get,
+    path = "/experiment/{id}/data",
+    tag = "experiments",
+    params(
+        ("id" = Uuid, Path, description = "Experiment id"),
+        ExperimentDataQuery,
+    ),
+    responses(
+        (status = 200, description = "Downsampled data", body = ExperimentData),
+        (status = 400, description = "Invalid query parameters", body = ApiErrorBody),
+        (status = 404, description = "Experiment not found", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn get_experiment_data(
+    _state: axum::extract::State<AxumState>,
+    _viewer: Viewer,
+    ApiPath(experiment_id): ApiPath<Uuid>,
+    ApiQuery(params): ApiQuery<ExperimentDataQuery>,
+) -> Result<Json<ExperimentData>, ApiError> {
+    let mut errors = Vec::new();
+    let columns = parse_columns(params.columns.as_deref());
+    if columns.is_none() {
+        warn!("Invalid columns requested: {:?}", params.columns);
+        errors.push(columns_error());
     }
-
-    let data: Value = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse InfluxDB response: {}", e))?;
-
-    // Convert JSON array to CSV
-    json_to_csv(&data, table_name)
-}
-
-/// Convert InfluxDB JSON response to CSV format
-fn json_to_csv(data: &Value, table_name: &str) -> Result<String, String> {
-    let records = data
-        .as_array()
-        .ok_or_else(|| "Response is not an array".to_string())?;
-
-    if records.is_empty() {
-        return Err(format!(
-            "No data found for experiment table '{}'",
-            table_name
-        ));
+    let points = params.points.unwrap_or(DEFAULT_DATA_POINTS);
+    if points == 0 || points > MAX_DATA_POINTS {
+        warn!("Invalid number of points requested: {}", points);
+        errors.push(FieldError {
+            field: "points",
+            message: format!("points must be between 1 and {MAX_DATA_POINTS}"),
+        });
     }
+    let (Some(columns), true) = (columns, errors.is_empty()) else {
+        return Err(ApiError::InvalidFields(errors));
+    };
 
-    // Extract column names from the first record
-    let first_record = records
-        .first()
-        .ok_or_else(|| "Empty records array".to_string())?
-        .as_object()
-        .ok_or_else(|| "First record is not an object".to_string())?;
-
-    let mut columns: Vec<String> = first_record.keys().cloned().collect();
-    columns.sort(); // Sort columns for consistent output
-
-    // Build CSV content
-    let mut csv = String::new();
+    let client = reqwest::Client::new();
+    let table_name = match resolve_experiment_table(&client, experiment_id).await {
+        Ok(Some(table_name)) => table_name,
+        Ok(None) => {
+            warn!("Experiment {} not found", experiment_id);
+            return Err(experiment_not_found(experiment_id));
+        }
+        Err(e) => {
+            error!("Failed to look up experiment {}: {}", experiment_id, e);
+            return Err(ApiError::Database(e));
+        }
+    };
 
-    // Write header
-    csv.push_str(&columns.join(","));
-    csv.push('\n');
+    // Default to the full experiment
+    let (from, to) = match (params.from, params.to) {
+        (Some(from), Some(to)) => (from, to),
+        (from, to) => match query_time_range(&client, &table_name).await {
+            Ok(Some((first, last))) => (from.unwrap_or(first), to.unwrap_or(last)),
+            Ok(None) => {
+                warn!("No data found for experiment {}", experiment_id);
+                return Err(ExportError::NoData(experiment_id.to_string()).into());
+            }
+            Err(e) => {
+                error!("Failed to query time range of {}: {}", table_name, e);
+                return Err(ApiError::Database(e));
+            }
+        },
+    };
+    if from > to {
+        warn!("Invalid time range requested: {} - {}", from, to);
+        return Err(ApiError::InvalidFields(vec![FieldError {
+            field: "from",
+            message: String::from("from must not lie after to"),
+        }]));
+    }
 
-    // Write data rows
-    for record in records {
-        let obj = record
-            .as_object()
-            .ok_or_else(|| "Record is not an object".to_string())?;
+    // Spread the range over the requested number of buckets
+    let bucket_ms = ((to - from).num_milliseconds() / points as i64).max(1);
 
-        let row: Vec<String> = columns
+    let rows = match query_downsampled(&client, &table_name, &columns, from, to, bucket_ms).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to query data of {}: {}", table_name, e);
+            return Err(ApiError::Database(e));
+        }
+    };
+
+    // Convert the rows into one vector per channel aggregate
+    let mut data = ExperimentData {
+        experiment_id,
+        from,
+        to,
+        bucket_ms,
+        time: Vec::with_capacity(rows.len()),
+        channels: columns
             .iter()
-            .map(|col| {
-                obj.get(col)
-                    .map(|v| value_to_csv_field(v))
-                    .unwrap_or_else(|| String::new())
-            })
-            .collect();
-
-        csv.push_str(&row.join(","));
-        csv.push('\n');
+            .map(|c| (c.to_string(), ChannelBuckets::default()))
+            .collect(),
+    };
+    for row in &rows {
+        data.time.push(
+            row.get("time")
+                .and_then(|t| t.as_str())
+                .unwrap_or("")
+                .to_string(),
+        );
+        for (column, buckets) in data.channels.iter_mut() {
+            let value = |aggregate: &str| {
+                row.get(format!("{column}_{aggregate}"))
+                    .and_then(|v| v.as_f64())
+            };
+            buckets.min.push(value("min"));
+            buckets.max.push(value("max"));
+            buckets.mean.push(value("mean"));
+        }
     }
 
     info!(
-        "Generated CSV with {} rows and {} columns",
-        records.len(),
-        columns.len()
+        "Returning {} buckets of {}ms for experiment {}",
+        rows.len(),
+        bucket_ms,
+        experiment_id
     );
-    Ok(csv)
+    Ok(Json(data))
 }
 
-/// Convert a JSON value to a CSV field, handling quotes and special characters
-fn value_to_csv_field(value: &Value) -> String {
-    match value {
-        Value::Null => String::new(),
-        Value::Bool(b) => b.to_string(),
-        Value::Number(n) => n.to_string(),
-        Value::String(s) => {
-            // If the string contains comma, quote, or newline, wrap it in quotes
-            if s.contains(',') || s.contains('"') || s.contains('\n') {
-                format!("\"{}\"", s.replace('"', "\"\""))
-            } else {
-                s.clone()
-            }
+/// Number of rows encoded into every chunk of a streamed export
+const EXPORT_CHUNK_ROWS: usize = 500;
+
+/// Query a table from InfluxDB and stream it in the given format, chunk by chunk
+/// Columns follow the table schema, so the output does not depend on the first record
+async fn stream_table_export(
+    table_name: &str,
+    format: ExportFormat,
+) -> Result<impl Stream<Item = Result<Vec<u8>, String>> + Send + 'static, ExportError> {
+    let client = reqwest::Client::new();
+    info!("Querying table {} for {:?} export", table_name, format);
+
+    let columns = query_table_columns(&client, table_name).await?;
+    if columns.is_empty() {
+        return Err(ExportError::NotFound(table_name.to_string()));
+    }
+
+    // Fetch the metadata and annotations of the experiment stored in this table, so they end up
+    // in the export
+    let mut experiment = get_experiment_metadata(&client, table_name)
+        .await?
+        .ok_or_else(|| ExportError::NoData(table_name.to_string()))?;
+    apply_catalogue(&client, &mut experiment).await?;
+    let annotations = match Uuid::parse_str(&experiment.experiment_id) {
+        Ok(id) => query_annotations(&client, id).await?,
+        Err(_) => Vec::new(),
+    };
+
+    // An export without a summary beats no export at all
+    let summary = match load_summary(&client, &experiment).await {
+        Ok(summary) => summary.map(|(summary, _)| summary),
+        Err(e) => {
+            warn!("Exporting {} without a summary: {}", table_name, e);
+            None
         }
-        Value::Array(_) | Value::Object(_) => {
-            // For complex types, serialize as JSON string
-            let json_str = serde_json::to_string(value).unwrap_or_default();
-            format!("\"{}\"", json_str.replace('"', "\"\""))
+    };
+
+    let metadata = experiment.clone();
+    let encoder = format
+        .encoder(ExportInfo {
+            columns,
+            experiment,
+            annotations,
+            summary,
+        })
+        .map_err(ExportError::Encoding)?;
+
+    // Query all data from the table ordered by time
+    let query = format!(
+        r#"SELECT * FROM {} ORDER BY time ASC"#,
+        quote_identifier(table_name)
+    );
+    // The tags of the rows still hold the name and description the data was recorded with
+    let rows = stream_sql(&client, &query)
+        .await?
+        .map(move |row| {
+            row.map(|mut row| {
+                apply_catalogue_to_row(&metadata, &mut row);
+                row
+            })
+        })
+        .boxed();
+
+    // Encode the rows as they come in
+    Ok(stream::unfold(Some((rows, encoder)), |state| async move {
+        let (mut rows, mut encoder) = state?;
+        let mut chunk = Vec::new();
+
+        for _ in 0..EXPORT_CHUNK_ROWS {
+            let result = match rows.next().await {
+                Some(Ok(row)) => encoder.write_row(&row, &mut chunk),
+                Some(Err(e)) => Err(e),
+                None => {
+                    let result = encoder.finish(&mut chunk).map(|_| chunk);
+                    info!("Exported {} rows", encoder.rows());
+                    return Some((result, None));
+                }
+            };
+            if let Err(e) = result {
+                return Some((Err(e), None));
+            }
         }
-    }
+
+        Some((Ok(chunk), Some((rows, encoder))))
+    }))
 }
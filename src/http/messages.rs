@@ -1,19 +1,37 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
 
-use crate::experiment::Experiment;
+use crate::analysis::hemodynamics::Hemodynamics;
+use crate::analysis::stats::ChannelStats;
+use crate::auth::Role;
+use crate::control::clock::McuClockStatus;
+use crate::experiment::listing::ExperimentFromDB;
+use crate::experiment::search::{ExperimentSort, SortOrder};
+use crate::experiment::{Experiment, ExperimentStartMessage, annotations::AnnotationMessage};
+use crate::export::ExportFormat;
+use crate::http::error::ApiErrorBody;
+use crate::messages::frontend_messages::{FrontendHeartControllerSetpoint, MockloopSetpoint};
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct HeartbeatMessage {
     status: &'static str,
     timestamp: DateTime<Utc>,
+    started_at: DateTime<Utc>,
+    mcu_clock: McuClockStatus,
 }
 
 impl HeartbeatMessage {
-    pub fn new() -> Self {
+    pub fn new(started_at: DateTime<Utc>, mcu_clock: McuClockStatus) -> Self {
         Self {
             status: "alive",
             timestamp: Utc::now(),
+            started_at,
+            mcu_clock,
         }
     }
 }
@@ -31,18 +49,288 @@ impl ExperimentList {
 }
 
 /// Response format for listing experiments from the database
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct ExperimentListFromDB {
     pub experiments: Vec<ExperimentFromDB>,
+    /// Number of experiments matching the filters, across all pages
+    #[serde(default)]
+    pub total: usize,
+}
+
+/// Response format for listing the setpoint events of an experiment
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct SetpointEventList {
+    pub events: Vec<SetpointEventFromDB>,
 }
 
-/// Individual experiment details from the database
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ExperimentFromDB {
-    pub table_name: String,
-    pub experiment_id: String,
-    pub experiment_name: String,
-    pub description: String,
-    pub start_time: Option<String>,
+/// Individual setpoint event from the database
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct SetpointEventFromDB {
+    pub time: String,
+    pub source: String,
+    pub client: String,
+    /// User that changed the setpoint, empty for events recorded before users were introduced
+    #[serde(default)]
+    pub user: String,
+    pub old: serde_json::Value,
+    pub new: serde_json::Value,
+}
+
+/// Individual annotation from the database
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct AnnotationFromDB {
+    pub time: String,
+    pub category: String,
+    pub text: String,
+}
+
+/// Individual data gap from the database
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct DataGapFromDB {
+    pub start: String,
+    pub end: String,
     pub duration_seconds: f64,
+    pub cause: String,
+}
+
+/// Response format for the experiment detail endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ExperimentDetail {
+    #[serde(flatten)]
+    pub experiment: ExperimentFromDB,
+    pub annotations: Vec<AnnotationFromDB>,
+}
+
+/// Encoding of the measurements streamed over the websocket
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WsEncoding {
+    /// Reports as JSON text messages
+    #[default]
+    Json,
+    /// Reports as binary frames, laid out as described by `/ws/schema`
+    Binary,
+}
+
+/// Query parameters of the websocket, chosen once per connection
+#[derive(Deserialize, Debug, Clone, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WsConnectQuery {
+    #[serde(default)]
+    pub encoding: WsEncoding,
+    /// Replay the reports of this many seconds before streaming new ones
+    #[serde(default)]
+    pub history_seconds: Option<u64>,
+}
+
+/// Commands the frontend may send over the websocket
+/// A command may carry an `id` of any JSON type, which is echoed in the reply to it
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsClientMessage {
+    /// Attach a marker to the running experiment
+    Annotate(AnnotationMessage),
+    /// Same as POST /control/loop
+    SetLoopSetpoint(MockloopSetpoint),
+    /// Same as POST /control/heart
+    SetHeartSetpoint(FrontendHeartControllerSetpoint),
+    /// Same as POST /experiment/start
+    StartExperiment(ExperimentStartMessage),
+    /// Same as POST /experiment/stop
+    StopExperiment,
+    /// Same as POST /experiment/pause
+    PauseExperiment,
+    /// Same as POST /experiment/resume
+    ResumeExperiment,
+    /// Start streaming measurements to this client, or change how they are streamed
+    /// Clients are subscribed to every report field at 10 Hz when they connect
+    Subscribe(WsSubscribeOptions),
+    /// Stop streaming measurements to this client
+    Unsubscribe,
+    /// Same as POST /control/lease, the lease is held by this socket until it closes
+    AcquireControl(LeaseOptions),
+    /// Same as DELETE /control/lease for the lease held by this socket
+    ReleaseControl,
+}
+
+impl WsClientMessage {
+    /// Role needed to send this command, the same as for the matching endpoint
+    pub fn required_role(&self) -> Role {
+        match self {
+            WsClientMessage::Subscribe(_) | WsClientMessage::Unsubscribe => Role::Viewer,
+            _ => Role::Operator,
+        }
+    }
+
+    /// Does this command need the control lease, like the matching endpoint?
+    pub fn requires_control(&self) -> bool {
+        matches!(
+            self,
+            WsClientMessage::SetLoopSetpoint(_)
+                | WsClientMessage::SetHeartSetpoint(_)
+                | WsClientMessage::StartExperiment(_)
+                | WsClientMessage::StopExperiment
+                | WsClientMessage::PauseExperiment
+                | WsClientMessage::ResumeExperiment
+        )
+    }
+}
+
+/// Options of a websocket measurement subscription
+#[derive(Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(default)]
+pub struct WsSubscribeOptions {
+    /// Messages per second, defaults to 10 and may be at most 100, the MCU report rate
+    pub rate_hz: Option<f64>,
+    /// Report fields to send, defaults to all of them. `time` is always sent
+    pub channels: Option<Vec<String>>,
+    /// Send every report received since the previous message, instead of only the latest one
+    pub full_rate: bool,
+}
+
+/// Messages sent over the websocket apart from single measurement reports
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsServerMessage {
+    /// The command was applied, `result` holds what the matching POST endpoint returns
+    Ack {
+        id: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+    },
+    /// The command was rejected, `status` is the status code the matching POST endpoint returns
+    Error {
+        id: Option<Value>,
+        status: u16,
+        #[serde(flatten)]
+        error: ApiErrorBody,
+    },
+    /// Every report received since the previous message, sent in full-rate mode
+    Reports { reports: Vec<Value> },
+    /// Reports received before the client connected, sent once when requested on connect
+    History { reports: Vec<Value> },
+}
+
+/// Query parameters of the measurement history endpoint
+#[derive(Deserialize, Debug, Clone, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// Number of seconds of reports to return, at most 300, defaults to 30
+    pub seconds: Option<u64>,
+}
+
+/// Query parameters of the experiment data endpoint
+#[derive(Deserialize, Debug, Clone, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExperimentDataQuery {
+    /// Start of the requested range, defaults to the first sample
+    pub from: Option<DateTime<Utc>>,
+    /// End of the requested range, defaults to the last sample
+    pub to: Option<DateTime<Utc>>,
+    /// Comma separated list of columns, defaults to the sensor channels
+    pub columns: Option<String>,
+    /// Target number of points the range is downsampled to
+    pub points: Option<u32>,
+}
+
+/// Downsampled experiment data, one entry per bucket in every vector
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ExperimentData {
+    pub experiment_id: Uuid,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub bucket_ms: i64,
+    /// Start time of every bucket
+    pub time: Vec<String>,
+    pub channels: BTreeMap<String, ChannelBuckets>,
+}
+
+/// Aggregated values of a single channel, null for buckets without samples
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+pub struct ChannelBuckets {
+    pub min: Vec<Option<f64>>,
+    pub max: Vec<Option<f64>>,
+    pub mean: Vec<Option<f64>>,
+}
+
+/// Query parameters of the experiment download endpoint
+#[derive(Deserialize, Debug, Clone, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    /// File format, CSV if absent
+    pub format: Option<ExportFormat>,
+}
+
+/// Query parameters of the experiment list endpoint, all filters are optional
+#[derive(Deserialize, Debug, Clone, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExperimentListQuery {
+    /// Comma separated tags, an experiment has to carry all of them
+    pub tags: Option<String>,
+    /// Case insensitive text searched for in the name and description
+    pub search: Option<String>,
+    /// Only experiments started at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only experiments started at or before this time
+    pub to: Option<DateTime<Utc>>,
+    /// Only experiments that ran for at least this long
+    pub min_duration_seconds: Option<f64>,
+    /// Field to sort by, the start time if absent
+    pub sort: Option<ExperimentSort>,
+    /// Sort direction, newest first if absent
+    pub order: Option<SortOrder>,
+    /// Number of matching experiments to skip
+    pub offset: Option<usize>,
+    /// Maximum number of experiments to return, all if absent
+    pub limit: Option<usize>,
+}
+
+/// Query parameters of the experiment comparison endpoint
+#[derive(Deserialize, Debug, Clone, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExperimentCompareQuery {
+    /// Comma separated experiment ids, the first one is the baseline
+    pub ids: String,
+    /// Comma separated list of columns, defaults to the sensor channels
+    pub columns: Option<String>,
+}
+
+/// Aligned statistics of two or more experiments
+/// Every vector holds one entry per experiment, in the order they were requested
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ExperimentComparison {
+    pub experiments: Vec<ExperimentFromDB>,
+    pub channels: BTreeMap<String, Vec<ChannelStats>>,
+    /// Difference of every channel mean to the mean of the baseline
+    pub mean_difference: BTreeMap<String, Vec<Option<f64>>>,
+    pub hemodynamics: Vec<Hemodynamics>,
+    /// Statistics per protocol step, only present if all experiments ran the same protocol
+    pub steps: Option<Vec<StepComparison>>,
+}
+
+/// Aligned statistics of a single protocol step
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct StepComparison {
+    pub index: usize,
+    /// Complete `FrontendSetpoint` during this step
+    pub setpoint: serde_json::Value,
+    pub duration_seconds: Vec<f64>,
+    pub channels: BTreeMap<String, Vec<ChannelStats>>,
+}
+
+/// Query parameters of the experiment archive endpoint
+#[derive(Deserialize, Debug, Clone, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ArchiveQuery {
+    /// Comma separated experiment ids, all experiments if absent
+    pub ids: Option<String>,
+}
+
+/// Options of a control lease request, sent as query parameters or with the websocket command
+#[derive(Deserialize, Debug, Clone, Default, IntoParams, ToSchema)]
+#[into_params(parameter_in = Query)]
+#[serde(default)]
+pub struct LeaseOptions {
+    /// Take the lease from the client holding it, only allowed to admins
+    pub takeover: bool,
 }
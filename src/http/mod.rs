@@ -1,6 +1,13 @@
+pub mod auth;
+pub mod binary;
+pub mod delete;
+pub mod error;
 pub mod get;
 pub mod messages;
+pub mod openapi;
+pub mod patch;
 pub mod post;
+pub mod sse;
 pub mod ws;
 
 pub const CONVEX_URI: &str = "http://192.168.0.4:5173";
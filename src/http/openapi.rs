@@ -0,0 +1,85 @@
+use axum::Json;
+use axum::Router;
+use axum::routing::get;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_scalar::{Scalar, Servable};
+
+use crate::axumstate::AxumState;
+use crate::experiment::search::{ExperimentSort, SortOrder};
+use crate::export::ExportFormat;
+use crate::http::messages::{WsClientMessage, WsEncoding, WsServerMessage};
+use crate::messages::system_events::SystemEventMessage;
+
+/// Path the OpenAPI document is served at
+pub const OPENAPI_PATH: &str = "/openapi.json";
+/// Path the API explorer is served at
+pub const EXPLORER_PATH: &str = "/docs";
+
+/// Parts of the OpenAPI document that are not collected from the routes
+/// The websocket is routed for any method, so it is listed here instead of through `routes!`.
+/// Its messages, and the enums used in query parameters, are not referenced by any route and are
+/// listed as schemas
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Loop-Sense",
+        description = "Backend of the Holland Hybrid Heart mockloop. Errors are returned as `ApiErrorBody`. \
+            Every endpoint without a security requirement of its own needs a token, and returns 401 without a valid one."
+    ),
+    modifiers(&TokenSecurity),
+    security(("bearer" = [])),
+    paths(crate::http::ws::handle_websocket_request),
+    components(schemas(
+        WsClientMessage,
+        WsServerMessage,
+        SystemEventMessage,
+        WsEncoding,
+        ExportFormat,
+        ExperimentSort,
+        SortOrder
+    )),
+    tags(
+        (name = "measurements", description = "Live reports of the MCU"),
+        (name = "control", description = "Setpoints of the mockloop and heart controllers"),
+        (name = "experiments", description = "Running and recorded experiments"),
+        (name = "system", description = "State of the service"),
+        (name = "auth", description = "Users and their tokens"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Token security schemes, which cannot be declared through the `OpenApi` derive
+struct TokenSecurity;
+
+impl Modify for TokenSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        // Only accepted by the websocket, as browsers cannot set headers on it
+        components.add_security_scheme(
+            "access_token",
+            SecurityScheme::ApiKey(ApiKey::Query(ApiKeyValue::new("access_token"))),
+        );
+    }
+}
+
+/// Turn the documented routes into the app router, serving their OpenAPI document and an
+/// explorer for it next to them
+pub fn with_api_docs(router: OpenApiRouter<AxumState>) -> Router<AxumState> {
+    let (router, api) = router.split_for_parts();
+    let document = api.clone();
+    router
+        .route(
+            OPENAPI_PATH,
+            get(move || {
+                let document = document.clone();
+                async move { Json(document) }
+            }),
+        )
+        .merge(Scalar::with_url(EXPLORER_PATH, api))
+}
@@ -0,0 +1,96 @@
+use crate::axumstate::AxumState;
+use crate::experiment::ExperimentCommand;
+use crate::experiment::catalogue::{
+    CatalogueEntry, ExperimentEdit, load_catalogue_entry, store_catalogue_entry,
+};
+use crate::experiment::naming::slugify;
+use crate::http::auth::{Operator, check_control_lease};
+use crate::http::error::{ApiError, ApiErrorBody, ApiJson, ApiPath};
+use crate::http::post::ensure_unique_experiment_name;
+use axum::Json;
+use axum::http::HeaderMap;
+use chrono::Utc;
+use tracing::*;
+use uuid::Uuid;
+
+/// PATCH request handler to change the name, description or tags of an experiment
+/// Earlier data keeps the metadata it was recorded with, the catalogue takes precedence over it.
+/// Data recorded from now on by a running experiment is tagged with the new metadata, so editing
+/// the running experiment needs the control lease
+#[utoipa::path(
+    patch,
+    path = "/experiment/{id}",
+    tag = "experiments",
+    params(
+        ("id" = Uuid, Path, description = "Experiment id"),
+        ("X-Control-Lease" = Option<String>, Header, description = "Token of the control lease, required if the experiment is running"),
+    ),
+    request_body = ExperimentEdit,
+    responses(
+        (status = 200, description = "Updated catalogue entry", body = CatalogueEntry),
+        (status = 400, description = "Invalid name or tags", body = ApiErrorBody),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 404, description = "Experiment not found", body = ApiErrorBody),
+        (status = 409, description = "Experiment is in the trash, or its name is taken", body = ApiErrorBody),
+        (status = 423, description = "Experiment is running and the control lease was not sent", body = ApiErrorBody),
+        (status = 500, description = "Internal error", body = ApiErrorBody),
+        (status = 503, description = "Database or background task unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn patch_experiment(
+    state: axum::extract::State<AxumState>,
+    _operator: Operator,
+    headers: HeaderMap,
+    ApiPath(experiment_id): ApiPath<Uuid>,
+    ApiJson(mut edit): ApiJson<ExperimentEdit>,
+) -> Result<Json<CatalogueEntry>, ApiError> {
+    if let Err(err) = edit.validate() {
+        warn!("Rejecting edit of experiment {experiment_id}: {err}");
+        return Err(err.into());
+    }
+
+    let (mut entry, is_running) = load_catalogue_entry(&state, experiment_id).await?;
+    if entry.deleted_at.is_some() {
+        warn!("Rejecting edit of experiment {experiment_id}, which is in the trash");
+        return Err(ApiError::Conflict(String::from(
+            "restore the experiment before editing it",
+        )));
+    }
+    if is_running {
+        check_control_lease(&state, &headers).inspect_err(|err| {
+            warn!("Rejecting edit of the running experiment {experiment_id}: {err}");
+        })?;
+    }
+
+    // Renaming an experiment to a variant of its own name is fine
+    if let Some(ref name) = edit.name
+        && slugify(name) != slugify(&entry.name)
+    {
+        ensure_unique_experiment_name(&state, name, Some(experiment_id)).await?;
+    }
+
+    edit.apply(&mut entry);
+    entry.time = Utc::now();
+    store_catalogue_entry(&state, entry.clone()).await?;
+
+    // Send the stored metadata rather than the partial edit, so the running experiment ends up
+    // matching the catalogue whatever order concurrent edits are queued in
+    let stored = ExperimentEdit {
+        name: Some(entry.name.clone()),
+        description: Some(entry.description.clone()),
+        tags: Some(entry.tags.clone()),
+    };
+    if is_running
+        && let Err(err) = state
+            .experiment_commands
+            .send(ExperimentCommand::Edit(experiment_id, stored))
+            .await
+    {
+        error!("Unable to edit the running experiment: {err}");
+        return Err(ApiError::TaskUnavailable("experiment manager"));
+    }
+
+    info!("Edited experiment {experiment_id}: {:?}", entry);
+    Ok(Json(entry))
+}
@@ -1,81 +1,633 @@
+use crate::auth::{Role, User};
 use crate::axumstate::AxumState;
-use crate::experiment::{self};
+use crate::control::lease::{LeaseChange, LeaseGrant};
+use crate::experiment::annotations::{Annotation, AnnotationMessage, annotate_running_experiment};
+use crate::experiment::catalogue::{CatalogueEntry, load_catalogue_entry, store_catalogue_entry};
+use crate::experiment::events::{SetpointEvent, SetpointSource};
+use crate::experiment::listing::query_experiments_from_influxdb;
+use crate::experiment::naming::{ExperimentNameError, slugify};
+use crate::experiment::{self, ExperimentCommand};
+use crate::export::archive::{ImportError, ImportSummary, import_archive};
+use crate::http::auth::{Controller, Operator, lease_token};
+use crate::http::error::{ApiError, ApiErrorBody, ApiJson, ApiPath, ApiQuery};
+use crate::http::messages::LeaseOptions;
+use crate::messages::db_messages::DatabaseMessage;
 use crate::messages::frontend_messages::{
-    FrontendHeartControllerSetpoint, HeartControllerSetpoint, MockloopSetpoint,
+    FrontendHeartControllerSetpoint, FrontendSetpoint, HeartControllerSetpoint, MockloopSetpoint,
 };
+use crate::messages::system_events::{SystemEvent, publish_event};
 use axum::Json;
-use axum::http::StatusCode;
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderMap, StatusCode};
+use chrono::Utc;
+use std::net::SocketAddr;
 use tracing::*;
+use uuid::Uuid;
+
+/// POST request handler to acquire the control lease, or renew the lease sent along
+/// Only the client holding the lease may change the setpoints and run experiments
+#[utoipa::path(
+    post,
+    path = "/control/lease",
+    tag = "control",
+    params(
+        LeaseOptions,
+        ("X-Control-Lease" = Option<String>, Header, description = "Token of the lease to renew"),
+    ),
+    responses(
+        (status = 200, description = "Lease held by this client", body = LeaseGrant),
+        (status = 403, description = "Not an operator, or a takeover by a non-admin", body = ApiErrorBody),
+        (status = 423, description = "The lease is held by another client", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn post_control_lease(
+    state: axum::extract::State<AxumState>,
+    Operator(user): Operator,
+    headers: HeaderMap,
+    ApiQuery(options): ApiQuery<LeaseOptions>,
+) -> Result<Json<LeaseGrant>, ApiError> {
+    acquire_control(&state, &user, lease_token(&headers), options).map(Json)
+}
+
+/// Acquire or renew the control lease, announcing a change of holder to every client
+/// Shared by the POST /control/lease handler and the websocket
+pub(crate) fn acquire_control(
+    state: &AxumState,
+    user: &User,
+    token: Option<&str>,
+    options: LeaseOptions,
+) -> Result<LeaseGrant, ApiError> {
+    if options.takeover {
+        user.require(Role::Admin)?;
+    }
+
+    let (grant, change, previous_owner) = match state.control_lease.lock() {
+        Ok(mut lease) => {
+            let previous_owner = lease.status().owner;
+            let (grant, change) = lease.acquire(&user.name, token, options.takeover)?;
+            (grant, change, previous_owner)
+        }
+        Err(_) => {
+            error!("Unable to acquire the control lease");
+            return Err(ApiError::StateUnavailable("control lease"));
+        }
+    };
+
+    if let Some(change) = change {
+        match change {
+            LeaseChange::TakenOver => info!(
+                "{} took over the control lease of {}",
+                user.name,
+                previous_owner.as_deref().unwrap_or_default()
+            ),
+            _ => info!("{} acquired the control lease", user.name),
+        }
+        publish_event(
+            &state.event_broadcast,
+            SystemEvent::ControlLeaseChanged {
+                change,
+                owner: Some(grant.owner.clone()),
+                previous_owner,
+            },
+        );
+    }
+    Ok(grant)
+}
 
 /// POST request handler to update the mockloop setpoints (hemodynamic resistance/compliance)
+#[utoipa::path(
+    post,
+    path = "/control/loop",
+    tag = "control",
+    params(("X-Control-Lease" = String, Header, description = "Token of the control lease")),
+    request_body = MockloopSetpoint,
+    responses(
+        (status = 200, description = "Setpoint sent to the MCU"),
+        (status = 400, description = "Invalid setpoint", body = ApiErrorBody),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 423, description = "Control lease not held", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+        (status = 503, description = "Background task not running", body = ApiErrorBody),
+    )
+)]
 #[axum::debug_handler]
 pub async fn post_loop_setpoint(
     state: axum::extract::State<AxumState>,
-    Json(new_setpoint): Json<MockloopSetpoint>,
-) -> StatusCode {
+    Controller(user): Controller,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    ApiJson(new_setpoint): ApiJson<MockloopSetpoint>,
+) -> Result<StatusCode, ApiError> {
+    update_loop_setpoint(
+        &state,
+        SetpointSource::LoopEndpoint,
+        client,
+        &user,
+        new_setpoint,
+    )
+    .await?;
+    Ok(StatusCode::OK)
+}
+
+/// POST request handler to update the mockloop setpoints (hemodynamic resistance/compliance)
+#[utoipa::path(
+    post,
+    path = "/control/heart",
+    tag = "control",
+    params(("X-Control-Lease" = String, Header, description = "Token of the control lease")),
+    request_body = FrontendHeartControllerSetpoint,
+    responses(
+        (status = 200, description = "Setpoint sent to the MCU"),
+        (status = 400, description = "Invalid setpoint", body = ApiErrorBody),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 423, description = "Control lease not held", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+        (status = 503, description = "Background task not running", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn post_heart_setpoint(
+    state: axum::extract::State<AxumState>,
+    Controller(user): Controller,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    ApiJson(new_setpoint): ApiJson<FrontendHeartControllerSetpoint>,
+) -> Result<StatusCode, ApiError> {
+    update_heart_setpoint(
+        &state,
+        SetpointSource::HeartEndpoint,
+        client,
+        &user,
+        new_setpoint,
+    )
+    .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Replace the mockloop setpoint and record the change
+/// Shared by the POST /control/loop handler and the websocket
+pub(crate) async fn update_loop_setpoint(
+    state: &AxumState,
+    source: SetpointSource,
+    client: SocketAddr,
+    user: &User,
+    new_setpoint: MockloopSetpoint,
+) -> Result<(), ApiError> {
+    if let Err(errors) = new_setpoint.validate() {
+        warn!("Rejecting mockloop controller setpoint: {:?}", errors);
+        return Err(errors.into());
+    }
+
     // Attempt to lock mutex guarding the latest setpoint
-    if let Ok(mut setpoint) = state.setpoint.lock() {
+    let change = if let Ok(mut setpoint) = state.setpoint.lock() {
         // Update the latest setpoint to the one received
         info!(
-            "POST updated mockloop controller setpoint to: {:?}",
+            "{} ({}) updated mockloop controller setpoint to: {:?}",
+            user.name,
+            source.as_str(),
             new_setpoint
         );
 
+        let old = setpoint.clone();
         setpoint.mockloop_setpoint = new_setpoint;
-        return StatusCode::OK;
+        Some((old, setpoint.clone()))
+    } else {
+        None
+    };
+
+    if let Some((old, new)) = change {
+        record_setpoint_event(state, source, client, user, old, new).await;
+        return Ok(());
     }
+
     // Unable to lock mutex, or mutex was poisoned
     error!(
-        "unable to update mockloop controller setpoint in post_loop_setpoint, mutex poisoned or unable to lock - Returning INTERNAL_SERVER_ERROR"
+        "unable to update mockloop controller setpoint in update_loop_setpoint, mutex poisoned or unable to lock"
     );
-    StatusCode::INTERNAL_SERVER_ERROR
+    Err(ApiError::StateUnavailable("setpoint"))
 }
 
-/// POST request handler to update the mockloop setpoints (hemodynamic resistance/compliance)
-#[axum::debug_handler]
-pub async fn post_heart_setpoint(
-    state: axum::extract::State<AxumState>,
-    Json(new_setpoint): Json<FrontendHeartControllerSetpoint>,
-) -> StatusCode {
+/// Replace the heart controller setpoint and record the change
+/// Shared by the POST /control/heart handler and the websocket
+pub(crate) async fn update_heart_setpoint(
+    state: &AxumState,
+    source: SetpointSource,
+    client: SocketAddr,
+    user: &User,
+    new_setpoint: FrontendHeartControllerSetpoint,
+) -> Result<(), ApiError> {
+    if let Err(errors) = new_setpoint.validate() {
+        warn!("Rejecting heart controller setpoint: {:?}", errors);
+        return Err(errors.into());
+    }
+
     // Attempt to lock mutex guarding the latest setpoint
-    if let Ok(mut setpoint) = state.setpoint.lock() {
+    let change = if let Ok(mut setpoint) = state.setpoint.lock() {
         // Update the latest setpoint to the one received
         info!(
-            "POST updated heart controller setpoint to: {:?}",
+            "{} ({}) updated heart controller setpoint to: {:?}",
+            user.name,
+            source.as_str(),
             &new_setpoint
         );
 
+        let old = setpoint.clone();
         setpoint.heart_controller_setpoint = new_setpoint.into();
-        return StatusCode::OK;
+        Some((old, setpoint.clone()))
+    } else {
+        None
+    };
+
+    if let Some((old, new)) = change {
+        record_setpoint_event(state, source, client, user, old, new).await;
+        return Ok(());
     }
+
     // Unable to lock mutex, or mutex was poisoned
     error!(
-        "unable to update heart controller setpoint in post_heart_setpoint, mutex poisoned or unable to lock - Returning INTERNAL_SERVER_ERROR"
+        "unable to update heart controller setpoint in update_heart_setpoint, mutex poisoned or unable to lock"
     );
-    StatusCode::INTERNAL_SERVER_ERROR
+    Err(ApiError::StateUnavailable("setpoint"))
 }
 
+/// POST request handler to start a new experiment
+#[utoipa::path(
+    post,
+    path = "/experiment/start",
+    tag = "experiments",
+    params(("X-Control-Lease" = String, Header, description = "Token of the control lease")),
+    request_body = experiment::ExperimentStartMessage,
+    responses(
+        (status = 200, description = "Experiment started"),
+        (status = 400, description = "Invalid name, tags or backfill", body = ApiErrorBody),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 409, description = "Name is taken", body = ApiErrorBody),
+        (status = 423, description = "Control lease not held", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+        (status = 503, description = "Database or background task unavailable", body = ApiErrorBody),
+    )
+)]
 #[axum::debug_handler]
 pub async fn post_start_experiment(
     state: axum::extract::State<AxumState>,
-    Json(start_message): Json<experiment::ExperimentStartMessage>,
-) -> StatusCode {
-    if let Err(err) = state.experiment_watch.send(Some(start_message.clone())) {
+    Controller(user): Controller,
+    ApiJson(start_message): ApiJson<experiment::ExperimentStartMessage>,
+) -> Result<StatusCode, ApiError> {
+    start_experiment(&state, &user, start_message).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Validate a start request and ask the experiment manager to start the experiment
+/// Shared by the POST /experiment/start handler and the websocket
+pub(crate) async fn start_experiment(
+    state: &AxumState,
+    user: &User,
+    mut start_message: experiment::ExperimentStartMessage,
+) -> Result<(), ApiError> {
+    // Reject invalid and duplicate names before anything is logged under them
+    if let Err(err) = start_message.validate() {
+        warn!("Rejecting experiment start: {err}");
+        return Err(err.into());
+    }
+    ensure_unique_experiment_name(state, start_message.name(), None).await?;
+
+    if let Err(err) = state
+        .experiment_commands
+        .send(ExperimentCommand::Start(
+            start_message.clone(),
+            user.name.clone(),
+        ))
+        .await
+    {
         error!("Unable to start a new experiment: {err}");
-        StatusCode::INTERNAL_SERVER_ERROR
+        Err(ApiError::TaskUnavailable("experiment manager"))
     } else {
-        info!("Started a new experiment: {:?}", start_message);
-        StatusCode::OK
+        info!(
+            "{} started a new experiment: {:?}",
+            user.name, start_message
+        );
+        Ok(())
     }
 }
 
+/// Check that no running or recorded experiment, other than `exclude`, has a name with the same
+/// slug. Experiments in the trash count as well, so they can always be restored
+pub(crate) async fn ensure_unique_experiment_name(
+    state: &AxumState,
+    name: &str,
+    exclude: Option<Uuid>,
+) -> Result<(), ApiError> {
+    let slug = slugify(name);
+    let duplicate = || {
+        let err = ExperimentNameError::Duplicate(name.to_string());
+        warn!("Rejecting experiment name: {err}");
+        Err(ApiError::Conflict(err.to_string()))
+    };
+
+    let running_name = match state.current_experiment.lock() {
+        Ok(experiment) => experiment
+            .as_ref()
+            .filter(|e| Some(e.id) != exclude)
+            .map(|e| e.name.clone()),
+        Err(_) => {
+            error!("Unable to fetch the current experiment");
+            return Err(ApiError::StateUnavailable("current experiment"));
+        }
+    };
+    if running_name.is_some_and(|running| slugify(&running) == slug) {
+        return duplicate();
+    }
+
+    match query_experiments_from_influxdb().await {
+        Ok(experiments)
+            if experiments.iter().any(|e| {
+                Uuid::parse_str(&e.experiment_id).ok() != exclude
+                    && slugify(&e.experiment_name) == slug
+            }) =>
+        {
+            duplicate()
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to retrieve experiments from InfluxDB: {}", e);
+            Err(ApiError::Database(format!(
+                "unable to verify that the experiment name is unique: {e}"
+            )))
+        }
+    }
+}
+
+/// POST request handler to stop the running experiment
+#[utoipa::path(
+    post,
+    path = "/experiment/stop",
+    tag = "experiments",
+    params(("X-Control-Lease" = String, Header, description = "Token of the control lease")),
+    responses(
+        (status = 200, description = "Experiment stopped"),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 423, description = "Control lease not held", body = ApiErrorBody),
+        (status = 503, description = "Background task not running", body = ApiErrorBody),
+    )
+)]
 #[axum::debug_handler]
-pub async fn post_stop_experiment(state: axum::extract::State<AxumState>) -> StatusCode {
-    if let Err(err) = state.experiment_watch.send(None) {
+pub async fn post_stop_experiment(
+    state: axum::extract::State<AxumState>,
+    _controller: Controller,
+) -> Result<StatusCode, ApiError> {
+    stop_experiment(&state).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Ask the experiment manager to stop the running experiment
+/// Shared by the POST /experiment/stop handler and the websocket
+pub(crate) async fn stop_experiment(state: &AxumState) -> Result<(), ApiError> {
+    if let Err(err) = state
+        .experiment_commands
+        .send(ExperimentCommand::Stop)
+        .await
+    {
         error!("Unable to stop current experiment: {err}");
-        StatusCode::INTERNAL_SERVER_ERROR
+        Err(ApiError::TaskUnavailable("experiment manager"))
     } else {
         info!("Stopped experiment");
-        StatusCode::OK
+        Ok(())
     }
 }
+
+/// POST request handler to pause DB logging without ending the running experiment
+#[utoipa::path(
+    post,
+    path = "/experiment/pause",
+    tag = "experiments",
+    params(("X-Control-Lease" = String, Header, description = "Token of the control lease")),
+    responses(
+        (status = 200, description = "Logging paused"),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 409, description = "No experiment running, or already paused", body = ApiErrorBody),
+        (status = 423, description = "Control lease not held", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+        (status = 503, description = "Background task not running", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn post_pause_experiment(
+    state: axum::extract::State<AxumState>,
+    _controller: Controller,
+) -> Result<StatusCode, ApiError> {
+    change_experiment_pause(&state, ExperimentCommand::Pause, false).await?;
+    Ok(StatusCode::OK)
+}
+
+/// POST request handler to resume DB logging of a paused experiment
+#[utoipa::path(
+    post,
+    path = "/experiment/resume",
+    tag = "experiments",
+    params(("X-Control-Lease" = String, Header, description = "Token of the control lease")),
+    responses(
+        (status = 200, description = "Logging resumed"),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 409, description = "No experiment running, or not paused", body = ApiErrorBody),
+        (status = 423, description = "Control lease not held", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+        (status = 503, description = "Background task not running", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn post_resume_experiment(
+    state: axum::extract::State<AxumState>,
+    _controller: Controller,
+) -> Result<StatusCode, ApiError> {
+    change_experiment_pause(&state, ExperimentCommand::Resume, true).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Forward a pause/resume command to the experiment manager if the running experiment is
+/// currently in the expected pause state
+pub(crate) async fn change_experiment_pause(
+    state: &AxumState,
+    command: ExperimentCommand,
+    expect_paused: bool,
+) -> Result<(), ApiError> {
+    match state.current_experiment.lock() {
+        Ok(experiment) => match *experiment {
+            Some(ref exp) if exp.is_running && exp.is_paused == expect_paused => {}
+            _ => {
+                warn!("Unable to {:?} experiment in its current state", command);
+                let message = if expect_paused {
+                    "no paused experiment is running"
+                } else {
+                    "no unpaused experiment is running"
+                };
+                return Err(ApiError::Conflict(String::from(message)));
+            }
+        },
+        Err(_) => {
+            error!("Unable to fetch the current experiment");
+            return Err(ApiError::StateUnavailable("current experiment"));
+        }
+    }
+
+    if let Err(err) = state.experiment_commands.send(command).await {
+        error!("Unable to change experiment pause state: {err}");
+        Err(ApiError::TaskUnavailable("experiment manager"))
+    } else {
+        info!("Changed experiment pause state");
+        Ok(())
+    }
+}
+
+/// POST request handler to attach a timestamped marker to the running experiment
+#[utoipa::path(
+    post,
+    path = "/experiment/annotate",
+    tag = "experiments",
+    request_body = AnnotationMessage,
+    responses(
+        (status = 200, description = "Annotation attached", body = Annotation),
+        (status = 400, description = "Empty text, or time outside of the experiment", body = ApiErrorBody),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 409, description = "No experiment running", body = ApiErrorBody),
+        (status = 500, description = "Shared state unavailable", body = ApiErrorBody),
+        (status = 503, description = "Background task not running", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn post_annotate_experiment(
+    state: axum::extract::State<AxumState>,
+    _operator: Operator,
+    ApiJson(message): ApiJson<AnnotationMessage>,
+) -> Result<Json<Annotation>, ApiError> {
+    annotate_running_experiment(&state, message)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+/// Forward an accepted setpoint change to the DB task, tagged with the running experiment
+async fn record_setpoint_event(
+    state: &AxumState,
+    source: SetpointSource,
+    client: SocketAddr,
+    user: &User,
+    old: FrontendSetpoint,
+    new: FrontendSetpoint,
+) {
+    let experiment_id = match state.current_experiment.lock() {
+        Ok(experiment) => experiment.as_ref().map(|e| e.id).unwrap_or_default(),
+        Err(_) => {
+            error!("Unable to fetch the current experiment while recording setpoint event");
+            Default::default()
+        }
+    };
+
+    let event = SetpointEvent {
+        time: Utc::now(),
+        experiment_id,
+        source,
+        client: Some(client.to_string()),
+        user: Some(user.name.clone()),
+        old,
+        new,
+    };
+    publish_event(
+        &state.event_broadcast,
+        SystemEvent::SetpointChanged {
+            source,
+            client: event.client.clone(),
+            user: event.user.clone(),
+            setpoint: event.new.clone(),
+        },
+    );
+
+    if let Err(err) = state
+        .db_sender
+        .send(DatabaseMessage::SetpointEvent(event))
+        .await
+    {
+        error!("Unable to send setpoint event to database task: {err}");
+    }
+}
+
+/// Recreate the experiments in an archive made by the archive download endpoint, under their
+/// original ids. Experiments which already exist are skipped and reported as duplicates
+#[utoipa::path(
+    post,
+    path = "/experiment/import",
+    tag = "experiments",
+    request_body(
+        description = "Archive made by GET /experiment/archive",
+        content_type = "application/gzip",
+    ),
+    responses(
+        (status = 200, description = "Outcome of the import", body = ImportSummary),
+        (status = 400, description = "Invalid archive", body = ApiErrorBody),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn post_import_experiments(
+    _state: axum::extract::State<AxumState>,
+    _operator: Operator,
+    body: Body,
+) -> Result<Json<ImportSummary>, ApiError> {
+    match import_archive(body).await {
+        Ok(summary) => {
+            info!(
+                "Imported {} experiments ({} rows), skipped {} duplicates",
+                summary.imported.len(),
+                summary.rows,
+                summary.duplicates.len()
+            );
+            Ok(Json(summary))
+        }
+        Err(err @ ImportError::InvalidArchive(_)) => {
+            warn!("Rejected experiment import: {err}");
+            Err(err.into())
+        }
+        Err(err @ ImportError::Database(_)) => {
+            error!("Experiment import failed: {err}");
+            Err(err.into())
+        }
+    }
+}
+
+/// POST request handler to take an experiment out of the trash
+#[utoipa::path(
+    post,
+    path = "/experiment/{id}/restore",
+    tag = "experiments",
+    params(("id" = Uuid, Path, description = "Experiment id")),
+    responses(
+        (status = 200, description = "Restored catalogue entry", body = CatalogueEntry),
+        (status = 403, description = "Not an operator", body = ApiErrorBody),
+        (status = 404, description = "Experiment not found", body = ApiErrorBody),
+        (status = 409, description = "Experiment is not in the trash", body = ApiErrorBody),
+        (status = 500, description = "Internal error", body = ApiErrorBody),
+        (status = 503, description = "Database unavailable", body = ApiErrorBody),
+    )
+)]
+#[axum::debug_handler]
+pub async fn post_restore_experiment(
+    state: axum::extract::State<AxumState>,
+    _operator: Operator,
+    ApiPath(experiment_id): ApiPath<Uuid>,
+) -> Result<Json<CatalogueEntry>, ApiError> {
+    let (mut entry, _) = load_catalogue_entry(&state, experiment_id).await?;
+    if entry.deleted_at.is_none() {
+        warn!("Rejecting restore of experiment {experiment_id}, which is not in the trash");
+        return Err(ApiError::Conflict(String::from(
+            "experiment is not in the trash",
+        )));
+    }
+
+    entry.deleted_at = None;
+    entry.time = Utc::now();
+    store_catalogue_entry(&state, entry.clone()).await?;
+
+    info!("Restored experiment {experiment_id} from the trash");
+    Ok(Json(entry))
+}
@@ -0,0 +1,42 @@
+use crate::axumstate::AxumState;
+use crate::http::auth::Viewer;
+use crate::messages::system_events::SystemEventMessage;
+use axum::extract::State;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::{self, Stream};
+use tokio::sync::broadcast;
+use tracing::*;
+
+/// Stream system and experiment state changes to the client as Server-Sent Events
+/// Every event is a JSON `SystemEventMessage`
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "system",
+    responses(
+        (status = 200, description = "Stream of JSON events", content_type = "text/event-stream", body = SystemEventMessage),
+    )
+)]
+pub async fn get_events(
+    State(state): State<AxumState>,
+    _viewer: Viewer,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let receiver = state.event_broadcast.subscribe();
+
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => {
+                    let event = Event::default().json_data(&message);
+                    return Some((event, receiver));
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Event stream client fell behind, skipped {skipped} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
@@ -1,58 +1,441 @@
+use crate::auth::Role;
 use crate::axumstate::AxumState;
+use crate::config::config;
+use crate::experiment::ExperimentCommand;
+use crate::experiment::annotations::annotate_running_experiment;
+use crate::experiment::events::SetpointSource;
+use crate::http::auth::{ViewerSession, authenticate_token};
+use crate::http::binary::{FrameKind, channel_mask, encode_reports};
+use crate::http::delete::release_control;
+use crate::http::error::{ApiError, ApiErrorBody, ApiQuery};
+use crate::http::get::{recent_reports, validate_history_seconds};
+use crate::http::messages::{
+    WsClientMessage, WsConnectQuery, WsEncoding, WsServerMessage, WsSubscribeOptions,
+};
+use crate::http::post::{
+    acquire_control, change_experiment_pause, start_experiment, stop_experiment,
+    update_heart_setpoint, update_loop_setpoint,
+};
+use crate::messages::frontend_messages::{REPORT_CHANNELS, Report};
+use crate::messages::validation::FieldError;
 use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{State, WebSocketUpgrade};
-use axum::response::Response;
+use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
+use axum::response::{IntoResponse, Response};
 use futures_util::{sink::SinkExt, stream::StreamExt};
-use tokio::time::{self, Duration};
+use serde_json::Value;
+use std::net::SocketAddr;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{self, Duration, Interval, MissedTickBehavior};
 use tracing::*;
 
-const MEASUREMENT_SEND_PERIOD: Duration = Duration::from_millis(100);
+/// Highest rate a client can subscribe at, the rate at which the MCU sends reports
+const MAX_SEND_RATE_HZ: f64 = 100.0;
+/// Lowest rate a client can subscribe at, bounds the size of full-rate batches
+const MIN_SEND_RATE_HZ: f64 = 0.1;
+/// Number of replies that may be queued for a client before its commands are no longer read
+const REPLY_BUFFER_LEN: usize = 16;
+/// Period at which the token of an open socket is checked, a socket whose token was revoked is
+/// closed
+const TOKEN_CHECK_PERIOD: Duration = Duration::from_secs(5);
 
-/// Attempt to establish websocket
+/// How measurements are streamed to a websocket client
+#[derive(Debug, Clone)]
+struct Subscription {
+    period: Duration,
+    /// Report fields to send, None for all of them
+    channels: Option<Vec<String>>,
+    full_rate: bool,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            period: config().measurement_send_period(),
+            channels: None,
+            full_rate: false,
+        }
+    }
+}
+
+impl TryFrom<WsSubscribeOptions> for Subscription {
+    type Error = ApiError;
+
+    fn try_from(options: WsSubscribeOptions) -> Result<Self, Self::Error> {
+        let period = match options.rate_hz {
+            Some(rate) if (MIN_SEND_RATE_HZ..=MAX_SEND_RATE_HZ).contains(&rate) => {
+                Duration::from_secs_f64(1.0 / rate)
+            }
+            Some(rate) => {
+                return Err(ApiError::InvalidFields(vec![FieldError {
+                    field: "rate_hz",
+                    message: format!(
+                        "rate_hz must be between {MIN_SEND_RATE_HZ} and {MAX_SEND_RATE_HZ}, got {rate}"
+                    ),
+                }]));
+            }
+            None => config().measurement_send_period(),
+        };
+
+        if let Some(ref channels) = options.channels
+            && let Some(unknown) = channels
+                .iter()
+                .find(|c| !REPORT_CHANNELS.contains(&c.as_str()))
+        {
+            return Err(ApiError::InvalidFields(vec![FieldError {
+                field: "channels",
+                message: format!("unknown channel {unknown}"),
+            }]));
+        }
+
+        Ok(Self {
+            period,
+            channels: options.channels,
+            full_rate: options.full_rate,
+        })
+    }
+}
+
+impl Subscription {
+    /// Report as sent to the client, limited to the subscribed channels
+    fn select_channels(&self, report: &Report) -> Value {
+        let mut value = serde_json::to_value(report).unwrap_or_default();
+        if let (Some(channels), Value::Object(fields)) = (&self.channels, &mut value) {
+            fields.retain(|field, _| field == "time" || channels.contains(field));
+        }
+        value
+    }
+
+    /// Message replaying the reports received before the client connected
+    fn encode_history(&self, reports: &[Report], encoding: WsEncoding) -> Option<Message> {
+        match encoding {
+            WsEncoding::Binary => {
+                let mask = channel_mask(self.channels.as_deref());
+                Some(Message::Binary(
+                    encode_reports(reports, mask, FrameKind::History).into(),
+                ))
+            }
+            WsEncoding::Json => serde_json::to_string(&WsServerMessage::History {
+                reports: reports.iter().map(|r| self.select_channels(r)).collect(),
+            })
+            .ok()
+            .map(|text| Message::Text(text.into())),
+        }
+    }
+
+    /// JSON message holding the reports, a single report is sent as is
+    fn encode_json(&self, reports: Vec<Report>) -> serde_json::Result<String> {
+        if self.full_rate {
+            serde_json::to_string(&WsServerMessage::Reports {
+                reports: reports.iter().map(|r| self.select_channels(r)).collect(),
+            })
+        } else {
+            let report = reports.last().map(|r| self.select_channels(r));
+            serde_json::to_string(&report)
+        }
+    }
+}
+
+/// Attempt to establish websocket, also served at /ws/measurements
+/// Measurements are streamed as `Report`s, commands are `WsClientMessage`s and every other message
+/// sent by the server is a `WsServerMessage`
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "measurements",
+    params(WsConnectQuery),
+    security(("bearer" = []), ("access_token" = [])),
+    responses(
+        (status = 101, description = "Switching to the websocket protocol"),
+        (status = 400, description = "Invalid query parameters", body = ApiErrorBody),
+        (status = 401, description = "Missing or unknown token", body = ApiErrorBody),
+    )
+)]
 pub async fn handle_websocket_request(
     ws: WebSocketUpgrade,
+    session: ViewerSession,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    ApiQuery(query): ApiQuery<WsConnectQuery>,
     State(state): State<AxumState>,
 ) -> Response {
-    ws.on_upgrade(|socket| handle_ws_measurements(socket, state))
+    if let Some(seconds) = query.history_seconds
+        && let Err(err) = validate_history_seconds("history_seconds", seconds)
+    {
+        return err.into_response();
+    }
+    ws.on_upgrade(move |socket| handle_ws_measurements(socket, state, client, session, query))
 }
 
-/// Continously send latest measurements over websocket
-async fn handle_ws_measurements(socket: WebSocket, state: AxumState) {
-    let (mut sender, _) = socket.split();
+/// Continously send measurements over websocket, and handle commands sent by the frontend
+/// Replies to commands are always sent as JSON, whatever the encoding of the measurements
+/// The token of the socket is checked again for every command, and periodically
+async fn handle_ws_measurements(
+    socket: WebSocket,
+    state: AxumState,
+    client: SocketAddr,
+    session: ViewerSession,
+    query: WsConnectQuery,
+) {
+    let ViewerSession { mut user, token } = session;
+    let encoding = query.encoding;
+    let (mut sender, mut receiver) = socket.split();
+
+    // Replies to commands are sent by the same task as the measurements
+    let (reply_sender, mut reply_receiver) = mpsc::channel::<WsServerMessage>(REPLY_BUFFER_LEN);
+    let (subscription_sender, mut subscription_receiver) =
+        watch::channel(Some(Subscription::default()));
+    let mut report_receiver = state.report_broadcast.subscribe();
 
-    tokio::spawn(async move {
-        let mut interval = time::interval(MEASUREMENT_SEND_PERIOD);
+    // Read the history after subscribing, so no report falls in between
+    let history = query
+        .history_seconds
+        .and_then(|seconds| recent_reports(&state, seconds))
+        .unwrap_or_default();
+    // Reports up to this time were replayed, and are skipped when they arrive live as well
+    let replayed_until = history.last().map(Report::time);
+
+    let send_task = tokio::spawn(async move {
+        let mut subscription = subscription_receiver.borrow_and_update().clone();
+        let mut interval = send_interval(subscription.as_ref());
+
+        if !history.is_empty()
+            && let Some(message) = Subscription::default().encode_history(&history, encoding)
+            && let Err(e) = sender.send(message).await
+        {
+            error!("WebSocket send failed: {}", e);
+            return;
+        }
+        // The replayed reports are not needed for the rest of the connection
+        drop(history);
+
+        // Reports received since the previous message
+        let mut latest: Option<Report> = None;
+        let mut batch: Vec<Report> = Vec::new();
 
         loop {
-            // Get the latest report, if it exists
-            let report = {
-                // Attempt to lock the report mutex in axumstate
-                // Note: lock scope is strictly limited and cannot be held across an await (like
-                // the one below)
-                let guard = match state.report.lock() {
-                    Ok(g) => g,
-                    Err(_) => return,
-                };
-
-                // Was a report already created?
-                match &*guard {
-                    // Yes -> return it
-                    Some(r) => r.clone(),
-                    // No -> pass
-                    _ => return,
+            let message = tokio::select! {
+                report = report_receiver.recv() => {
+                    match report {
+                        Ok(report) if replayed_until.is_some_and(|t| report.time() <= t) => {}
+                        Ok(report) if subscription.as_ref().is_some_and(|s| s.full_rate) => {
+                            batch.push(report)
+                        }
+                        Ok(report) => latest = Some(report),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("WebSocket client fell behind, skipped {skipped} reports");
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    }
+                    continue;
                 }
+                _ = interval.tick() => {
+                    let Some(ref subscription) = subscription else {
+                        continue;
+                    };
+                    let reports = if subscription.full_rate {
+                        std::mem::take(&mut batch)
+                    } else {
+                        // Only send reports the client has not seen yet
+                        latest.take().into_iter().collect()
+                    };
+                    if reports.is_empty() {
+                        continue;
+                    }
+                    match encoding {
+                        WsEncoding::Binary => {
+                            let mask = channel_mask(subscription.channels.as_deref());
+                            Message::Binary(encode_reports(&reports, mask, FrameKind::Live).into())
+                        }
+                        WsEncoding::Json => match subscription.encode_json(reports) {
+                            Ok(text) => Message::Text(text.into()),
+                            Err(_) => continue,
+                        },
+                    }
+                }
+                Ok(()) = subscription_receiver.changed() => {
+                    subscription = subscription_receiver.borrow_and_update().clone();
+                    interval = send_interval(subscription.as_ref());
+                    latest = None;
+                    batch.clear();
+                    continue;
+                }
+                Some(reply) = reply_receiver.recv() => match serde_json::to_string(&reply) {
+                    Ok(text) => Message::Text(text.into()),
+                    Err(_) => continue,
+                },
+                else => return,
             };
 
-            if let Ok(bytes) = serde_json::to_string(&report) {
-                let msg = Message::Text(bytes.into());
+            if let Err(e) = sender.send(message).await {
+                error!("WebSocket send failed: {}", e);
+                return;
+            }
+        }
+    });
+
+    // Token of the control lease acquired over this socket, if any
+    let mut lease = None;
+
+    let mut token_check = time::interval(TOKEN_CHECK_PERIOD);
 
-                if let Err(e) = sender.send(msg).await {
-                    error!("WebSocket send failed: {}", e);
-                    return;
+    // Handle commands received from the frontend until the socket closes, or its token is revoked
+    loop {
+        tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = handle_ws_command(
+                        &state,
+                        client,
+                        &token,
+                        &mut lease,
+                        &subscription_sender,
+                        &text,
+                    )
+                    .await;
+                    if reply_sender.send(reply).await.is_err() {
+                        break;
+                    }
                 }
-            }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = token_check.tick() => match authenticate_token(&state, &token, Role::Viewer) {
+                Ok(current) => user = current,
+                Err(err) => {
+                    info!("Closing websocket of {}: {err}", user.name);
+                    break;
+                }
+            },
+        }
+    }
 
-            interval.tick().await;
+    send_task.abort();
+
+    // A closed socket cannot use its lease anymore, so other clients need not wait for it to expire
+    if lease.is_some()
+        && let Err(err) = release_control(&state, &user, lease.as_deref())
+    {
+        debug!("Control lease of closed websocket was not released: {err}");
+    }
+}
+
+/// Interval at which measurements are sent to a client with the given subscription
+fn send_interval(subscription: Option<&Subscription>) -> Interval {
+    let period = subscription.map_or(config().measurement_send_period(), |s| s.period);
+    let mut interval = time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// Apply a command received over the websocket, and build the reply to it
+async fn handle_ws_command(
+    state: &AxumState,
+    client: SocketAddr,
+    token: &str,
+    lease: &mut Option<String>,
+    subscription: &watch::Sender<Option<Subscription>>,
+    text: &str,
+) -> WsServerMessage {
+    // Parse in two steps, so even a malformed command gets a reply with its id
+    let (id, command) = match serde_json::from_str::<Value>(text) {
+        Ok(value) => (
+            value.get("id").cloned(),
+            serde_json::from_value::<WsClientMessage>(value),
+        ),
+        Err(err) => (None, Err(err)),
+    };
+    let command = match command {
+        Ok(command) => command,
+        Err(err) => {
+            warn!("Unable to parse websocket message {text}: {err}");
+            return error_reply(id, ApiError::InvalidRequest(err.to_string()));
         }
-    });
+    };
+    // The token may have been revoked, or its role changed, since the socket was opened
+    let user = match authenticate_token(state, token, command.required_role()) {
+        Ok(user) => user,
+        Err(err) => {
+            warn!("WebSocket command rejected: {err}");
+            return error_reply(id, err);
+        }
+    };
+    if command.requires_control() {
+        let checked = match state.control_lease.lock() {
+            Ok(mut control_lease) => control_lease
+                .check(lease.as_deref())
+                .map_err(ApiError::from),
+            Err(_) => Err(ApiError::StateUnavailable("control lease")),
+        };
+        if let Err(err) = checked {
+            warn!("WebSocket command rejected: {err}");
+            return error_reply(id, err);
+        }
+    }
+
+    let result = match command {
+        WsClientMessage::Annotate(message) => annotate_running_experiment(state, message)
+            .await
+            .map(|annotation| serde_json::to_value(annotation).ok())
+            .map_err(ApiError::from),
+        WsClientMessage::SetLoopSetpoint(setpoint) => {
+            update_loop_setpoint(state, SetpointSource::Websocket, client, &user, setpoint)
+                .await
+                .map(|_| None)
+        }
+        WsClientMessage::SetHeartSetpoint(setpoint) => {
+            update_heart_setpoint(state, SetpointSource::Websocket, client, &user, setpoint)
+                .await
+                .map(|_| None)
+        }
+        WsClientMessage::StartExperiment(message) => {
+            start_experiment(state, &user, message).await.map(|_| None)
+        }
+        WsClientMessage::StopExperiment => stop_experiment(state).await.map(|_| None),
+        WsClientMessage::PauseExperiment => {
+            change_experiment_pause(state, ExperimentCommand::Pause, false)
+                .await
+                .map(|_| None)
+        }
+        WsClientMessage::ResumeExperiment => {
+            change_experiment_pause(state, ExperimentCommand::Resume, true)
+                .await
+                .map(|_| None)
+        }
+        WsClientMessage::Subscribe(options) => Subscription::try_from(options).map(|subscribed| {
+            subscription.send_replace(Some(subscribed));
+            None
+        }),
+        WsClientMessage::Unsubscribe => {
+            subscription.send_replace(None);
+            Ok(None)
+        }
+        WsClientMessage::AcquireControl(options) => {
+            acquire_control(state, &user, lease.as_deref(), options).map(|grant| {
+                *lease = Some(grant.token.clone());
+                serde_json::to_value(grant).ok()
+            })
+        }
+        WsClientMessage::ReleaseControl => {
+            release_control(state, &user, lease.as_deref()).map(|_| {
+                *lease = None;
+                None
+            })
+        }
+    };
+
+    match result {
+        Ok(result) => WsServerMessage::Ack { id, result },
+        Err(err) => {
+            warn!("WebSocket command rejected: {err}");
+            error_reply(id, err)
+        }
+    }
+}
+
+/// Reply to a rejected command, with the status and body the matching endpoint returns
+fn error_reply(id: Option<Value>, err: ApiError) -> WsServerMessage {
+    WsServerMessage::Error {
+        id,
+        status: err.status().as_u16(),
+        error: err.body(),
+    }
 }
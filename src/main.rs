@@ -1,34 +1,53 @@
+use crate::auth::UserStore;
 use crate::axumstate::AxumState;
+use crate::config::{Config, config};
+use crate::control::clock::McuClockStatus;
 use crate::control::controller::control_loop;
+use crate::control::history::ReportHistory;
+use crate::control::lease::{ControlLease, expire_control_lease};
 use crate::database::db_communication_task::communicate_with_db;
+use crate::experiment::catalogue::purge_trash;
 use crate::experiment::manage::manage_experiments;
+use crate::experiment::quality::DataQuality;
 use crate::http::CONVEX_URI;
+use crate::http::auth::*;
+use crate::http::binary::*;
+use crate::http::delete::*;
 use crate::http::get::*;
 use crate::http::messages::ExperimentList;
+use crate::http::openapi::{ApiDoc, with_api_docs};
+use crate::http::patch::*;
 use crate::http::post::*;
+use crate::http::sse::*;
 use crate::http::ws::handle_websocket_request;
 use crate::messages::frontend_messages;
 use crate::micro_communication_task::communicate_with_micro;
-use axum::Router;
-use axum::routing::{any, get, post};
+use axum::routing::any;
 use chrono::Utc;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
 use tokio::task;
-use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::*;
 use tracing_subscriber::FmtSubscriber;
+use utoipa::OpenApi;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
 
+pub mod analysis;
+pub mod auth;
 pub mod axumstate;
 pub mod communicator;
+pub mod config;
 pub mod control;
 pub mod database;
 pub mod experiment;
+pub mod export;
 pub mod http;
 pub mod messages;
 pub mod micro_communication_task;
 
-const PI_IP: &str = "192.168.0.4";
 const FRONTEND_PORT_PROD: usize = 80;
 const FRONTEND_PORT_DEV: usize = 5173;
 
@@ -44,6 +63,18 @@ async fn main() {
     tracing::subscriber::set_global_default(subscriber)
         .expect("setting default tracing subscriber failed");
 
+    // Read the configuration before anything depends on it
+    match Config::load() {
+        Ok(config) => {
+            info!("Configuration: {:?}", config.redacted());
+            config::init(config);
+        }
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    }
+
     // Create communication channels between tasks
     let (db_report_sender, db_report_receiver) = tokio::sync::mpsc::channel(100);
     let (mcu_setpoint_sender, mcu_setpoint_receiver) =
@@ -53,8 +84,11 @@ async fn main() {
         mpsc::Receiver<love_letter::Report>,
     ) = tokio::sync::mpsc::channel(10);
     let (experiment_sender, experiment_receiver) = tokio::sync::watch::channel(None);
-    let (experiment_started_sender, experiment_started_receiver) =
-        tokio::sync::watch::channel(None);
+    // Holds 10 s of reports, so slow websocket clients in full-rate mode do not miss any
+    let (report_broadcast, _) = tokio::sync::broadcast::channel(1000);
+    let (event_broadcast, _) = tokio::sync::broadcast::channel(100);
+    // Every command has to reach the experiment manager, a Stop may not be replaced by a Pause
+    let (experiment_command_sender, experiment_command_receiver) = tokio::sync::mpsc::channel(16);
 
     // Initialize application state
     let initial_setpoint: frontend_messages::FrontendSetpoint =
@@ -62,12 +96,24 @@ async fn main() {
 
     let initial_report = None;
     let initial_experiment = None;
+    let users = UserStore::load_or_init()
+        .await
+        .expect("unable to load or create the users file");
     let state = AxumState {
         setpoint: Arc::new(Mutex::new(initial_setpoint)),
         report: Arc::new(Mutex::new(initial_report)),
+        report_broadcast,
+        history: Arc::new(Mutex::new(ReportHistory::default())),
         current_experiment: Arc::new(Mutex::new(initial_experiment)),
-        experiment_watch: experiment_started_sender,
+        data_quality: Arc::new(Mutex::new(DataQuality::default())),
+        experiment_commands: experiment_command_sender,
         experiments: Arc::new(Mutex::new(ExperimentList::new())),
+        event_broadcast: event_broadcast.clone(),
+        db_sender: db_report_sender.clone(),
+        mcu_clock: Arc::new(Mutex::new(McuClockStatus::default())),
+        control_lease: Arc::new(Mutex::new(ControlLease::default())),
+        users: Arc::new(Mutex::new(users)),
+        users_update: Arc::new(tokio::sync::Mutex::new(())),
         start_time: Arc::new(Utc::now()),
     };
 
@@ -86,47 +132,118 @@ async fn main() {
         mcu_setpoint_sender,
         experiment_receiver,
         state.clone(),
-        db_report_sender,
+        db_report_sender.clone(),
     ));
 
     // Start the experiment manager task
     task::spawn(manage_experiments(
-        experiment_started_receiver,
+        experiment_command_receiver,
         experiment_sender,
+        db_report_sender,
+        event_broadcast,
     ));
 
     // Start the DB communication task
-    task::spawn(communicate_with_db(db_report_receiver));
+    task::spawn(communicate_with_db(
+        db_report_receiver,
+        state.data_quality.clone(),
+    ));
+
+    // Start the task removing experiments that were in the trash for too long
+    task::spawn(purge_trash());
 
-    // Define CORS rules
+    // Start the task dropping the control lease once its holder stops renewing it
+    task::spawn(expire_control_lease(state.clone()));
+
+    // Define CORS rules, only the frontend may call the API from a browser
     let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([axum::http::Method::GET, axum::http::Method::POST])
-        .allow_headers(Any);
+        .allow_origin(AllowOrigin::list([
+            frontend_origin(&config().pi_ip, FRONTEND_PORT_PROD),
+            frontend_origin(&config().pi_ip, FRONTEND_PORT_DEV),
+            frontend_origin("localhost", FRONTEND_PORT_DEV),
+        ]))
+        .allow_methods([
+            axum::http::Method::GET,
+            axum::http::Method::POST,
+            axum::http::Method::PATCH,
+            axum::http::Method::DELETE,
+        ])
+        .allow_headers([
+            axum::http::header::AUTHORIZATION,
+            axum::http::header::CONTENT_TYPE,
+            axum::http::HeaderName::from_static(LEASE_HEADER),
+        ]);
 
-    // Set up Axum routers
-    let app = Router::new()
+    // Set up Axum routers, the OpenAPI document is collected from the documented handlers
+    let router = OpenApiRouter::with_openapi(ApiDoc::openapi())
         // GET endpoints
-        .route("/heartbeat", get(get_heartbeat))
-        .route("/measurements", get(get_measurements))
+        .routes(routes!(get_heartbeat))
+        .routes(routes!(get_config))
+        .routes(routes!(get_measurements))
+        .routes(routes!(get_measurement_history))
+        .route("/ws", any(handle_websocket_request))
         .route("/ws/measurements", any(handle_websocket_request))
-        .route("/experiment/status", get(get_experiment_status))
-        .route("/experiment/list", get(get_list_experiments_from_db))
-        .route(
-            "/experiment/download/{table_name}",
-            get(download_experiment_csv),
-        )
+        .routes(routes!(get_binary_schema))
+        .routes(routes!(get_events))
+        .routes(routes!(get_experiment_status))
+        .routes(routes!(
+            get_control_lease,
+            post_control_lease,
+            delete_control_lease
+        ))
+        .routes(routes!(get_list_experiments_from_db))
+        .routes(routes!(download_experiment))
+        .routes(routes!(download_experiment_archive))
+        .routes(routes!(get_experiment_trash))
+        .routes(routes!(get_experiment_comparison))
+        .routes(routes!(
+            get_experiment_detail,
+            patch_experiment,
+            delete_experiment
+        ))
+        .routes(routes!(get_setpoint_events))
+        .routes(routes!(get_experiment_data))
+        .routes(routes!(get_experiment_summary))
+        .routes(routes!(get_current_user))
+        .routes(routes!(get_users, post_user))
         // POST endpoints
-        .route("/control/loop", post(post_loop_setpoint))
-        .route("/control/heart", post(post_heart_setpoint))
-        .route("/experiment/start", post(post_start_experiment))
-        .route("/experiment/stop", post(post_stop_experiment))
+        .routes(routes!(post_loop_setpoint))
+        .routes(routes!(post_heart_setpoint))
+        .routes(routes!(post_start_experiment))
+        .routes(routes!(post_stop_experiment))
+        .routes(routes!(post_pause_experiment))
+        .routes(routes!(post_resume_experiment))
+        .routes(routes!(post_annotate_experiment))
+        .routes(routes!(post_import_experiments))
+        .routes(routes!(post_restore_experiment))
+        // DELETE endpoints
+        .routes(routes!(delete_user));
+
+    let app = with_api_docs(router)
         .layer(cors.clone()) // Attach CORS middleware
         .with_state(state.clone()); // Give the routers access to the application state
 
     // Start serving webrequests
     info!("Axum Router & communication task initialised");
-    info!("Listening on http://localhost:8000");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    info!("Listening on http://localhost:{}", config().port);
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", config().port))
+        .await
+        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+/// Origin the frontend is served from, browsers leave out the default HTTP port
+fn frontend_origin(host: &str, port: usize) -> axum::http::HeaderValue {
+    let origin = match port {
+        80 => format!("http://{host}"),
+        port => format!("http://{host}:{port}"),
+    };
+    origin
+        .parse()
+        .expect("frontend origin is a valid header value")
 }
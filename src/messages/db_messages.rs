@@ -2,7 +2,31 @@ use chrono::{DateTime, Utc};
 use influxdb::InfluxDbWriteable;
 use uom::si::{frequency::cycle_per_minute, pressure::bar, volume_rate::liter_per_minute};
 
+use crate::analysis::summary::ExperimentSummary;
 use crate::control::ControllerReport;
+use crate::experiment::annotations::Annotation;
+use crate::experiment::catalogue::CatalogueEntry;
+use crate::experiment::events::SetpointEvent;
+use crate::experiment::gaps::DataGap;
+
+/// Messages handled by the database communication task
+#[derive(Debug, Clone)]
+pub enum DatabaseMessage {
+    /// Measurement report to log to the running experiment table
+    Report(ControllerReport),
+    /// Buffered reports to log retroactively to the experiment table they are tagged with
+    Backfill(Vec<ControllerReport>),
+    /// Setpoint change to log to the setpoint event table
+    SetpointEvent(SetpointEvent),
+    /// Operator marker to log to the annotation table
+    Annotation(Annotation),
+    /// Period without recorded data to log to the gap table
+    DataGap(DataGap),
+    /// Metadata change to log to the catalogue table
+    Catalogue(CatalogueEntry),
+    /// Summary of a stopped experiment to log to the summary table
+    Summary(Box<ExperimentSummary>),
+}
 
 #[derive(Debug, Clone, InfluxDbWriteable)]
 pub struct DatabaseRecord {
@@ -28,7 +52,12 @@ pub struct DatabaseRecord {
     pulmonary_afterload_compliance: Option<f32>,
 
     // Metadata
-    simulation_time: f32,
+    /// MCU clock in seconds since the firmware started
+    simulation_time: f64,
+    /// Raw MCU timestamp, in µs since the firmware started
+    mcu_timestamp_us: u64,
+    /// User that started the experiment
+    started_by: Option<String>,
     time: DateTime<Utc>,
     #[influxdb(tag)]
     experiment_id: String,
@@ -95,7 +124,9 @@ impl From<ControllerReport> for DatabaseRecord {
                 .then_some(r.mockloop_setpoint.pulmonary_afterload_compliance),
 
             // Metadata
-            simulation_time: 0.0,
+            simulation_time: r.measurements.timestamp as f64 / 1e6,
+            mcu_timestamp_us: r.measurements.timestamp,
+            started_by: r.experiment.started_by,
             time: r.time,
             experiment_id: String::from(uuid),
             experiment_name: r.experiment.name,
@@ -103,3 +134,138 @@ impl From<ControllerReport> for DatabaseRecord {
         }
     }
 }
+
+#[derive(Debug, Clone, InfluxDbWriteable)]
+pub struct SetpointEventRecord {
+    source: String,
+    client: String,
+    user: String,
+    old_setpoint: String,
+    new_setpoint: String,
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    experiment_id: String,
+}
+
+impl From<SetpointEvent> for SetpointEventRecord {
+    fn from(e: SetpointEvent) -> Self {
+        // Parse uuid into hyphenated string
+        let mut buf = [b'0'; 40];
+        let uuid = e.experiment_id.as_hyphenated().encode_lower(&mut buf);
+
+        Self {
+            source: e.source.as_str().to_string(),
+            client: e.client.unwrap_or_default(),
+            user: e.user.unwrap_or_default(),
+            // Setpoints are stored as json so they can be returned as-is by the API
+            old_setpoint: serde_json::to_string(&e.old).unwrap_or_default(),
+            new_setpoint: serde_json::to_string(&e.new).unwrap_or_default(),
+            time: e.time,
+            experiment_id: String::from(uuid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, InfluxDbWriteable)]
+pub struct AnnotationRecord {
+    category: String,
+    text: String,
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    experiment_id: String,
+}
+
+impl From<Annotation> for AnnotationRecord {
+    fn from(a: Annotation) -> Self {
+        // Parse uuid into hyphenated string
+        let mut buf = [b'0'; 40];
+        let uuid = a.experiment_id.as_hyphenated().encode_lower(&mut buf);
+
+        Self {
+            category: a.category.unwrap_or_default(),
+            text: a.text,
+            time: a.time,
+            experiment_id: String::from(uuid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, InfluxDbWriteable)]
+pub struct DataGapRecord {
+    end_time: String,
+    duration_seconds: f64,
+    cause: String,
+    /// Start of the gap
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    experiment_id: String,
+}
+
+impl From<DataGap> for DataGapRecord {
+    fn from(g: DataGap) -> Self {
+        // Parse uuid into hyphenated string
+        let mut buf = [b'0'; 40];
+        let uuid = g.experiment_id.as_hyphenated().encode_lower(&mut buf);
+
+        Self {
+            end_time: g.end.to_rfc3339(),
+            duration_seconds: (g.end - g.start).num_milliseconds() as f64 / 1000.0,
+            cause: g.cause.as_str().to_string(),
+            time: g.start,
+            experiment_id: String::from(uuid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, InfluxDbWriteable)]
+pub struct CatalogueRecord {
+    name: String,
+    description: String,
+    /// Json array of tags
+    tags: String,
+    /// Rfc3339 time the experiment was moved to the trash, empty if it was not
+    deleted_at: String,
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    experiment_id: String,
+}
+
+impl From<CatalogueEntry> for CatalogueRecord {
+    fn from(c: CatalogueEntry) -> Self {
+        // Parse uuid into hyphenated string
+        let mut buf = [b'0'; 40];
+        let uuid = c.experiment_id.as_hyphenated().encode_lower(&mut buf);
+
+        Self {
+            name: c.name,
+            description: c.description,
+            tags: serde_json::to_string(&c.tags).unwrap_or_default(),
+            deleted_at: c.deleted_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            time: c.time,
+            experiment_id: String::from(uuid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, InfluxDbWriteable)]
+pub struct SummaryRecord {
+    /// Json encoded `ExperimentSummary`
+    summary: String,
+    time: DateTime<Utc>,
+    #[influxdb(tag)]
+    experiment_id: String,
+}
+
+impl From<ExperimentSummary> for SummaryRecord {
+    fn from(s: ExperimentSummary) -> Self {
+        // Parse uuid into hyphenated string
+        let mut buf = [b'0'; 40];
+        let uuid = s.experiment_id.as_hyphenated().encode_lower(&mut buf);
+
+        Self {
+            summary: serde_json::to_string(&s).unwrap_or_default(),
+            time: s.generated_at,
+            experiment_id: String::from(uuid),
+        }
+    }
+}
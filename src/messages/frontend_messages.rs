@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
 use tracing::error;
 use uom::si::pressure::millibar;
 use uom::si::{
@@ -7,10 +8,12 @@ use uom::si::{
     pressure::bar,
     volume_rate::liter_per_minute,
 };
+use utoipa::ToSchema;
 
 use crate::control::ControllerReport;
+use crate::messages::validation::{FieldError, check_range};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct Report {
     // Sensor data
     pulmonary_preload_pressure_mmhg: f32,
@@ -34,12 +37,85 @@ pub struct Report {
     pulmonary_afterload_compliance: Option<f32>,
 
     // Metadata
+    /// Time of the report, in ns since the unix epoch
     time: i64,
     experiment_id: String,
     experiment_name: String,
     experiment_description: String,
 }
 
+/// Fields of a `Report`, which websocket clients can subscribe to
+pub const REPORT_CHANNELS: &[&str] = &[
+    "pulmonary_preload_pressure_mmhg",
+    "systemic_preload_pressure_mmhg",
+    "pulmonary_afterload_pressure_mmhg",
+    "systemic_afterload_pressure_mmhg",
+    "systemic_flow_l_per_min",
+    "pulmonary_flow_l_per_min",
+    "heart_controller_enable",
+    "heart_rate",
+    "pressure",
+    "systole_ratio",
+    "mockloop_controller_enable",
+    "systemic_resistance",
+    "pulmonary_resistance",
+    "systemic_afterload_compliance",
+    "pulmonary_afterload_compliance",
+    "time",
+    "experiment_id",
+    "experiment_name",
+    "experiment_description",
+];
+
+/// Numeric fields of a `Report`, in the order of `Report::numeric_values`
+pub const NUMERIC_REPORT_CHANNELS: &[&str] = &[
+    "pulmonary_preload_pressure_mmhg",
+    "systemic_preload_pressure_mmhg",
+    "pulmonary_afterload_pressure_mmhg",
+    "systemic_afterload_pressure_mmhg",
+    "systemic_flow_l_per_min",
+    "pulmonary_flow_l_per_min",
+    "heart_controller_enable",
+    "heart_rate",
+    "pressure",
+    "systole_ratio",
+    "mockloop_controller_enable",
+    "systemic_resistance",
+    "pulmonary_resistance",
+    "systemic_afterload_compliance",
+    "pulmonary_afterload_compliance",
+];
+
+impl Report {
+    /// Time of the report, in ns since the unix epoch
+    pub fn time(&self) -> i64 {
+        self.time
+    }
+
+    /// Numeric fields in the order of `NUMERIC_REPORT_CHANNELS`, booleans as 0 or 1
+    /// Setpoints of a disabled controller are None
+    pub fn numeric_values(&self) -> [Option<f32>; 15] {
+        let flag = |enable: bool| Some(if enable { 1.0 } else { 0.0 });
+        [
+            Some(self.pulmonary_preload_pressure_mmhg),
+            Some(self.systemic_preload_pressure_mmhg),
+            Some(self.pulmonary_afterload_pressure_mmhg),
+            Some(self.systemic_afterload_pressure_mmhg),
+            Some(self.systemic_flow_l_per_min),
+            Some(self.pulmonary_flow_l_per_min),
+            flag(self.heart_controller_enable),
+            self.heart_rate,
+            self.pressure,
+            self.systole_ratio,
+            flag(self.mockloop_controller_enable),
+            self.systemic_resistance,
+            self.pulmonary_resistance,
+            self.systemic_afterload_compliance,
+            self.pulmonary_afterload_compliance,
+        ]
+    }
+}
+
 impl From<ControllerReport> for Report {
     fn from(r: ControllerReport) -> Self {
         // Parse uuid into hyphenated string
@@ -105,7 +181,7 @@ impl From<ControllerReport> for Report {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
 pub struct FrontendSetpoint {
     /// Should the mockloop controller be enabled?
     pub mockloop_setpoint: MockloopSetpoint,
@@ -131,30 +207,90 @@ impl From<FrontendSetpoint> for love_letter::Setpoint {
     }
 }
 
+/// Allowed hemodynamic resistance, in mmHg·s/L
+pub const RESISTANCE_RANGE: RangeInclusive<f32> = 0.0..=10_000.0;
+/// Allowed afterload compliance, in L/mmHg
+pub const COMPLIANCE_RANGE: RangeInclusive<f32> = 0.0..=1.0;
+/// Allowed heart rate, in beats per minute
+pub const HEART_RATE_RANGE: RangeInclusive<f32> = 20.0..=200.0;
+/// Allowed regulator pressure, in mbar
+pub const PRESSURE_RANGE: RangeInclusive<f32> = 0.0..=2000.0;
+/// Allowed ratio of systole duration to total cardiac phase duration
+pub const SYSTOLE_RATIO_RANGE: RangeInclusive<f32> = 0.1..=0.9;
+
+/// Range a setpoint field has to lie in, any finite value if its controller is disabled
+fn setpoint_range(enable: bool, range: RangeInclusive<f32>) -> RangeInclusive<f32> {
+    if enable { range } else { f32::MIN..=f32::MAX }
+}
+
 /// Setpoint for the mockloop hemodynamics controller
-#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, ToSchema)]
 pub struct MockloopSetpoint {
     /// Enable the controller?
     pub enable: bool,
+    /// In mmHg·s/L
     pub systemic_resistance: f32,
+    /// In mmHg·s/L
     pub pulmonary_resistance: f32,
+    /// In L/mmHg
     pub systemic_afterload_compliance: f32,
+    /// In L/mmHg
     pub pulmonary_afterload_compliance: f32,
 }
 
 /// Setpoint for the pneumatic heart prototype controller
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
 pub struct HeartControllerSetpoint {
     /// Enable the controller?
     pub enable: bool,
-    /// Desired heart rate
+    /// Desired heart rate, in Hz
+    #[schema(value_type = f32)]
     pub heart_rate: Frequency,
-    /// Desired regulator pressure
+    /// Desired regulator pressure, in Pa
+    #[schema(value_type = f32)]
     pub pressure: Pressure,
     /// Ratio of systole duration to total cardiac phase duration
     pub systole_ratio: f32,
 }
 
+impl MockloopSetpoint {
+    /// Check every field against its allowed range, returning all invalid fields
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let resistance = || setpoint_range(self.enable, RESISTANCE_RANGE);
+        let compliance = || setpoint_range(self.enable, COMPLIANCE_RANGE);
+        check_range(
+            &mut errors,
+            "systemic_resistance",
+            self.systemic_resistance,
+            resistance(),
+        );
+        check_range(
+            &mut errors,
+            "pulmonary_resistance",
+            self.pulmonary_resistance,
+            resistance(),
+        );
+        check_range(
+            &mut errors,
+            "systemic_afterload_compliance",
+            self.systemic_afterload_compliance,
+            compliance(),
+        );
+        check_range(
+            &mut errors,
+            "pulmonary_afterload_compliance",
+            self.pulmonary_afterload_compliance,
+            compliance(),
+        );
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
 pub struct FrontendMockloopSetpoint {
     enable: bool,
     systemic_mmhg_s_per_l: f32,
@@ -163,14 +299,48 @@ pub struct FrontendMockloopSetpoint {
     pulmonary_afterload_compliance_l_per_mmhg: f32,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
 pub struct FrontendHeartControllerSetpoint {
     enable: bool,
+    /// Desired heart rate, in beats per minute
     heart_rate: f32,
+    /// Desired regulator pressure, in mbar
     pressure: f32,
+    /// Ratio of systole duration to total cardiac phase duration
     systole_ratio: f32,
 }
 
+impl FrontendHeartControllerSetpoint {
+    /// Check every field against its allowed range, returning all invalid fields
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let range = |range| setpoint_range(self.enable, range);
+        check_range(
+            &mut errors,
+            "heart_rate",
+            self.heart_rate,
+            range(HEART_RATE_RANGE),
+        );
+        check_range(
+            &mut errors,
+            "pressure",
+            self.pressure,
+            range(PRESSURE_RANGE),
+        );
+        check_range(
+            &mut errors,
+            "systole_ratio",
+            self.systole_ratio,
+            range(SYSTOLE_RATIO_RANGE),
+        );
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
 pub struct FrontendExperimentSetpoint {
     name: String,
     description: String,
@@ -244,3 +414,109 @@ impl From<love_letter::HeartControllerSetpoint> for HeartControllerSetpoint {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::binary::{FrameKind, binary_schema, channel_mask, encode_reports};
+    use serde_json::Value;
+
+    fn report() -> Report {
+        Report {
+            pulmonary_preload_pressure_mmhg: 1.5,
+            systemic_preload_pressure_mmhg: 2.5,
+            pulmonary_afterload_pressure_mmhg: 3.5,
+            systemic_afterload_pressure_mmhg: 4.5,
+            systemic_flow_l_per_min: 5.5,
+            pulmonary_flow_l_per_min: 6.5,
+            heart_controller_enable: true,
+            heart_rate: Some(60.0),
+            pressure: Some(0.2),
+            systole_ratio: Some(0.35),
+            mockloop_controller_enable: false,
+            systemic_resistance: None,
+            pulmonary_resistance: None,
+            systemic_afterload_compliance: None,
+            pulmonary_afterload_compliance: None,
+            time: 1_760_000_000_000_000_000,
+            experiment_id: String::new(),
+            experiment_name: String::new(),
+            experiment_description: String::new(),
+        }
+    }
+
+    /// Read a little endian value of a type named in the binary schema
+    fn read(frame: &[u8], offset: &mut usize, kind: &str) -> Value {
+        let len = match kind {
+            "u8" => 1,
+            "u16" => 2,
+            "u32" | "f32" => 4,
+            "i64" => 8,
+            _ => panic!("unknown type {kind}"),
+        };
+        let bytes = &frame[*offset..*offset + len];
+        *offset += len;
+        match kind {
+            "u8" => Value::from(bytes[0]),
+            "u16" => Value::from(u16::from_le_bytes(bytes.try_into().unwrap())),
+            "u32" => Value::from(u32::from_le_bytes(bytes.try_into().unwrap())),
+            "f32" => Value::from(f32::from_le_bytes(bytes.try_into().unwrap())),
+            _ => Value::from(i64::from_le_bytes(bytes.try_into().unwrap())),
+        }
+    }
+
+    #[test]
+    fn report_channels_match_report_fields() {
+        let Value::Object(fields) = serde_json::to_value(report()).unwrap() else {
+            panic!("report is not serialised as an object");
+        };
+        let mut fields: Vec<&str> = fields.keys().map(String::as_str).collect();
+        let mut channels = REPORT_CHANNELS.to_vec();
+        fields.sort_unstable();
+        channels.sort_unstable();
+        assert_eq!(fields, channels);
+    }
+
+    #[test]
+    fn binary_frame_decodes_with_schema() {
+        let report = report();
+        let schema = binary_schema();
+        let frame = encode_reports(
+            std::slice::from_ref(&report),
+            channel_mask(None),
+            FrameKind::History,
+        );
+
+        let mut offset = 0;
+        let header: Vec<(&str, Value)> = schema
+            .header
+            .iter()
+            .map(|field| (field.name, read(&frame, &mut offset, field.kind)))
+            .collect();
+        assert_eq!(
+            header,
+            [
+                ("version", Value::from(schema.version)),
+                ("kind", Value::from(FrameKind::History as u8)),
+                ("samples", Value::from(1)),
+                ("channels", Value::from(channel_mask(None))),
+            ]
+        );
+
+        // Every channel is sent, so the sample holds every numeric field of the report
+        let expected = serde_json::to_value(&report).unwrap();
+        for field in &schema.sample {
+            let value = read(&frame, &mut offset, field.kind);
+            assert_eq!(value, expected[field.name], "{}", field.name);
+        }
+        for channel in &schema.channels {
+            let value = read(&frame, &mut offset, channel.kind);
+            match &expected[channel.name] {
+                Value::Bool(flag) => assert_eq!(value, Value::from(*flag as u8 as f32)),
+                Value::Null => assert!(value.as_f64().is_none_or(f64::is_nan), "{}", channel.name),
+                other => assert_eq!(&value, other, "{}", channel.name),
+            }
+        }
+        assert_eq!(offset, frame.len());
+    }
+}
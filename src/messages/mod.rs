@@ -1,2 +1,4 @@
 pub mod db_messages;
 pub mod frontend_messages;
+pub mod system_events;
+pub mod validation;
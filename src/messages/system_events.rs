@@ -0,0 +1,116 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::control::lease::LeaseChange;
+use crate::experiment::Experiment;
+use crate::experiment::events::SetpointSource;
+use crate::messages::frontend_messages::FrontendSetpoint;
+
+/// Change in the state of the system, pushed to every client of the event stream
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SystemEvent {
+    ExperimentStarted {
+        experiment_id: Uuid,
+        name: String,
+    },
+    ExperimentStopped {
+        experiment_id: Uuid,
+        name: String,
+    },
+    ExperimentPaused {
+        experiment_id: Uuid,
+    },
+    ExperimentResumed {
+        experiment_id: Uuid,
+    },
+    /// The name, description or tags of the running experiment changed
+    ExperimentEdited {
+        experiment_id: Uuid,
+        name: String,
+    },
+    SetpointChanged {
+        source: SetpointSource,
+        client: Option<String>,
+        user: Option<String>,
+        setpoint: FrontendSetpoint,
+    },
+    /// The client allowed to drive the rig changed
+    ControlLeaseChanged {
+        change: LeaseChange,
+        /// User now holding the lease, None if nobody is in control
+        owner: Option<String>,
+        previous_owner: Option<String>,
+    },
+    /// The first report arrived after the MCU was silent
+    McuLinkUp,
+    /// No report arrived from the MCU within the communication timeout
+    McuLinkDown,
+    /// Reports are queuing up for the DB task, which is not keeping up
+    DbBacklog {
+        queued: usize,
+        capacity: usize,
+    },
+    /// The DB task caught up with the queued reports
+    DbBacklogCleared,
+}
+
+impl SystemEvent {
+    /// Events describing the transition from the `previous` to the `current` experiment
+    pub fn experiment_changes(
+        previous: &Option<Experiment>,
+        current: &Option<Experiment>,
+    ) -> Vec<SystemEvent> {
+        let mut events = Vec::new();
+        match (previous, current) {
+            (Some(previous), Some(current)) if previous.id == current.id => {
+                let experiment_id = current.id;
+                if current.is_paused && !previous.is_paused {
+                    events.push(SystemEvent::ExperimentPaused { experiment_id });
+                } else if previous.is_paused && !current.is_paused {
+                    events.push(SystemEvent::ExperimentResumed { experiment_id });
+                } else {
+                    events.push(SystemEvent::ExperimentEdited {
+                        experiment_id,
+                        name: current.name.clone(),
+                    });
+                }
+            }
+            (previous, current) => {
+                if let Some(previous) = previous {
+                    events.push(SystemEvent::ExperimentStopped {
+                        experiment_id: previous.id,
+                        name: previous.name.clone(),
+                    });
+                }
+                if let Some(current) = current {
+                    events.push(SystemEvent::ExperimentStarted {
+                        experiment_id: current.id,
+                        name: current.name.clone(),
+                    });
+                }
+            }
+        }
+        events
+    }
+}
+
+/// System event as sent to the clients of the event stream
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SystemEventMessage {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: SystemEvent,
+}
+
+/// Push an event to every client of the event stream
+pub fn publish_event(events: &broadcast::Sender<SystemEventMessage>, event: SystemEvent) {
+    // Sending only fails when no client is listening, in which case nobody misses the event
+    let _ = events.send(SystemEventMessage {
+        time: Utc::now(),
+        event,
+    });
+}
@@ -0,0 +1,31 @@
+use serde::Serialize;
+use std::ops::RangeInclusive;
+use utoipa::ToSchema;
+
+/// Invalid field of a request
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// Check that a field lies within its allowed range, noting an error if it does not
+pub fn check_range(
+    errors: &mut Vec<FieldError>,
+    field: &'static str,
+    value: f32,
+    range: RangeInclusive<f32>,
+) {
+    let message = if !value.is_finite() {
+        format!("{field} must be a finite number")
+    } else if !range.contains(&value) {
+        format!(
+            "{field} must be between {} and {}, got {value}",
+            range.start(),
+            range.end()
+        )
+    } else {
+        return;
+    };
+    errors.push(FieldError { field, message });
+}
@@ -5,9 +5,7 @@ use tokio::time::{self, timeout};
 use tracing::*;
 
 use crate::communicator::MockloopCommunicator;
-
-/// Bounds the maximum duration between consecutive setpoints / reports
-const COMMS_TIMEOUT: Duration = Duration::from_millis(2000);
+use crate::config::config;
 
 pub async fn communicate_with_micro(
     setpoint_receiver: watch::Receiver<Setpoint>,
@@ -41,14 +39,21 @@ pub async fn communicate_with_micro(
 
         // Send latest setpoint to the mcu
         let setpoint = setpoint_receiver.borrow().clone();
-        if let Err(err) = timeout(COMMS_TIMEOUT, mcu_communicator.send_setpoint(setpoint)).await {
+        if let Err(err) = timeout(
+            config().comms_timeout(),
+            mcu_communicator.send_setpoint(setpoint),
+        )
+        .await
+        {
             error!("timeout sending setpoint to mcu: {err}");
         }
 
         // Receive latest report from mcu and forward to controller task
-        match timeout(COMMS_TIMEOUT, mcu_communicator.receive_report()).await {
+        match timeout(config().comms_timeout(), mcu_communicator.receive_report()).await {
             Ok(mcu_report) => {
-                if let Err(err) = timeout(COMMS_TIMEOUT, report_sender.send(mcu_report)).await {
+                if let Err(err) =
+                    timeout(config().comms_timeout(), report_sender.send(mcu_report)).await
+                {
                     error!("timeout sending report to controller task: {err}");
                 }
             }